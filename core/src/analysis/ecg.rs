@@ -5,6 +5,7 @@ use find_peaks::PeakFinder;
 use ndarray::{Array1, ArrayView1};
 use noisy_float::prelude::Float;
 use crate::analysis::filter::{highpass_filter};
+use crate::analysis::gaps;
 use noisy_float::types::{R64, r64};
 use slog::{Logger, o, trace, error};
 use crate::log::create_logger;
@@ -20,6 +21,8 @@ pub struct Parameters {
     pub hr_min: f64,
     pub hr_max: f64,
     pub hr_max_diff: f64,
+    pub max_interpolated_gap_sec: f64,
+    pub min_segment_sec: f64,
 }
 
 pub struct Results {
@@ -59,6 +62,29 @@ impl Analysis {
         self.analyze_view(signal.view())
     }
     
+    /// Analyzes a signal with missing samples: gaps up to `max_interpolated_gap_sec` are
+    /// interpolated, longer ones split the signal into segments analyzed on their own.
+    /// Segments shorter than `min_segment_sec` count as zero quality.
+    pub fn analyze_with_gaps(&self, signal: Vec<Option<f64>>) -> Results {
+        let fs = self.params.sampling_frequency;
+        let segmented = gaps::segment(&signal, (self.params.max_interpolated_gap_sec * fs).round() as usize);
+        let min_len = ((self.params.min_segment_sec * fs).round() as usize).max(1);
+
+        let results: Vec<(usize, f64, f64)> = segmented.segments.iter().map(|segment| {
+            if segment.len() < min_len {
+                return (segment.len(), f64::NAN, 0.0);
+            }
+            let results = self.analyze_view(ArrayView1::from(segment.as_slice()));
+            (segment.len(), results.hr_estimate, results.signal_quality)
+        }).collect();
+        trace!(self.logger, "Segment results"; "results" => format!("{:?}", results), "missing" => segmented.missing_fraction);
+
+        match gaps::combine(&results, segmented.missing_fraction) {
+            Some((hr_estimate, signal_quality)) => Results { hr_estimate, signal_quality },
+            None => Results { hr_estimate: f64::NAN, signal_quality: 0.0 },
+        }
+    }
+
     pub fn analyze_view(&self, signal: ArrayView1<f64>) -> Results {
         self.plot_signal(signal, "Raw Signal", "signal_raw.png", None);
 
@@ -0,0 +1,115 @@
+/// A signal with missing samples, cut into segments that can be analyzed on their own
+#[derive(Debug, PartialEq)]
+pub struct Segmented {
+    /// runs of samples, with the short gaps inside them linearly interpolated
+    pub segments: Vec<Vec<f64>>,
+    /// share of missing samples between the first and the last present one
+    pub missing_fraction: f64,
+}
+
+/// Interpolates gaps of up to `max_gap` samples and splits the signal at longer ones.
+///
+/// Missing samples before the first and after the last present one are not counted as gaps,
+/// the ring buffers start out empty.
+pub fn segment(signal: &[Option<f64>], max_gap: usize) -> Segmented {
+    let first = signal.iter().position(|x| x.is_some());
+    let last = signal.iter().rposition(|x| x.is_some());
+    let (first, last) = match (first, last) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return Segmented { segments: vec![], missing_fraction: 0.0 },
+    };
+    let span = &signal[first..=last];
+
+    let mut segments = vec![];
+    let mut current: Vec<f64> = vec![];
+    let mut gap = 0;
+    let mut missing = 0;
+    for sample in span {
+        match sample {
+            None => {
+                gap += 1;
+                missing += 1;
+            }
+            Some(value) => {
+                if gap > max_gap {
+                    segments.push(std::mem::take(&mut current));
+                } else if gap > 0 {
+                    let previous = *current.last().expect("span starts with a sample");
+                    let step = (value - previous) / (gap + 1) as f64;
+                    current.extend((1..=gap).map(|i| previous + step * i as f64));
+                }
+                current.push(*value);
+                gap = 0;
+            }
+        }
+    }
+    segments.push(current);
+
+    Segmented {
+        segments,
+        missing_fraction: missing as f64 / span.len() as f64,
+    }
+}
+
+/// Combines the heart rate and signal quality of the analyzed segments, weighted by their length.
+/// The quality is reduced by the share of missing samples. Returns `None` without any results.
+pub fn combine(results: &[(usize, f64, f64)], missing_fraction: f64) -> Option<(f64, f64)> {
+    let valid: Vec<&(usize, f64, f64)> = results.iter().filter(|(_, hr, _)| hr.is_finite()).collect();
+    let total = results.iter().map(|(len, _, _)| *len).sum::<usize>() as f64;
+    let valid_total = valid.iter().map(|(len, _, _)| *len).sum::<usize>() as f64;
+    if total == 0.0 {
+        return None;
+    }
+
+    let hr_estimate = if valid_total > 0.0 {
+        valid.iter().map(|(len, hr, _)| *len as f64 * hr).sum::<f64>() / valid_total
+    } else {
+        f64::NAN
+    };
+    let quality = results.iter()
+        .map(|(len, _, quality)| *len as f64 * if quality.is_nan() { 0.0 } else { *quality })
+        .sum::<f64>() / total;
+
+    Some((hr_estimate, quality * (1.0 - missing_fraction)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interpolates_short_gaps() {
+        let signal = [None, Some(1.0), None, None, Some(4.0), Some(5.0), None];
+        let segmented = segment(&signal, 2);
+
+        assert_eq!(segmented.segments, vec![vec![1.0, 2.0, 3.0, 4.0, 5.0]]);
+        assert_eq!(segmented.missing_fraction, 2.0 / 5.0);
+    }
+
+    #[test]
+    fn splits_at_long_gaps() {
+        let signal = [Some(1.0), Some(2.0), None, None, None, Some(6.0), None, Some(8.0)];
+        let segmented = segment(&signal, 2);
+
+        assert_eq!(segmented.segments, vec![vec![1.0, 2.0], vec![6.0, 7.0, 8.0]]);
+        assert_eq!(segmented.missing_fraction, 4.0 / 8.0);
+    }
+
+    #[test]
+    fn empty_signal_has_no_segments() {
+        assert!(segment(&[None, None], 2).segments.is_empty());
+    }
+
+    #[test]
+    fn combines_weighted_by_length() {
+        let (hr, quality) = combine(&[(300, 60.0, 1.0), (100, 80.0, 0.6)], 0.0).unwrap();
+        assert_eq!(hr, 65.0);
+        assert_eq!(quality, 0.9);
+
+        let (hr, quality) = combine(&[(300, 60.0, 1.0), (100, f64::NAN, f64::NAN)], 0.5).unwrap();
+        assert_eq!(hr, 60.0);
+        assert_eq!(quality, 0.375);
+
+        assert!(combine(&[], 0.0).is_none());
+    }
+}
@@ -1,6 +1,11 @@
 
+// the analyses, the filter and the analysis tests are older than the lint gate
+#[allow(clippy::type_complexity, clippy::unnecessary_cast)]
 pub mod ppg;
+#[allow(clippy::type_complexity, clippy::manual_is_multiple_of, clippy::suspicious_map)]
 pub mod ecg;
+#[allow(clippy::needless_return, clippy::manual_is_multiple_of, clippy::implicit_saturating_sub)]
 mod filter;
+mod gaps;
+#[allow(unused_parens, clippy::module_inception, clippy::let_and_return, clippy::needless_borrows_for_generic_args)]
 pub(crate) mod tests;
-
@@ -3,6 +3,7 @@ use std::error::Error;
 use ndarray::{Array1, ArrayView1, s};
 use slog::{error, Logger, o, trace};
 use crate::analysis::filter::{bandpass_filter, lower_envelope_est};
+use crate::analysis::gaps;
 use crate::log::create_logger;
 
 #[derive(Debug, PartialEq, Clone)]
@@ -18,6 +19,8 @@ pub struct Parameters {
     pub trough_depth_max: f64,
     pub pulse_width_min: f64,
     pub pulse_width_max: f64,
+    pub max_interpolated_gap_sec: f64,
+    pub min_segment_sec: f64,
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -95,6 +98,38 @@ impl Analysis {
         self.analyze_view(signal.view())
     }
 
+    /// Analyzes a signal with missing samples: gaps up to `max_interpolated_gap_sec` are
+    /// interpolated, longer ones split the signal into segments analyzed on their own.
+    /// Segments that are shorter than `min_segment_sec` or without enough pulses count as
+    /// zero quality, if no segment has enough pulses there are no results.
+    pub fn analyze_with_gaps(&self, signal: Vec<Option<f64>>) -> Option<Results> {
+        let fs = self.params.sampling_frequency;
+        let segmented = gaps::segment(&signal, (self.params.max_interpolated_gap_sec * fs).round() as usize);
+        let min_len = ((self.params.min_segment_sec * fs).round() as usize).max(1);
+
+        let results: Vec<(usize, Option<Results>)> = segmented.segments.iter().map(|segment| {
+            if segment.len() < min_len {
+                return (segment.len(), None);
+            }
+            (segment.len(), self.analyze_view(ArrayView1::from(segment.as_slice())))
+        }).collect();
+        trace!(self.logger, "Segment results"; "results" => format!("{:?}", results), "missing" => segmented.missing_fraction);
+
+        if results.iter().all(|(_, r)| r.is_none()) {
+            return None;
+        }
+
+        let results: Vec<(usize, f64, f64)> = results.into_iter().map(|(len, r)| match r {
+            Some(r) => (len, r.hr_estimate, r.signal_quality),
+            None => (len, f64::NAN, 0.0),
+        }).collect();
+
+        gaps::combine(&results, segmented.missing_fraction).map(|(hr_estimate, signal_quality)| Results {
+            hr_estimate,
+            signal_quality,
+        })
+    }
+
     pub fn analyze_view(&self, signal: ArrayView1<f64>) -> Option<Results> {
         self.plot_signal(signal, "Raw Signal", "signal_raw.png", None);
 
@@ -28,6 +28,8 @@ pub(crate) mod tests {
             trough_depth_max: 0.25,
             pulse_width_min: 0.333, // 200 bpm
             pulse_width_max: 1.5, // 40 bpm
+            max_interpolated_gap_sec: 0.5,
+            min_segment_sec: 4.0,
         };
 
         let analyzer = ppg::Analysis { params, logger: logger.clone(), plotter: Some(Box::new(plot_signal)) };
@@ -69,6 +71,8 @@ pub(crate) mod tests {
             hr_min: 40.0,
             hr_max: 200.0,
             hr_max_diff: 20.0,
+            max_interpolated_gap_sec: 0.25,
+            min_segment_sec: 4.0,
         };
 
         let analyzer = ecg::Analysis { params, logger: logger.clone(), plotter: Some(Box::new(plot_signal)) };
@@ -155,4 +159,53 @@ pub(crate) mod tests {
         root.present()?;
         Ok(())
     }
+
+    fn ecg_params() -> ecg::Parameters {
+        ecg::Parameters {
+            sampling_frequency: 32.0,
+            filter_cutoff_low: 0.6,
+            filter_order: 1,
+            r_peak_prominence_mad_multiple: 12.0,
+            r_peak_distance: 5,
+            r_peak_plateau: 1,
+            hr_min: 40.0,
+            hr_max: 200.0,
+            hr_max_diff: 20.0,
+            max_interpolated_gap_sec: 0.25,
+            min_segment_sec: 4.0,
+        }
+    }
+
+    /// 30 s of R peaks at 75 bpm on a slowly wandering baseline
+    fn synthetic_ecg() -> Vec<f64> {
+        let fs = 32.0;
+        (0..960).map(|i| {
+            let t = i as f64 / fs;
+            let phase = (t * 75.0 / 60.0).fract();
+            let qrs = (-((phase - 0.5) * 40.0).powi(2)).exp();
+            qrs + 0.1 * (t * 0.3).sin() + 0.02 * (t * 7.0).sin()
+        }).collect()
+    }
+
+    #[test]
+    fn test_ecg_with_gaps() {
+        let signal = synthetic_ecg();
+        let analyzer = ecg::Analysis { params: ecg_params(), logger: get_logger(), plotter: None };
+        let reference = analyzer.analyze(signal.clone());
+        assert!((reference.hr_estimate - 75.0).abs() < 2.0, "reference heart rate {}", reference.hr_estimate);
+
+        // short dropouts every few seconds and a long one in the middle
+        let with_gaps: Vec<Option<f64>> = signal.iter().enumerate()
+            .map(|(i, &x)| if i % 100 < 3 || (480..576).contains(&i) { None } else { Some(x) })
+            .collect();
+        let results = analyzer.analyze_with_gaps(with_gaps.clone());
+
+        assert!((results.hr_estimate - 75.0).abs() < 2.0, "heart rate with gaps {}", results.hr_estimate);
+        assert!(results.signal_quality < reference.signal_quality);
+        assert!(results.signal_quality > 0.5 * reference.signal_quality);
+
+        // dropping the missing samples compresses time and overestimates the heart rate
+        let compressed: Vec<f64> = with_gaps.iter().filter_map(|x| *x).collect();
+        assert!(analyzer.analyze(compressed).hr_estimate > results.hr_estimate + 2.0);
+    }
 }
@@ -0,0 +1,324 @@
+use std::collections::HashSet;
+use std::time::Duration;
+use tokio::time::Instant;
+use super::DecodedPacket;
+use super::channel_layout::ChannelLayout;
+use super::control::{CommandId, ControlCommand, LED_CURRENT_STEP_MA};
+use crate::ChannelType;
+
+/// Bounds and targets of the PPG auto-gain control
+#[derive(Debug, PartialEq, Clone)]
+pub struct Parameters {
+    /// LED current set when a device connects
+    pub initial_current_ma: f32,
+    pub min_current_ma: f32,
+    pub max_current_ma: f32,
+    /// bounds of the DC level, as fractions of the raw range of a channel
+    pub target_low: f64,
+    pub target_high: f64,
+    /// fraction of samples at the end of the raw range above which a channel counts as clipping
+    pub max_clipped: f64,
+    /// length of the windows the DC level and the clipping are measured over
+    pub window_ms: u32,
+    /// time after a current change whose samples are marked for analysis to ignore
+    pub settle_ms: u32,
+}
+
+/// Largest factor a single adjustment changes a current by
+const MAX_STEP_FACTOR: f64 = 2.0;
+
+struct PpgChannel {
+    id: String,
+    /// index of the LED in `SetLedCurrents`
+    led: usize,
+    /// raw range of the samples
+    range: (i64, i64),
+    sum: f64,
+    count: usize,
+    clipped_high: usize,
+    clipped_low: usize,
+}
+
+impl PpgChannel {
+    fn reset(&mut self) {
+        self.sum = 0.0;
+        self.count = 0;
+        self.clipped_high = 0;
+        self.clipped_low = 0;
+    }
+
+    /// Factor the current of the channel's LED should change by, `None` if the level is fine
+    fn correction(&self, parameters: &Parameters) -> Option<f64> {
+        if self.count == 0 {
+            return None;
+        }
+        let count = self.count as f64;
+        if self.clipped_high as f64 / count > parameters.max_clipped {
+            return Some(1.0 / MAX_STEP_FACTOR);
+        }
+        if self.clipped_low as f64 / count > parameters.max_clipped {
+            return Some(MAX_STEP_FACTOR);
+        }
+
+        let (low, high) = self.range;
+        let level = (self.sum / count - low as f64) / (high - low) as f64;
+        if (parameters.target_low..=parameters.target_high).contains(&level) {
+            return None;
+        }
+        // the DC level is roughly proportional to the LED current
+        let target = (parameters.target_low + parameters.target_high) / 2.0;
+        let factor = if level > 0.0 { target / level } else { MAX_STEP_FACTOR };
+        Some(factor.clamp(1.0 / MAX_STEP_FACTOR, MAX_STEP_FACTOR))
+    }
+}
+
+/// The LED a PPG channel is lit by, from the color in its name
+fn led_index(name: &str) -> Option<usize> {
+    let name = name.to_lowercase();
+    let words: Vec<&str> = name.split(|c: char| !c.is_alphanumeric()).collect();
+    if words.contains(&"green") {
+        Some(0)
+    } else if words.contains(&"ir") || words.contains(&"infrared") {
+        Some(2)
+    } else if words.contains(&"red") {
+        Some(1)
+    } else {
+        None
+    }
+}
+
+fn current_steps(ma: f32) -> u8 {
+    (ma / LED_CURRENT_STEP_MA).round().clamp(0.0, u8::MAX as f32) as u8
+}
+
+/// Keeps the raw DC level of the PPG channels of a device within the target bounds by
+/// changing its LED currents. Samples from the moment a change is sent until `settle_ms`
+/// after the device answered are marked as settling and not measured.
+pub struct AutoGain {
+    parameters: Parameters,
+    channels: Vec<PpgChannel>,
+    /// samples per channel and window
+    window: usize,
+    /// green, red and IR in steps of `LED_CURRENT_STEP_MA`
+    currents: [u8; 3],
+    min_steps: u8,
+    max_steps: u8,
+    /// ids of the changes sent and not answered yet
+    pending: HashSet<CommandId>,
+    settled_at: Option<Instant>,
+    /// number of the next change the auto-gain sends itself
+    next_change: u32,
+}
+
+impl AutoGain {
+    /// `None` if no PPG channel of the layout names the color of its LED
+    pub fn new(parameters: &Parameters, layout: &ChannelLayout, device_id: &str) -> Option<Self> {
+        let channels: Vec<PpgChannel> = layout.channel_fields(device_id).into_iter()
+            .filter(|(_, field)| field.channel_type == ChannelType::PPG)
+            .filter_map(|(id, field)| Some(PpgChannel {
+                id,
+                led: led_index(&field.name)?,
+                range: field.range(),
+                sum: 0.0,
+                count: 0,
+                clipped_high: 0,
+                clipped_low: 0,
+            }))
+            .collect();
+        if channels.is_empty() {
+            return None;
+        }
+
+        let min_steps = current_steps(parameters.min_current_ma);
+        let max_steps = current_steps(parameters.max_current_ma).max(min_steps);
+        let initial = current_steps(parameters.initial_current_ma).clamp(min_steps, max_steps);
+        Some(Self {
+            parameters: parameters.clone(),
+            channels,
+            window: ((layout.sampling_rate * parameters.window_ms as f64 / 1000.0).round() as usize).max(1),
+            currents: [initial; 3],
+            min_steps,
+            max_steps,
+            pending: HashSet::new(),
+            settled_at: None,
+            next_change: 0,
+        })
+    }
+
+    /// The command setting the initial currents, the device's own are unknown
+    pub fn initial_command(&mut self) -> (CommandId, ControlCommand) {
+        let [green, red, ir] = self.currents;
+        (self.own_change(), ControlCommand::SetLedCurrents(green, red, ir))
+    }
+
+    /// Begins a change the auto-gain sends itself
+    fn own_change(&mut self) -> CommandId {
+        let command_id = CommandId::AutoGain(self.next_change);
+        self.next_change = self.next_change.wrapping_add(1);
+        self.begin_change(command_id);
+        command_id
+    }
+
+    /// Ids of the channels whose samples are marked while settling
+    pub fn channel_ids(&self) -> HashSet<String> {
+        self.channels.iter().map(|channel| channel.id.clone()).collect()
+    }
+
+    pub fn settling(&self, now: Instant) -> bool {
+        !self.pending.is_empty() || self.settled_at.is_some_and(|settled_at| now < settled_at)
+    }
+
+    /// A change of the currents was sent, by us or the app
+    pub fn begin_change(&mut self, command_id: CommandId) {
+        self.pending.insert(command_id);
+    }
+
+    /// The device acknowledged new currents
+    pub fn applied(&mut self, command_id: CommandId, green: u8, red: u8, ir: u8, now: Instant) {
+        self.currents = [green, red, ir];
+        self.end_change(command_id, now);
+    }
+
+    /// A command completed, for a change that was rejected or went unanswered the device may
+    /// still have applied it. Commands other than changes are ignored.
+    pub fn end_change(&mut self, command_id: CommandId, now: Instant) {
+        if !self.pending.remove(&command_id) {
+            return;
+        }
+        self.settled_at = Some(now + Duration::from_millis(self.parameters.settle_ms as u64));
+        for channel in self.channels.iter_mut() {
+            channel.reset();
+        }
+    }
+
+    /// Measures the samples of a packet not taken while settling. Returns the command changing
+    /// the currents once a window is complete and a level is off target.
+    pub fn measure(&mut self, packet: &DecodedPacket, now: Instant) -> Option<(CommandId, ControlCommand)> {
+        if self.settling(now) {
+            return None;
+        }
+
+        for channel in self.channels.iter_mut() {
+            let (low, high) = channel.range;
+            for sample in packet.samples.get(&channel.id).into_iter().flatten().flatten() {
+                let sample = *sample as i64;
+                channel.sum += sample as f64;
+                channel.count += 1;
+                if sample >= high {
+                    channel.clipped_high += 1;
+                } else if sample <= low {
+                    channel.clipped_low += 1;
+                }
+            }
+        }
+        if self.channels.iter().all(|channel| channel.count < self.window) {
+            return None;
+        }
+
+        // an LED lighting several channels follows the one asking for the least current
+        let mut factors: [Option<f64>; 3] = [None; 3];
+        for channel in self.channels.iter_mut() {
+            if let Some(factor) = channel.correction(&self.parameters) {
+                let led = &mut factors[channel.led];
+                *led = Some(led.map_or(factor, |other| other.min(factor)));
+            }
+            channel.reset();
+        }
+
+        let mut currents = self.currents;
+        for (current, factor) in currents.iter_mut().zip(factors) {
+            if let Some(factor) = factor {
+                let scaled = (*current as f64 * factor).round().clamp(0.0, u8::MAX as f64) as u8;
+                // a current of zero scales to nothing
+                let scaled = if factor > 1.0 { scaled.max(current.saturating_add(1)) } else { scaled };
+                *current = scaled.clamp(self.min_steps, self.max_steps);
+            }
+        }
+        if currents == self.currents {
+            return None;
+        }
+
+        let [green, red, ir] = currents;
+        Some((self.own_change(), ControlCommand::SetLedCurrents(green, red, ir)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parameters() -> Parameters {
+        Parameters {
+            initial_current_ma: 10.0,
+            min_current_ma: 2.0,
+            max_current_ma: 20.0,
+            target_low: 0.25,
+            target_high: 0.75,
+            max_clipped: 0.05,
+            // 32 samples at the legacy rate
+            window_ms: 1000,
+            settle_ms: 500,
+        }
+    }
+
+    /// The legacy layout without ECG: green, red and IR
+    fn packet(green: i32, red: i32, ir: i32) -> DecodedPacket {
+        let mut packet = DecodedPacket::default();
+        for (n, value) in [green, red, ir].into_iter().enumerate() {
+            packet.samples.insert(format!("dev-{}", n), vec![Some(value); 32]);
+        }
+        packet
+    }
+
+    fn settled() -> AutoGain {
+        let mut auto_gain = AutoGain::new(&parameters(), &ChannelLayout::legacy(false), "dev").unwrap();
+        assert_eq!(auto_gain.initial_command(), (CommandId::AutoGain(0), ControlCommand::SetLedCurrents(50, 50, 50)));
+        auto_gain.applied(CommandId::AutoGain(0), 50, 50, 50, Instant::now() - Duration::from_secs(1));
+        auto_gain
+    }
+
+    #[test]
+    fn maps_channels_to_leds() {
+        assert_eq!(led_index("PPG green"), Some(0));
+        assert_eq!(led_index("PPG red"), Some(1));
+        assert_eq!(led_index("PPG IR"), Some(2));
+        assert_eq!(led_index("infrared"), Some(2));
+        assert_eq!(led_index("PPG ambient"), None);
+
+        let auto_gain = settled();
+        assert_eq!(auto_gain.channel_ids().len(), 3);
+        assert!(AutoGain::new(&parameters(), &ChannelLayout { fields: vec![], ..ChannelLayout::legacy(false) }, "dev").is_none());
+    }
+
+    #[test]
+    fn adjusts_currents_within_bounds() {
+        let mut auto_gain = settled();
+        let now = Instant::now();
+
+        // on target
+        assert_eq!(auto_gain.measure(&packet(32768, 32768, 32768), now), None);
+        // green clips, red sits at a tenth of the range and IR at the floor
+        assert_eq!(auto_gain.measure(&packet(65535, 6553, 0), now), Some((CommandId::AutoGain(1), ControlCommand::SetLedCurrents(25, 100, 100))));
+
+        // nothing is measured until the change settled, other commands do not end it
+        assert!(auto_gain.settling(now));
+        assert_eq!(auto_gain.measure(&packet(0, 0, 0), now), None);
+        auto_gain.end_change(CommandId::AutoGain(0), now - Duration::from_secs(1));
+        auto_gain.end_change(CommandId::App(1), now - Duration::from_secs(1));
+        assert!(auto_gain.settling(now));
+        auto_gain.applied(CommandId::AutoGain(1), 25, 100, 100, now);
+        assert!(auto_gain.settling(now + Duration::from_millis(499)));
+        assert!(!auto_gain.settling(now + Duration::from_millis(500)));
+
+        let later = now + Duration::from_secs(1);
+        assert_eq!(auto_gain.measure(&packet(0, 0, 65535), later), Some((CommandId::AutoGain(2), ControlCommand::SetLedCurrents(50, 100, 50))));
+        auto_gain.end_change(CommandId::AutoGain(2), later);
+
+        // a window that is only partly measured waits for the rest
+        let mut half = packet(0, 32768, 32768);
+        half.samples.values_mut().for_each(|samples| samples.truncate(16));
+        let later = later + Duration::from_secs(1);
+        assert_eq!(auto_gain.measure(&half, later), None);
+        assert_eq!(auto_gain.measure(&half, later), Some((CommandId::AutoGain(3), ControlCommand::SetLedCurrents(50, 100, 100))));
+    }
+}
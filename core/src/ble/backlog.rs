@@ -0,0 +1,250 @@
+use std::time::Duration;
+use tokio::time::Instant;
+use super::{DatapointDecoder, DecodedPacket};
+use super::channel_layout::ChannelLayout;
+
+const REQUEST: u8 = 0x01;
+const HEADER: u8 = 0x81;
+const CHUNK: u8 = 0x82;
+const END: u8 = 0x83;
+
+/// Where a data stream is, the sequence counter of a packet and the host time of its last sample
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct StreamPosition {
+    pub sequence: u32,
+    pub timestamp_us: i64,
+}
+
+/// Packets a device recorded to its flash while the link was down, between the last packet of
+/// the previous stream and the first one of the current stream.
+///
+/// The missing packets are counted by the time between the two streams, the sequence counters
+/// settle the count, as they alone cannot tell gaps longer than the counter range apart. The
+/// missing samples are spread evenly between the two known samples, which keeps the
+/// timestamps right even if the device clock drifted.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Gap {
+    /// sequence counter of the packet the gap follows
+    after: u32,
+    packets: u32,
+    samples_per_packet: usize,
+    start_us: i64,
+    step_us: f64,
+}
+
+impl Gap {
+    /// `None` if nothing is missing or the layout has no sequence counter
+    pub fn between(end: StreamPosition, first: StreamPosition, layout: &ChannelLayout) -> Option<Self> {
+        let counter = layout.counter()?;
+        let modulus = 1u64 << (8 * counter.width.min(4));
+        let samples_per_packet = layout.samples_per_packet.max(1);
+        let period_us = 1e6 / layout.sampling_rate;
+
+        // time of the first sample of the first packet
+        let first_us = first.timestamp_us as f64 - (samples_per_packet - 1) as f64 * period_us;
+        let elapsed_us = first_us - end.timestamp_us as f64;
+        let by_time = ((elapsed_us / period_us - 1.0) / samples_per_packet as f64).max(0.0);
+
+        let by_counter = (first.sequence as u64 + modulus - end.sequence as u64 % modulus - 1) % modulus;
+        let wraps = ((by_time - by_counter as f64) / modulus as f64).round().max(0.0) as u64;
+        let packets = u32::try_from(by_counter + wraps * modulus).ok().filter(|packets| *packets > 0)?;
+
+        let samples = packets as usize * samples_per_packet;
+        Some(Self {
+            after: end.sequence,
+            packets,
+            samples_per_packet,
+            start_us: end.timestamp_us,
+            step_us: elapsed_us.max(0.0) / (samples + 1) as f64,
+        })
+    }
+
+    pub fn packets(&self) -> u32 {
+        self.packets
+    }
+
+    /// Asks for the packets following the packet with counter `after`
+    pub fn request(&self) -> Vec<u8> {
+        let mut frame = vec![REQUEST];
+        frame.extend_from_slice(&self.after.to_le_bytes());
+        frame.extend_from_slice(&self.packets.to_le_bytes());
+        frame
+    }
+
+    fn samples(&self) -> usize {
+        self.packets as usize * self.samples_per_packet
+    }
+
+    fn timestamp(&self, sample: usize) -> i64 {
+        (self.start_us as f64 + (sample + 1) as f64 * self.step_us).round() as i64
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BacklogEvent {
+    /// the device has this many packets of the gap
+    Started(u32),
+    /// samples of a chunk, packets received so far and the total
+    Data(DecodedPacket, u32, u32),
+    /// all packets the device has were received
+    Completed(u32),
+}
+
+/// Downloads the packets of a gap from the backlog characteristic.
+///
+/// The request is answered with a header notification, `0x81` and the number of packets the
+/// device still has of the gap (u32), then chunks of whole packets in the layout of the data
+/// characteristic, `0x82`, the chunk index counting from 0 (u16) and the packets, and finally
+/// `0x83` and a status byte, 0 meaning success. Flash that overwrote the oldest packets keeps
+/// the newest ones, so a short backlog is placed at the end of the gap.
+pub struct BacklogTransfer {
+    gap: Gap,
+    decoder: DatapointDecoder,
+    packet_length: usize,
+    /// packets the device has, once its header arrived
+    total: Option<u32>,
+    received: u32,
+    next_chunk: u16,
+    /// index in the gap of the next sample
+    next_sample: usize,
+    timeout: Duration,
+    deadline: Instant,
+}
+
+impl BacklogTransfer {
+    /// A transfer failing if the device stays silent for `timeout`
+    pub fn new(gap: Gap, layout: &ChannelLayout, device_id: &str, timeout: Duration) -> Self {
+        Self {
+            gap,
+            decoder: layout.decoder(device_id),
+            packet_length: layout.packet_length,
+            total: None,
+            received: 0,
+            next_chunk: 0,
+            next_sample: 0,
+            timeout,
+            deadline: Instant::now() + timeout,
+        }
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    pub fn received(&self) -> u32 {
+        self.received
+    }
+
+    pub fn receive(&mut self, value: &[u8]) -> Result<BacklogEvent, String> {
+        self.deadline = Instant::now() + self.timeout;
+        match (value.first(), self.total) {
+            (Some(&HEADER), None) => {
+                let total = value.get(1..5).ok_or("Backlog header too short")?;
+                let total = u32::from_le_bytes([total[0], total[1], total[2], total[3]]).min(self.gap.packets);
+                self.next_sample = (self.gap.packets - total) as usize * self.gap.samples_per_packet;
+                self.total = Some(total);
+                Ok(BacklogEvent::Started(total))
+            }
+            (Some(&CHUNK), Some(total)) => {
+                let index = value.get(1..3).ok_or("Backlog chunk too short")?;
+                let index = u16::from_le_bytes([index[0], index[1]]);
+                if index != self.next_chunk {
+                    return Err(format!("Backlog chunk {} missing", self.next_chunk));
+                }
+                self.next_chunk = self.next_chunk.wrapping_add(1);
+
+                let mut chunk = DecodedPacket::default();
+                for packet in value[3..].chunks_exact(self.packet_length) {
+                    let decoded = (self.decoder)(packet.to_vec());
+                    self.received += 1;
+                    chunk.lost_packets += decoded.lost_packets;
+                    for (channel_id, samples) in decoded.samples {
+                        chunk.samples.entry(channel_id).or_default().extend(samples);
+                    }
+                }
+
+                // samples past the gap would overlap the current stream
+                let available = self.gap.samples().saturating_sub(self.next_sample);
+                let count = chunk.samples.values().next().map_or(0, |samples| samples.len()).min(available);
+                for samples in chunk.samples.values_mut() {
+                    samples.truncate(count);
+                }
+                chunk.timestamps_us = (self.next_sample..self.next_sample + count).map(|n| self.gap.timestamp(n)).collect();
+                self.next_sample += count;
+
+                Ok(BacklogEvent::Data(chunk, self.received.min(total), total))
+            }
+            (Some(&END), Some(_)) => match value.get(1) {
+                Some(0) => Ok(BacklogEvent::Completed(self.received)),
+                Some(status) => Err(format!("Device aborted the backlog transfer with status {}", status)),
+                None => Err("Backlog end too short".to_string()),
+            },
+            _ => Err(format!("Unexpected backlog notification {:?}", value)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ChannelType;
+    use crate::tests::legacy_packet;
+
+    fn position(sequence: u32, timestamp_us: i64) -> StreamPosition {
+        StreamPosition { sequence, timestamp_us }
+    }
+
+    /// Legacy packets are 3 samples at 32 Hz, 93750 us each
+    fn gap(packets: i64, first_sequence: u32) -> Option<Gap> {
+        let period = 31250;
+        // the last sample of the first packet after the gap
+        let first_us = 1_000_000 + (packets * 3 + 3) * period;
+        Gap::between(position(10, 1_000_000), position(first_sequence, first_us), &ChannelLayout::legacy(true))
+    }
+
+    #[test]
+    fn counts_missing_packets() {
+        assert_eq!(gap(0, 11), None);
+        assert_eq!(gap(4, 15).unwrap().packets(), 4);
+        // the counter wrapped, the time tells
+        assert_eq!(gap(260, 15).unwrap().packets(), 260);
+        // the time is off by a packet, the counter tells
+        assert_eq!(gap(5, 15).unwrap().packets(), 4);
+        assert_eq!(gap(4, 15).unwrap().request(), vec![REQUEST, 10, 0, 0, 0, 4, 0, 0, 0]);
+
+        let mut layout = ChannelLayout::legacy(true);
+        layout.fields.retain(|field| field.channel_type != ChannelType::CNT);
+        assert_eq!(Gap::between(position(10, 0), position(15, 1_000_000), &layout), None);
+    }
+
+    #[test]
+    fn downloads_chunks_into_the_gap() {
+        let layout = ChannelLayout::legacy(true);
+        let mut transfer = BacklogTransfer::new(gap(4, 15).unwrap(), &layout, "dev", Duration::from_secs(1));
+
+        // the flash only kept the newest 3 packets
+        assert_eq!(transfer.receive(&[HEADER, 3, 0, 0, 0]), Ok(BacklogEvent::Started(3)));
+
+        let mut chunk = vec![CHUNK, 0, 0];
+        chunk.extend(legacy_packet(12, [(1, 2, 3, 4); 3]));
+        chunk.extend(legacy_packet(13, [(5, 6, 7, 8); 3]));
+        let BacklogEvent::Data(data, received, total) = transfer.receive(&chunk).unwrap() else {
+            panic!("Expected data");
+        };
+        assert_eq!((received, total), (2, 3));
+        assert_eq!(data.samples["dev-0"], vec![Some(1), Some(1), Some(1), Some(5), Some(5), Some(5)]);
+        // right after the sample of the packet that was overwritten
+        assert_eq!(data.timestamps_us[0], 1_000_000 + 4 * 31250);
+        assert_eq!(data.timestamps_us[5], 1_000_000 + 9 * 31250);
+
+        let mut chunk = vec![CHUNK, 2, 0];
+        chunk.extend(legacy_packet(14, [(0, 0, 0, 0); 3]));
+        assert_eq!(transfer.receive(&chunk), Err("Backlog chunk 1 missing".to_string()));
+
+        let mut transfer = BacklogTransfer::new(gap(4, 15).unwrap(), &layout, "dev", Duration::from_secs(1));
+        assert!(transfer.receive(&[CHUNK, 0, 0]).is_err());
+        transfer.receive(&[HEADER, 0, 0, 0, 0]).unwrap();
+        assert_eq!(transfer.receive(&[END, 0]), Ok(BacklogEvent::Completed(0)));
+        assert!(transfer.receive(&[END, 3]).unwrap_err().contains("status 3"));
+    }
+}
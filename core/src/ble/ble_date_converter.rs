@@ -33,7 +33,7 @@ pub fn time_to_ble_data(current_time: DateTime<Utc>) -> Vec<u8> {
 }
 
 #[allow(deprecated)]
-pub fn ble_data_to_time(data: &[u8]) -> Result<DateTime<Utc>, Box<dyn Error>> {
+pub fn ble_data_to_time(data: &[u8]) -> Result<DateTime<Utc>, Box<dyn Error + Send + Sync>> {
     if data.len() == 11 {
         let year = u16::from_le_bytes(data[0..2].try_into()?) as i32;
         let month = data[2] as u32;
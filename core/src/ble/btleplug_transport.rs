@@ -0,0 +1,133 @@
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use async_trait::async_trait;
+use btleplug::api::{Central, CentralEvent, Characteristic, Manager as _, Peripheral as _, ScanFilter, Service, WriteType};
+use btleplug::platform::{Adapter, Manager, Peripheral};
+use futures::stream::StreamExt;
+use tokio::sync::OnceCell;
+use uuid::Uuid;
+use super::transport::*;
+
+/// `BleTransport` backed by the first adapter `btleplug` finds on this platform
+pub struct BtleplugTransport {
+    adapter: OnceCell<Adapter>,
+}
+
+impl BtleplugTransport {
+    pub fn new() -> Self {
+        Self {
+            adapter: OnceCell::new(),
+        }
+    }
+
+    async fn adapter(&self) -> BleResult<&Adapter> {
+        self.adapter.get_or_try_init(|| async {
+            let manager = Manager::new().await?;
+            let adapters = manager.adapters().await?;
+            adapters.into_iter().next().ok_or_else(|| "No adapter found".into())
+        }).await
+    }
+}
+
+impl Default for BtleplugTransport {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl BleTransport for BtleplugTransport {
+    async fn events(&self) -> BleResult<TransportEventStream> {
+        let events = self.adapter().await?.events().await?;
+        let events = events.filter_map(|event| async move {
+            match event {
+                CentralEvent::DeviceDiscovered(id) => Some(TransportEvent::DeviceDiscovered(id.to_string())),
+                CentralEvent::DeviceUpdated(id) => Some(TransportEvent::DeviceUpdated(id.to_string())),
+                CentralEvent::DeviceDisconnected(id) => Some(TransportEvent::DeviceDisconnected(id.to_string())),
+                _ => None,
+            }
+        });
+        Ok(Box::pin(events))
+    }
+
+    async fn start_scan(&self, services: Vec<Uuid>) -> BleResult<()> {
+        self.adapter().await?.start_scan(ScanFilter { services }).await?;
+        Ok(())
+    }
+
+    async fn stop_scan(&self) -> BleResult<()> {
+        self.adapter().await?.stop_scan().await?;
+        Ok(())
+    }
+
+    async fn peripherals(&self) -> BleResult<Vec<Arc<dyn BlePeripheral>>> {
+        let peripherals = self.adapter().await?.peripherals().await?;
+        Ok(peripherals.into_iter().map(|p| Arc::new(BtleplugPeripheral(p)) as Arc<dyn BlePeripheral>).collect())
+    }
+
+    async fn peripheral(&self, id: &str) -> BleResult<Arc<dyn BlePeripheral>> {
+        // btleplug's PeripheralId is platform specific and cannot be built from a string
+        self.peripherals().await?
+            .into_iter()
+            .find(|p| p.id() == id)
+            .ok_or_else(|| format!("Peripheral {} not found", id).into())
+    }
+}
+
+pub struct BtleplugPeripheral(Peripheral);
+
+#[async_trait]
+impl BlePeripheral for BtleplugPeripheral {
+    fn id(&self) -> String {
+        self.0.id().to_string()
+    }
+
+    fn services(&self) -> BTreeSet<Service> {
+        self.0.services()
+    }
+
+    async fn advertisement(&self) -> BleResult<Advertisement> {
+        let properties = self.0.properties().await?.unwrap_or_default();
+        Ok(Advertisement {
+            local_name: properties.local_name,
+            rssi: properties.rssi,
+            services: properties.services,
+        })
+    }
+
+    async fn is_connected(&self) -> BleResult<bool> {
+        Ok(self.0.is_connected().await?)
+    }
+
+    async fn connect(&self) -> BleResult<()> {
+        Ok(self.0.connect().await?)
+    }
+
+    async fn disconnect(&self) -> BleResult<()> {
+        Ok(self.0.disconnect().await?)
+    }
+
+    async fn discover_services(&self) -> BleResult<()> {
+        Ok(self.0.discover_services().await?)
+    }
+
+    async fn read(&self, characteristic: &Characteristic) -> BleResult<Vec<u8>> {
+        Ok(self.0.read(characteristic).await?)
+    }
+
+    async fn write(&self, characteristic: &Characteristic, data: &[u8], write_type: WriteType) -> BleResult<()> {
+        Ok(self.0.write(characteristic, data, write_type).await?)
+    }
+
+    async fn subscribe(&self, characteristic: &Characteristic) -> BleResult<()> {
+        Ok(self.0.subscribe(characteristic).await?)
+    }
+
+    async fn unsubscribe(&self, characteristic: &Characteristic) -> BleResult<()> {
+        Ok(self.0.unsubscribe(characteristic).await?)
+    }
+
+    async fn notifications(&self) -> BleResult<NotificationStream> {
+        Ok(self.0.notifications().await?)
+    }
+}
@@ -0,0 +1,264 @@
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::{Arc, Mutex};
+use async_trait::async_trait;
+use btleplug::api::{Characteristic, Service, ValueNotification, WriteType};
+use serde::{Deserialize, Serialize};
+use slog::{warn, Logger};
+use tokio_stream::StreamExt;
+use uuid::Uuid;
+use super::transport::*;
+use crate::clock::Clock;
+
+/// A line of a session file. Every record carries the host time it was taken at.
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CaptureRecord {
+    /// the GATT table of a device, once its services were discovered
+    Device {
+        time_us: i64,
+        device: String,
+        local_name: Option<String>,
+        services: Vec<CapturedService>,
+    },
+    Read {
+        time_us: i64,
+        device: String,
+        characteristic: Uuid,
+        #[serde(with = "hex")]
+        value: Vec<u8>,
+    },
+    Notification {
+        time_us: i64,
+        device: String,
+        characteristic: Uuid,
+        #[serde(with = "hex")]
+        value: Vec<u8>,
+    },
+    Disconnected {
+        time_us: i64,
+        device: String,
+    },
+}
+
+impl CaptureRecord {
+    pub fn time_us(&self) -> i64 {
+        match self {
+            CaptureRecord::Device { time_us, .. }
+            | CaptureRecord::Read { time_us, .. }
+            | CaptureRecord::Notification { time_us, .. }
+            | CaptureRecord::Disconnected { time_us, .. } => *time_us,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct CapturedService {
+    pub uuid: Uuid,
+    pub characteristics: Vec<CapturedCharacteristic>,
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
+pub struct CapturedCharacteristic {
+    pub uuid: Uuid,
+    /// `CharPropFlags` bits
+    pub properties: u8,
+}
+
+mod hex {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&value.iter().map(|b| format!("{:02x}", b)).collect::<String>())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        (0..hex.len()).step_by(2)
+            .map(|i| hex.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(|| serde::de::Error::custom(format!("Invalid hex {:?}", hex)))
+    }
+}
+
+/// Writes records as JSON lines, flushing each so a session survives the app being killed
+pub struct CaptureFile {
+    writer: Mutex<BufWriter<File>>,
+    clock: Arc<dyn Clock>,
+    logger: Logger,
+}
+
+impl CaptureFile {
+    pub fn create(path: &str, clock: Arc<dyn Clock>, logger: Logger) -> BleResult<Arc<Self>> {
+        let file = File::create(path).map_err(|e| format!("Failed to create capture file {}: {}", path, e))?;
+        Ok(Arc::new(Self { writer: Mutex::new(BufWriter::new(file)), clock, logger }))
+    }
+
+    /// The time to record events at
+    fn now_us(&self) -> i64 {
+        self.clock.now().timestamp_micros()
+    }
+
+    pub fn record(&self, record: CaptureRecord) {
+        let mut writer = self.writer.lock().unwrap();
+        let written = serde_json::to_writer(&mut *writer, &record).map_err(|e| e.to_string())
+            .and_then(|_| writer.write_all(b"\n").and_then(|_| writer.flush()).map_err(|e| e.to_string()));
+        if let Err(e) = written {
+            warn!(self.logger, "Failed to write capture record"; "error" => e);
+        }
+    }
+}
+
+/// Wraps a transport, writing what its peripherals send to a `CaptureFile`: the GATT table and
+/// local name of each device, every value read and every notification, and disconnects.
+pub struct CaptureTransport {
+    inner: Arc<dyn BleTransport>,
+    file: Arc<CaptureFile>,
+}
+
+impl CaptureTransport {
+    pub fn new(inner: Arc<dyn BleTransport>, file: Arc<CaptureFile>) -> Arc<Self> {
+        Arc::new(Self { inner, file })
+    }
+
+    fn wrap(&self, peripheral: Arc<dyn BlePeripheral>) -> Arc<dyn BlePeripheral> {
+        Arc::new(CapturePeripheral { inner: peripheral, file: self.file.clone() })
+    }
+}
+
+#[async_trait]
+impl BleTransport for CaptureTransport {
+    async fn events(&self) -> BleResult<TransportEventStream> {
+        let file = self.file.clone();
+        let events = self.inner.events().await?.map(move |event| {
+            if let TransportEvent::DeviceDisconnected(device) = &event {
+                file.record(CaptureRecord::Disconnected { time_us: file.now_us(), device: device.clone() });
+            }
+            event
+        });
+        Ok(Box::pin(events))
+    }
+
+    async fn start_scan(&self, services: Vec<Uuid>) -> BleResult<()> {
+        self.inner.start_scan(services).await
+    }
+
+    async fn stop_scan(&self) -> BleResult<()> {
+        self.inner.stop_scan().await
+    }
+
+    async fn peripherals(&self) -> BleResult<Vec<Arc<dyn BlePeripheral>>> {
+        Ok(self.inner.peripherals().await?.into_iter().map(|peripheral| self.wrap(peripheral)).collect())
+    }
+
+    async fn peripheral(&self, id: &str) -> BleResult<Arc<dyn BlePeripheral>> {
+        Ok(self.wrap(self.inner.peripheral(id).await?))
+    }
+}
+
+struct CapturePeripheral {
+    inner: Arc<dyn BlePeripheral>,
+    file: Arc<CaptureFile>,
+}
+
+#[async_trait]
+impl BlePeripheral for CapturePeripheral {
+    fn id(&self) -> String {
+        self.inner.id()
+    }
+
+    fn services(&self) -> BTreeSet<Service> {
+        self.inner.services()
+    }
+
+    async fn advertisement(&self) -> BleResult<Advertisement> {
+        self.inner.advertisement().await
+    }
+
+    async fn is_connected(&self) -> BleResult<bool> {
+        self.inner.is_connected().await
+    }
+
+    async fn connect(&self) -> BleResult<()> {
+        self.inner.connect().await
+    }
+
+    async fn disconnect(&self) -> BleResult<()> {
+        self.inner.disconnect().await
+    }
+
+    async fn discover_services(&self) -> BleResult<()> {
+        self.inner.discover_services().await?;
+        let local_name = self.inner.advertisement().await.ok().and_then(|advertisement| advertisement.local_name);
+        let services = self.inner.services().into_iter()
+            .map(|service| CapturedService {
+                uuid: service.uuid,
+                characteristics: service.characteristics.iter()
+                    .map(|characteristic| CapturedCharacteristic { uuid: characteristic.uuid, properties: characteristic.properties.bits() })
+                    .collect(),
+            })
+            .collect();
+        self.file.record(CaptureRecord::Device { time_us: self.file.now_us(), device: self.inner.id(), local_name, services });
+        Ok(())
+    }
+
+    async fn read(&self, characteristic: &Characteristic) -> BleResult<Vec<u8>> {
+        let value = self.inner.read(characteristic).await?;
+        self.file.record(CaptureRecord::Read {
+            time_us: self.file.now_us(),
+            device: self.inner.id(),
+            characteristic: characteristic.uuid,
+            value: value.clone(),
+        });
+        Ok(value)
+    }
+
+    async fn write(&self, characteristic: &Characteristic, data: &[u8], write_type: WriteType) -> BleResult<()> {
+        self.inner.write(characteristic, data, write_type).await
+    }
+
+    async fn subscribe(&self, characteristic: &Characteristic) -> BleResult<()> {
+        self.inner.subscribe(characteristic).await
+    }
+
+    async fn unsubscribe(&self, characteristic: &Characteristic) -> BleResult<()> {
+        self.inner.unsubscribe(characteristic).await
+    }
+
+    async fn notifications(&self) -> BleResult<NotificationStream> {
+        let file = self.file.clone();
+        let device = self.inner.id();
+        let notifications = self.inner.notifications().await?.map(move |notification: ValueNotification| {
+            file.record(CaptureRecord::Notification {
+                time_us: file.now_us(),
+                device: device.clone(),
+                characteristic: notification.uuid,
+                value: notification.value.clone(),
+            });
+            notification
+        });
+        Ok(Box::pin(notifications))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_round_trip_as_json_lines() {
+        let record = CaptureRecord::Notification {
+            time_us: 1_700_000_000_000_000,
+            device: "dev".to_string(),
+            characteristic: Uuid::from_u128(0x2A37),
+            value: vec![0x00, 0x3c, 0xff],
+        };
+        let line = serde_json::to_string(&record).unwrap();
+        assert!(line.starts_with(r#"{"type":"notification""#), "{}", line);
+        assert!(line.contains(r#""value":"003cff""#), "{}", line);
+        assert_eq!(serde_json::from_str::<CaptureRecord>(&line).unwrap(), record);
+
+        assert!(serde_json::from_str::<CaptureRecord>(&line.replace("003cff", "003")).is_err());
+    }
+}
@@ -0,0 +1,397 @@
+use super::sequence::SequenceTracker;
+use super::transport::BleResult;
+use super::{DatapointDecoder, DecodedPacket};
+use crate::{Channel, ChannelType};
+
+const LAYOUT_VERSION: u8 = 1;
+const HEADER_LEN: usize = 9;
+const FIELD_HEADER_LEN: usize = 6;
+const SIGNED_FLAG: u8 = 0x80;
+
+/// Describes how the samples of each channel are packed into a data notification.
+///
+/// Firmware exposes this on the channel layout characteristic, little-endian:
+///
+/// | bytes | content                                          |
+/// |-------|--------------------------------------------------|
+/// | 0     | format version (1)                               |
+/// | 1     | number of fields                                 |
+/// | 2     | samples per packet and channel                   |
+/// | 3..5  | packet length in bytes (u16)                     |
+/// | 5..9  | sampling rate in mHz (u32)                       |
+///
+/// followed by one entry per field:
+///
+/// | bytes | content                                          |
+/// |-------|--------------------------------------------------|
+/// | 0     | channel type (0 = CNT, 1 = ECG, 2 = PPG, 3 = HR, |
+/// |       | 4 = RR, 5 = SPO2, 6 = STATUS)                    |
+/// | 1     | sample width in bytes (1-4), bit 7 set if signed |
+/// | 2..4  | byte offset of the first sample (u16)            |
+/// | 4     | byte stride between consecutive samples          |
+/// | 5     | name length n                                    |
+/// | 6..   | n bytes of UTF-8 name                            |
+///
+/// A CNT field carries the packet sequence counter, used to detect lost packets, and does
+/// not become a channel. Samples on the
+/// characteristic are always little-endian, sensor profiles may declare big-endian fields.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ChannelLayout {
+    pub packet_length: usize,
+    pub samples_per_packet: usize,
+    pub sampling_rate: f64,
+    pub fields: Vec<ChannelField>,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct ChannelField {
+    pub name: String,
+    pub channel_type: ChannelType,
+    pub width: usize,
+    pub signed: bool,
+    pub big_endian: bool,
+    pub offset: usize,
+    pub stride: usize,
+}
+
+impl ChannelField {
+    fn new(name: &str, channel_type: ChannelType, width: usize, signed: bool, offset: usize, stride: usize) -> Self {
+        Self {
+            name: name.to_string(),
+            channel_type,
+            width,
+            signed,
+            big_endian: false,
+            offset,
+            stride,
+        }
+    }
+
+    /// Smallest and largest raw value of a sample
+    pub fn range(&self) -> (i64, i64) {
+        let bits = 8 * self.width as u32;
+        if self.signed {
+            (-(1i64 << (bits - 1)), (1i64 << (bits - 1)) - 1)
+        } else {
+            (0, (1i64 << bits) - 1)
+        }
+    }
+
+    /// Reads the n-th sample of this field from a packet, sign extending if needed
+    fn sample(&self, packet: &[u8], n: usize) -> i32 {
+        let start = self.offset + n * self.stride;
+        let bytes = &packet[start..start + self.width];
+        let mut raw: u32 = 0;
+        for (i, byte) in bytes.iter().enumerate() {
+            let shift = if self.big_endian { self.width - 1 - i } else { i };
+            raw |= (*byte as u32) << (8 * shift);
+        }
+
+        let unused_bits = 32 - 8 * self.width as u32;
+        if self.signed {
+            ((raw << unused_bits) as i32) >> unused_bits
+        } else {
+            raw as i32
+        }
+    }
+}
+
+impl ChannelLayout {
+    pub fn parse(data: &[u8]) -> BleResult<Self> {
+        if data.len() < HEADER_LEN {
+            return Err("Channel layout too short".into());
+        }
+        if data[0] != LAYOUT_VERSION {
+            return Err(format!("Unsupported channel layout version {}", data[0]).into());
+        }
+
+        let field_count = data[1] as usize;
+        let samples_per_packet = data[2] as usize;
+        let packet_length = u16::from_le_bytes([data[3], data[4]]) as usize;
+        let sampling_rate = u32::from_le_bytes([data[5], data[6], data[7], data[8]]) as f64 / 1000.0;
+
+        let mut fields = Vec::with_capacity(field_count);
+        let mut pos = HEADER_LEN;
+        for _ in 0..field_count {
+            let header = data.get(pos..pos + FIELD_HEADER_LEN).ok_or("Channel layout truncated")?;
+            let channel_type = match header[0] {
+                0 => ChannelType::CNT,
+                1 => ChannelType::ECG,
+                2 => ChannelType::PPG,
+                3 => ChannelType::HR,
+                4 => ChannelType::RR,
+                5 => ChannelType::SPO2,
+                6 => ChannelType::STATUS,
+                other => return Err(format!("Unknown channel type {}", other).into()),
+            };
+            let width = (header[1] & !SIGNED_FLAG) as usize;
+            let signed = header[1] & SIGNED_FLAG != 0;
+            let offset = u16::from_le_bytes([header[2], header[3]]) as usize;
+            let stride = header[4] as usize;
+            let name_len = header[5] as usize;
+            pos += FIELD_HEADER_LEN;
+
+            let name = data.get(pos..pos + name_len).ok_or("Channel layout truncated")?;
+            let name = String::from_utf8_lossy(name).to_string();
+            pos += name_len;
+
+            fields.push(ChannelField { name, channel_type, width, signed, big_endian: false, offset, stride });
+        }
+
+        let layout = Self { packet_length, samples_per_packet, sampling_rate, fields };
+        layout.validate()?;
+        Ok(layout)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = vec![LAYOUT_VERSION, self.fields.len() as u8, self.samples_per_packet as u8];
+        data.extend_from_slice(&(self.packet_length as u16).to_le_bytes());
+        data.extend_from_slice(&((self.sampling_rate * 1000.0).round() as u32).to_le_bytes());
+
+        for field in self.fields.iter() {
+            let channel_type = match field.channel_type {
+                ChannelType::CNT => 0,
+                ChannelType::ECG => 1,
+                ChannelType::PPG => 2,
+                ChannelType::HR => 3,
+                ChannelType::RR => 4,
+                ChannelType::SPO2 => 5,
+                ChannelType::STATUS => 6,
+            };
+            let format = field.width as u8 | if field.signed { SIGNED_FLAG } else { 0 };
+            data.push(channel_type);
+            data.push(format);
+            data.extend_from_slice(&(field.offset as u16).to_le_bytes());
+            data.push(field.stride as u8);
+            data.push(field.name.len() as u8);
+            data.extend_from_slice(field.name.as_bytes());
+        }
+        data
+    }
+
+    pub(crate) fn validate(&self) -> BleResult<()> {
+        if self.samples_per_packet == 0 {
+            return Err("Channel layout without samples".into());
+        }
+        // samples are timestamped from the rate
+        if self.sampling_rate <= 0.0 || !self.sampling_rate.is_finite() {
+            return Err("Channel layout without sampling rate".into());
+        }
+
+        for field in self.fields.iter() {
+            if !(1..=4).contains(&field.width) {
+                return Err(format!("Invalid sample width {} for {}", field.width, field.name).into());
+            }
+
+            let samples = if field.channel_type == ChannelType::CNT { 1 } else { self.samples_per_packet };
+            if samples > 1 && field.stride == 0 {
+                return Err(format!("Channel {} has no stride between its samples", field.name).into());
+            }
+            let end = field.offset + (samples - 1) * field.stride + field.width;
+            if end > self.packet_length {
+                return Err(format!("Channel {} exceeds packet length", field.name).into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Layouts of the firmware without a channel layout characteristic: three frames of
+    /// ECG (i16) and PPG green, red, IR (u16) behind a one byte packet counter, at 32 Hz
+    pub fn legacy(has_ecg: bool) -> Self {
+        let mut fields = vec![ChannelField::new("Counter", ChannelType::CNT, 1, false, 0, 0)];
+        if has_ecg {
+            fields.push(ChannelField::new("ECG", ChannelType::ECG, 2, true, 1, 8));
+        }
+        fields.push(ChannelField::new("PPG green", ChannelType::PPG, 2, false, 3, 8));
+        fields.push(ChannelField::new("PPG red", ChannelType::PPG, 2, false, 5, 8));
+        fields.push(ChannelField::new("PPG IR", ChannelType::PPG, 2, false, 7, 8));
+
+        Self {
+            packet_length: 25,
+            samples_per_packet: 3,
+            sampling_rate: 32.0,
+            fields,
+        }
+    }
+
+    /// The sequence counter field, if the packets have one
+    pub fn counter(&self) -> Option<&ChannelField> {
+        self.fields.iter().find(|f| f.channel_type == ChannelType::CNT)
+    }
+
+    fn data_fields(&self) -> impl Iterator<Item=&ChannelField> {
+        self.fields.iter().filter(|f| f.channel_type != ChannelType::CNT)
+    }
+
+    /// The field of every channel id, in channel order
+    pub fn channel_fields(&self, device_id: &str) -> Vec<(String, &ChannelField)> {
+        self.data_fields().enumerate()
+            .map(|(n, field)| (format!("{}-{}", device_id, n), field))
+            .collect()
+    }
+
+    pub fn channels(&self, device_id: &str) -> Vec<Channel> {
+        self.data_fields().enumerate().map(|(n, field)| Channel {
+            id: format!("{}-{}", device_id, n),
+            name: field.name.clone(),
+            channel_type: field.channel_type.clone(),
+            signal_quality: None,
+            samples_received: 0,
+            samples_lost: 0,
+        }).collect()
+    }
+
+    /// Builds a decoder mapping a data notification to the samples of every channel id.
+    /// If the layout has a counter, the samples of lost packets are prepended as `None`.
+    /// Packets shorter than the layout, repeated packets and packets arriving after their
+    /// successors decode to nothing.
+    pub fn decoder(&self, device_id: &str) -> DatapointDecoder {
+        let fields: Vec<(String, ChannelField)> = self.channel_fields(device_id).into_iter()
+            .map(|(id, field)| (id, field.clone()))
+            .collect();
+        let counter = self.counter().cloned();
+        let mut tracker = counter.as_ref().map(|field| SequenceTracker::new(field.width));
+        let packet_length = self.packet_length;
+        let samples_per_packet = self.samples_per_packet;
+
+        Box::new(move |value: Vec<u8>| {
+            let mut decoded = DecodedPacket::default();
+            if value.len() < packet_length {
+                return decoded;
+            }
+
+            if let (Some(field), Some(tracker)) = (counter.as_ref(), tracker.as_mut()) {
+                let sequence = field.sample(&value, 0) as u32;
+                match tracker.track(sequence) {
+                    Some(lost_packets) => decoded.lost_packets = lost_packets,
+                    None => return decoded,
+                }
+                decoded.sequence = Some(sequence);
+            }
+
+            let missing = decoded.lost_packets as usize * samples_per_packet;
+            for (channel_id, field) in fields.iter() {
+                let mut samples = vec![None; missing];
+                samples.extend((0..samples_per_packet).map(|n| Some(field.sample(&value, n))));
+                decoded.samples.insert(channel_id.clone(), samples);
+            }
+            decoded
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn legacy_packet(counter: u8) -> Vec<u8> {
+        let mut packet = vec![counter];
+        for frame in 0..3u16 {
+            packet.extend_from_slice(&(-100i16 - frame as i16).to_le_bytes());
+            packet.extend_from_slice(&(40000 + frame).to_le_bytes());
+            packet.extend_from_slice(&(2000 + frame).to_le_bytes());
+            packet.extend_from_slice(&(3000 + frame).to_le_bytes());
+        }
+        packet
+    }
+
+    #[test]
+    fn legacy_layout_decodes_ecg_device() {
+        let mut decoder = ChannelLayout::legacy(true).decoder("dev");
+        let decoded = decoder(legacy_packet(7)).samples;
+
+        assert_eq!(decoded["dev-0"], vec![Some(-100), Some(-101), Some(-102)]);
+        assert_eq!(decoded["dev-1"], vec![Some(40000), Some(40001), Some(40002)]);
+        assert_eq!(decoded["dev-2"], vec![Some(2000), Some(2001), Some(2002)]);
+        assert_eq!(decoded["dev-3"], vec![Some(3000), Some(3001), Some(3002)]);
+    }
+
+    #[test]
+    fn fills_lost_packets_with_none() {
+        let mut decoder = ChannelLayout::legacy(true).decoder("dev");
+        assert_eq!(decoder(legacy_packet(254)).lost_packets, 0);
+
+        let decoded = decoder(legacy_packet(1));
+        assert_eq!(decoded.lost_packets, 2);
+        assert_eq!(decoded.samples["dev-1"].len(), 9);
+        assert!(decoded.samples["dev-1"][..6].iter().all(|x| x.is_none()));
+        assert_eq!(decoded.samples["dev-1"][6], Some(40000));
+    }
+
+    #[test]
+    fn drops_reordered_packets_without_filling() {
+        let mut decoder = ChannelLayout::legacy(true).decoder("dev");
+        decoder(legacy_packet(254));
+        assert_eq!(decoder(legacy_packet(253)), DecodedPacket::default());
+
+        let decoded = decoder(legacy_packet(255));
+        assert_eq!(decoded.lost_packets, 0);
+        assert_eq!(decoded.samples["dev-1"].len(), 3);
+    }
+
+    #[test]
+    fn legacy_layout_without_ecg_renumbers_channels() {
+        let layout = ChannelLayout::legacy(false);
+        let channels = layout.channels("dev");
+        assert_eq!(channels.len(), 3);
+        assert_eq!(channels[0].id, "dev-0");
+        assert_eq!(channels[0].name, "PPG green");
+
+        let decoded = layout.decoder("dev")(legacy_packet(7)).samples;
+        assert_eq!(decoded["dev-0"], vec![Some(40000), Some(40001), Some(40002)]);
+    }
+
+    #[test]
+    fn encode_parse_roundtrip() {
+        let layout = ChannelLayout {
+            packet_length: 13,
+            samples_per_packet: 2,
+            sampling_rate: 128.5,
+            fields: vec![
+                ChannelField::new("Counter", ChannelType::CNT, 1, false, 0, 0),
+                ChannelField::new("ECG", ChannelType::ECG, 3, true, 1, 6),
+                ChannelField::new("PPG", ChannelType::PPG, 3, false, 4, 6),
+            ],
+        };
+
+        assert_eq!(ChannelLayout::parse(&layout.encode()).unwrap(), layout);
+    }
+
+    #[test]
+    fn sign_extends_24_bit_samples() {
+        let field = ChannelField::new("ECG", ChannelType::ECG, 3, true, 0, 3);
+        assert_eq!(field.sample(&[0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x80], 0), -1);
+        assert_eq!(field.sample(&[0xFF, 0xFF, 0xFF, 0x00, 0x00, 0x80], 1), -8388608);
+    }
+
+    #[test]
+    fn reads_big_endian_samples() {
+        let mut field = ChannelField::new("PPG", ChannelType::PPG, 2, false, 0, 2);
+        field.big_endian = true;
+        assert_eq!(field.sample(&[0x12, 0x34, 0xAB, 0xCD], 1), 0xABCD);
+    }
+
+    #[test]
+    fn short_packets_decode_to_nothing() {
+        let mut decoder = ChannelLayout::legacy(true).decoder("dev");
+        assert!(decoder(vec![0; 10]).samples.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_layouts() {
+        let mut overflowing = ChannelLayout::legacy(true);
+        overflowing.packet_length = 20;
+        assert!(ChannelLayout::parse(&overflowing.encode()).is_err());
+
+        let mut without_rate = ChannelLayout::legacy(true);
+        without_rate.sampling_rate = 0.0;
+        assert!(ChannelLayout::parse(&without_rate.encode()).is_err());
+
+        let mut wrong_version = ChannelLayout::legacy(true).encode();
+        wrong_version[0] = 9;
+        assert!(ChannelLayout::parse(&wrong_version).is_err());
+
+        let truncated = ChannelLayout::legacy(true).encode();
+        assert!(ChannelLayout::parse(&truncated[..truncated.len() - 2]).is_err());
+    }
+}
@@ -0,0 +1,231 @@
+use std::collections::VecDeque;
+use chrono::{DateTime, Utc};
+
+/// Syncs kept for fitting the clock model
+const MODEL_CAPACITY: usize = 16;
+/// A sync further off the fit than this many uncertainties is a step of the device clock
+const JUMP_UNCERTAINTIES: f64 = 10.0;
+/// Steps smaller than this are left to the fit, for clocks synced without round trip time
+const MIN_JUMP_US: f64 = 10_000.0;
+
+/// One read of the device clock, NTP style: the device time is assumed to be taken
+/// halfway between sending the request and receiving the answer.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct SyncRound {
+    /// local time in the middle of the exchange, in us since the epoch
+    pub local_us: i64,
+    /// device clock minus local clock
+    pub offset_us: i64,
+    pub rtt_us: i64,
+}
+
+impl SyncRound {
+    pub fn new(sent: DateTime<Utc>, device_time: DateTime<Utc>, received: DateTime<Utc>) -> Self {
+        let sent = sent.timestamp_micros();
+        let received = received.timestamp_micros();
+        let local_us = sent + (received - sent) / 2;
+        Self {
+            local_us,
+            offset_us: device_time.timestamp_micros() - local_us,
+            rtt_us: received - sent,
+        }
+    }
+}
+
+/// Combines the rounds of one sync: only the fastest quarter of the rounds is kept, as queueing
+/// delays make slow exchanges asymmetric, and the median offset of those is taken
+pub fn estimate(rounds: &[SyncRound]) -> Option<SyncRound> {
+    if rounds.is_empty() {
+        return None;
+    }
+
+    let mut fastest = rounds.to_vec();
+    fastest.sort_by_key(|r| r.rtt_us);
+    fastest.truncate(rounds.len().div_ceil(4));
+    fastest.sort_by_key(|r| r.offset_us);
+
+    let median = fastest[fastest.len() / 2];
+    Some(SyncRound {
+        local_us: median.local_us,
+        offset_us: median.offset_us,
+        rtt_us: fastest.iter().map(|r| r.rtt_us).min().unwrap_or(median.rtt_us),
+    })
+}
+
+/// State of a device clock as reported on `Device`
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct ClockEstimate {
+    pub offset_us: i64,
+    pub skew_ppm: f64,
+    pub uncertainty_us: i64,
+    pub rtt_us: i64,
+}
+
+/// Linear model of a device clock, offset plus skew, fitted over successive syncs
+#[derive(Debug, Default)]
+pub struct ClockModel {
+    syncs: VecDeque<SyncRound>,
+}
+
+impl ClockModel {
+    /// Adds a sync to the fit. A sync far off the fit means the device clock stepped, fitting
+    /// it would turn the step into skew for many syncs, so the model starts over from it and
+    /// true is returned.
+    pub fn add(&mut self, sync: SyncRound) -> bool {
+        let jumped = self.jumped(&sync);
+        if jumped {
+            self.syncs.clear();
+        }
+        if self.syncs.len() == MODEL_CAPACITY {
+            self.syncs.pop_front();
+        }
+        self.syncs.push_back(sync);
+        jumped
+    }
+
+    /// Whether the offset of `sync` is too far from the fit to be drift. A single sync has no
+    /// skew to extrapolate with, so the second one is always taken.
+    fn jumped(&self, sync: &SyncRound) -> bool {
+        if self.syncs.len() < 2 {
+            return false;
+        }
+        let (Some(expected), Some(estimate)) = (self.offset_at(sync.local_us), self.estimate()) else {
+            return false;
+        };
+        let uncertainty = estimate.uncertainty_us as f64 + sync.rtt_us as f64 / 2.0;
+        (sync.offset_us as f64 - expected).abs() > (JUMP_UNCERTAINTIES * uncertainty).max(MIN_JUMP_US)
+    }
+
+    /// Least squares fit of offset over local time, as (offset at the latest sync, slope)
+    fn fit(&self) -> Option<(f64, f64)> {
+        let latest = self.syncs.back()?;
+        if self.syncs.len() < 2 {
+            return Some((latest.offset_us as f64, 0.0));
+        }
+
+        // relative to the latest sync, to keep the numbers small
+        let points: Vec<(f64, f64)> = self.syncs.iter()
+            .map(|s| ((s.local_us - latest.local_us) as f64, s.offset_us as f64))
+            .collect();
+        let n = points.len() as f64;
+        let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+        let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+        let var_x = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum::<f64>();
+        if var_x == 0.0 {
+            return Some((mean_y, 0.0));
+        }
+
+        let slope = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum::<f64>() / var_x;
+        Some((mean_y - slope * mean_x, slope))
+    }
+
+    /// Device clock minus local clock at the given local time
+    pub fn offset_at(&self, local_us: i64) -> Option<f64> {
+        let latest = self.syncs.back()?;
+        let (offset, slope) = self.fit()?;
+        Some(offset + slope * (local_us - latest.local_us) as f64)
+    }
+
+    /// Offset and skew from the fit. The uncertainty combines half the round trip time of the
+    /// latest sync, the bound of its offset error, with how far the syncs scatter around the fit.
+    pub fn estimate(&self) -> Option<ClockEstimate> {
+        let latest = self.syncs.back()?;
+        let (offset, slope) = self.fit()?;
+
+        let residual_sq = self.syncs.iter()
+            .map(|s| (s.offset_us as f64 - (offset + slope * (s.local_us - latest.local_us) as f64)).powi(2))
+            .sum::<f64>() / self.syncs.len() as f64;
+        let half_rtt = latest.rtt_us as f64 / 2.0;
+
+        Some(ClockEstimate {
+            offset_us: offset.round() as i64,
+            skew_ppm: slope * 1e6,
+            uncertainty_us: (half_rtt * half_rtt + residual_sq).sqrt().round() as i64,
+            rtt_us: latest.rtt_us,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn round(local_us: i64, offset_us: i64, rtt_us: i64) -> SyncRound {
+        SyncRound { local_us, offset_us, rtt_us }
+    }
+
+    #[test]
+    fn takes_device_time_halfway_through_the_exchange() {
+        let sent = Utc::now();
+        let round = SyncRound::new(sent, sent + Duration::milliseconds(505), sent + Duration::milliseconds(10));
+        assert_eq!(round.rtt_us, 10_000);
+        assert_eq!(round.offset_us, 500_000);
+    }
+
+    #[test]
+    fn estimates_from_fastest_rounds() {
+        let rounds = [
+            round(0, 900, 40_000),
+            round(1, 105, 2_000),
+            round(2, 100, 2_100),
+            round(3, -700, 30_000),
+            round(4, 110, 2_200),
+            round(5, 2_000, 50_000),
+            round(6, 95, 1_900),
+            round(7, 800, 20_000),
+        ];
+
+        let estimate = estimate(&rounds).unwrap();
+        assert_eq!(estimate.offset_us, 105);
+        assert_eq!(estimate.rtt_us, 1_900);
+        assert!(super::estimate(&[]).is_none());
+    }
+
+    #[test]
+    fn fits_offset_and_skew() {
+        let mut model = ClockModel::default();
+        // 50 ppm fast, 1 ms ahead at the start
+        for i in 0..10 {
+            let local_us = i * 60_000_000;
+            let jitter = if i % 2 == 0 { 40 } else { -40 };
+            model.add(round(local_us, 1_000 + local_us / 20_000 + jitter, 4_000));
+        }
+
+        let estimate = model.estimate().unwrap();
+        assert!((estimate.skew_ppm - 50.0).abs() < 0.1, "skew {}", estimate.skew_ppm);
+        assert!((estimate.offset_us - 28_000).abs() < 50, "offset {}", estimate.offset_us);
+        assert!(estimate.uncertainty_us >= 2_000);
+
+        let ahead = model.offset_at(10 * 60_000_000).unwrap();
+        assert!((ahead - 31_000.0).abs() < 50.0, "extrapolated {}", ahead);
+    }
+
+    #[test]
+    fn starts_over_when_the_device_clock_steps() {
+        let mut model = ClockModel::default();
+        // 100 ppm fast, then 2 s ahead from the sixth sync on
+        let offset = |i: i64| i * 6_000 + if i >= 5 { 2_000_000 } else { 0 };
+        let jumped: Vec<bool> = (0..8).map(|i| model.add(round(i * 60_000_000, offset(i), 4_000))).collect();
+        assert_eq!(jumped, [false, false, false, false, false, true, false, false]);
+
+        let estimate = model.estimate().unwrap();
+        assert!((estimate.skew_ppm - 100.0).abs() < 0.1, "skew {}", estimate.skew_ppm);
+        assert_eq!(estimate.offset_us, offset(7));
+
+        // drift and jitter alone do not restart the model
+        let mut model = ClockModel::default();
+        assert!((0..20).all(|i| !model.add(round(i * 60_000_000, i * 6_000 + if i % 2 == 0 { 1_500 } else { -1_500 }, 4_000))));
+    }
+
+    #[test]
+    fn single_sync_has_no_skew() {
+        let mut model = ClockModel::default();
+        model.add(round(0, -250, 1_000));
+
+        let estimate = model.estimate().unwrap();
+        assert_eq!(estimate.offset_us, -250);
+        assert_eq!(estimate.skew_ppm, 0.0);
+        assert_eq!(estimate.uncertainty_us, 500);
+    }
+}
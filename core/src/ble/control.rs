@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::Instant;
+use crate::{CommandOutcome, EcgGain};
+
+/// Opcode of the notifications the device answers every command with
+const RESPONSE: u8 = 0x80;
+
+/// Commands of the VitalVision control characteristic. A command is written as its opcode, a
+/// sequence number and its parameters, little endian. The device answers with a notification
+/// of `RESPONSE`, the sequence number, the opcode and a status byte, 0 meaning success.
+#[derive(Debug, PartialEq, Clone)]
+pub enum ControlCommand {
+    StartStreaming,
+    StopStreaming,
+    /// in Hz
+    SetSamplingRate(u16),
+    /// green, red and IR in steps of `LED_CURRENT_STEP_MA`
+    SetLedCurrents(u8, u8, u8),
+    SetEcgGain(EcgGain),
+    /// blinks the LED for the given seconds, to find the device among others
+    Identify(u8),
+}
+
+/// Resolution of the LED current parameters
+pub const LED_CURRENT_STEP_MA: f32 = 0.2;
+
+impl ControlCommand {
+    fn opcode(&self) -> u8 {
+        match self {
+            Self::StartStreaming => 0x01,
+            Self::StopStreaming => 0x02,
+            Self::SetSamplingRate(_) => 0x03,
+            Self::SetLedCurrents(..) => 0x04,
+            Self::SetEcgGain(_) => 0x05,
+            Self::Identify(_) => 0x06,
+        }
+    }
+
+    pub fn encode(&self, sequence: u8) -> Vec<u8> {
+        let mut frame = vec![self.opcode(), sequence];
+        match self {
+            Self::StartStreaming | Self::StopStreaming => {}
+            Self::SetSamplingRate(rate) => frame.extend_from_slice(&rate.to_le_bytes()),
+            Self::SetLedCurrents(green, red, ir) => frame.extend_from_slice(&[*green, *red, *ir]),
+            Self::SetEcgGain(gain) => frame.push(gain.factor()),
+            Self::Identify(seconds) => frame.push(*seconds),
+        }
+        frame
+    }
+
+    /// Converts currents in mA to parameter steps, `None` if one is out of range
+    pub fn led_currents(green_ma: f32, red_ma: f32, ir_ma: f32) -> Option<Self> {
+        let step = |ma: f32| {
+            let steps = (ma / LED_CURRENT_STEP_MA).round();
+            (0.0..=u8::MAX as f32).contains(&steps).then_some(steps as u8)
+        };
+        Some(Self::SetLedCurrents(step(green_ma)?, step(red_ma)?, step(ir_ma)?))
+    }
+}
+
+impl EcgGain {
+    fn factor(&self) -> u8 {
+        match self {
+            EcgGain::X1 => 1,
+            EcgGain::X2 => 2,
+            EcgGain::X3 => 3,
+            EcgGain::X4 => 4,
+            EcgGain::X6 => 6,
+            EcgGain::X8 => 8,
+            EcgGain::X12 => 12,
+        }
+    }
+}
+
+/// Id of a control command, numbered apart for the app and for the auto-gain so they never mix
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone, Copy)]
+pub enum CommandId {
+    /// returned to the app, which is told the outcome
+    App(u32),
+    /// an LED current change of the auto-gain, not reported to the app
+    AutoGain(u32),
+}
+
+struct Pending {
+    command_id: CommandId,
+    command: ControlCommand,
+    deadline: Instant,
+}
+
+/// Commands written to a device and waiting for their response, by sequence number
+pub struct ControlChannel {
+    timeout: Duration,
+    next_sequence: u8,
+    pending: HashMap<u8, Pending>,
+    /// commands whose sequence number was reused before they were answered, due at once
+    displaced: Vec<Pending>,
+}
+
+impl ControlChannel {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            next_sequence: 0,
+            pending: HashMap::new(),
+            displaced: Vec::new(),
+        }
+    }
+
+    /// Registers a command and returns the frame to write. With 256 commands in flight the
+    /// oldest sequence number is reused, its command then times out right away, as a late
+    /// response could not be told apart from the new one's.
+    pub fn send(&mut self, command_id: CommandId, command: ControlCommand) -> Vec<u8> {
+        let sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+
+        let now = Instant::now();
+        let frame = command.encode(sequence);
+        let displaced = self.pending.insert(sequence, Pending {
+            command_id,
+            command,
+            deadline: now + self.timeout,
+        });
+        if let Some(displaced) = displaced {
+            self.displaced.push(Pending { deadline: now, ..displaced });
+        }
+        frame
+    }
+
+    /// Drops a command whose write failed
+    pub fn cancel(&mut self, command_id: CommandId) {
+        self.pending.retain(|_, pending| pending.command_id != command_id);
+    }
+
+    /// Matches a notification to its command, `None` for malformed or unexpected responses
+    pub fn receive(&mut self, response: &[u8]) -> Option<(CommandId, ControlCommand, CommandOutcome)> {
+        let [RESPONSE, sequence, opcode, status, ..] = *response else {
+            return None;
+        };
+        if self.pending.get(&sequence)?.command.opcode() != opcode {
+            return None;
+        }
+
+        let pending = self.pending.remove(&sequence)?;
+        let outcome = match status {
+            0 => CommandOutcome::Acknowledged,
+            status => CommandOutcome::Rejected { status },
+        };
+        Some((pending.command_id, pending.command, outcome))
+    }
+
+    /// Removes all commands, e.g. when the device disconnected, and returns their ids
+    pub fn drain(&mut self) -> Vec<CommandId> {
+        let mut ids: Vec<CommandId> = self.pending.drain().map(|(_, pending)| pending)
+            .chain(self.displaced.drain(..))
+            .map(|pending| pending.command_id)
+            .collect();
+        ids.sort();
+        ids
+    }
+
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.pending.values().chain(self.displaced.iter()).map(|pending| pending.deadline).min()
+    }
+
+    /// Removes and returns the ids of the commands past their deadline
+    pub fn expire(&mut self, now: Instant) -> Vec<CommandId> {
+        let mut expired: Vec<CommandId> = self.pending.values()
+            .filter(|pending| pending.deadline <= now)
+            .map(|pending| pending.command_id)
+            .collect();
+        self.pending.retain(|_, pending| pending.deadline > now);
+        expired.extend(self.displaced.iter().filter(|pending| pending.deadline <= now).map(|pending| pending.command_id));
+        self.displaced.retain(|pending| pending.deadline > now);
+        expired.sort();
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_commands() {
+        assert_eq!(ControlCommand::StartStreaming.encode(7), vec![0x01, 7]);
+        assert_eq!(ControlCommand::SetSamplingRate(250).encode(0), vec![0x03, 0, 250, 0]);
+        assert_eq!(ControlCommand::SetEcgGain(EcgGain::X12).encode(1), vec![0x05, 1, 12]);
+        assert_eq!(ControlCommand::led_currents(10.0, 0.2, 51.0), Some(ControlCommand::SetLedCurrents(50, 1, 255)));
+        assert_eq!(ControlCommand::led_currents(60.0, 0.0, 0.0), None);
+        assert_eq!(ControlCommand::led_currents(-1.0, 0.0, 0.0), None);
+    }
+
+    use CommandId::App;
+
+    #[test]
+    fn matches_responses_to_commands() {
+        let mut channel = ControlChannel::new(Duration::from_secs(1));
+        assert_eq!(channel.send(App(10), ControlCommand::Identify(3)), vec![0x06, 0, 3]);
+        assert_eq!(channel.send(App(11), ControlCommand::StopStreaming), vec![0x02, 1]);
+
+        // wrong opcode, unknown sequence, too short
+        assert_eq!(channel.receive(&[RESPONSE, 0, 0x02, 0]), None);
+        assert_eq!(channel.receive(&[RESPONSE, 5, 0x06, 0]), None);
+        assert_eq!(channel.receive(&[RESPONSE, 0]), None);
+
+        assert_eq!(channel.receive(&[RESPONSE, 1, 0x02, 2]), Some((App(11), ControlCommand::StopStreaming, CommandOutcome::Rejected { status: 2 })));
+        assert_eq!(channel.receive(&[RESPONSE, 0, 0x06, 0]), Some((App(10), ControlCommand::Identify(3), CommandOutcome::Acknowledged)));
+        assert_eq!(channel.next_deadline(), None);
+    }
+
+    #[test]
+    fn expires_unanswered_commands() {
+        let mut channel = ControlChannel::new(Duration::from_millis(100));
+        channel.send(App(1), ControlCommand::StartStreaming);
+        channel.send(App(2), ControlCommand::StopStreaming);
+        std::thread::sleep(Duration::from_millis(1));
+        channel.send(App(3), ControlCommand::Identify(1));
+        channel.send(CommandId::AutoGain(2), ControlCommand::SetLedCurrents(1, 1, 1));
+        channel.cancel(App(2));
+
+        let deadline = channel.next_deadline().unwrap();
+        assert!(channel.expire(deadline - Duration::from_millis(1)).is_empty());
+        assert_eq!(channel.expire(deadline), vec![App(1)]);
+        assert_eq!(channel.drain(), vec![App(3), CommandId::AutoGain(2)]);
+        assert_eq!(channel.next_deadline(), None);
+    }
+
+    #[test]
+    fn times_out_commands_whose_sequence_number_is_reused() {
+        let mut channel = ControlChannel::new(Duration::from_secs(60));
+        for command_id in 0..256 {
+            channel.send(App(command_id), ControlCommand::Identify(1));
+        }
+        assert_eq!(channel.send(App(256), ControlCommand::StopStreaming), vec![0x02, 0]);
+
+        // due at once, not after the timeout
+        let now = Instant::now();
+        assert!(channel.next_deadline().unwrap() <= now);
+        assert_eq!(channel.expire(now), vec![App(0)]);
+        assert_eq!(channel.receive(&[RESPONSE, 0, 0x02, 0]), Some((App(256), ControlCommand::StopStreaming, CommandOutcome::Acknowledged)));
+        assert_eq!(channel.drain().len(), 255);
+    }
+}
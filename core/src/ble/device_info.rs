@@ -0,0 +1,92 @@
+/// Contents of the Device Information Service. The serial and the model identify the device,
+/// the other fields are only set if the device has them.
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct DeviceInformation {
+    pub serial: u16,
+    pub model: String,
+    pub manufacturer: Option<String>,
+    pub hardware_revision: Option<String>,
+    pub firmware_revision: Option<String>,
+    pub software_revision: Option<String>,
+    pub system_id: Option<String>,
+}
+
+/// Reads a UTF-8 string characteristic. Firmware pads these with NULs or spaces and sometimes
+/// sends invalid UTF-8, which is replaced rather than rejected. Empty strings count as absent.
+pub fn parse_string(data: &[u8]) -> Option<String> {
+    let string = String::from_utf8_lossy(data);
+    let string = string.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+    (!string.is_empty()).then(|| string.to_string())
+}
+
+/// Reads the serial number string as a decimal u16, leading zeros and padding allowed
+pub fn parse_serial(data: &[u8]) -> Result<u16, String> {
+    let serial = parse_string(data).unwrap_or_default();
+    serial.parse::<u16>().map_err(|e| format!("Invalid serial number {:?}: {}", serial, e))
+}
+
+/// Derives a serial for third-party sensors, whose serial numbers are often not decimal or
+/// missing and then identified by `fallback`. Decimal u16 serials are kept, anything else is
+/// folded into a u16 by FNV-1a.
+pub fn fold_serial(serial: Option<&str>, fallback: &str) -> u16 {
+    let serial = serial.unwrap_or(fallback);
+    serial.parse::<u16>().unwrap_or_else(|_| {
+        let hash = serial.bytes().fold(0x811c9dc5u32, |hash, byte| (hash ^ byte as u32).wrapping_mul(0x01000193));
+        (hash >> 16) as u16 ^ hash as u16
+    })
+}
+
+/// Formats the System ID as hex. The 8 byte form of the spec, a 40 bit manufacturer identifier
+/// and a 24 bit OUI both sent little endian, is shown as `OUI-manufacturer identifier`, other
+/// lengths as their bytes in the order received.
+pub fn parse_system_id(data: &[u8]) -> Option<String> {
+    if data.is_empty() {
+        return None;
+    }
+
+    let hex = |bytes: &[u8]| bytes.iter().map(|b| format!("{:02X}", b)).collect::<String>();
+    if data.len() == 8 {
+        let mut manufacturer = data[..5].to_vec();
+        let mut oui = data[5..].to_vec();
+        manufacturer.reverse();
+        oui.reverse();
+        Some(format!("{}-{}", hex(&oui), hex(&manufacturer)))
+    } else {
+        Some(hex(data))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tolerates_padded_and_invalid_strings() {
+        assert_eq!(parse_string(b"1.2.3\0\0"), Some("1.2.3".to_string()));
+        assert_eq!(parse_string(b" rev B "), Some("rev B".to_string()));
+        assert_eq!(parse_string(b"\0\0"), None);
+        assert_eq!(parse_string(&[b'v', 0xff, b'2']), Some("v\u{fffd}2".to_string()));
+    }
+
+    #[test]
+    fn parses_serials() {
+        assert_eq!(parse_serial(b"0071\0"), Ok(71));
+        assert!(parse_serial(b"7x1").unwrap_err().contains("Invalid serial number \"7x1\""));
+        assert!(parse_serial(b"").is_err());
+    }
+
+    #[test]
+    fn folds_third_party_serials() {
+        assert_eq!(fold_serial(Some("0071"), "id"), 71);
+        assert_eq!(fold_serial(Some("C9A1B2C3"), "id"), fold_serial(Some("C9A1B2C3"), "other"));
+        assert_ne!(fold_serial(Some("C9A1B2C3"), "id"), fold_serial(Some("C9A1B2C4"), "id"));
+        assert_eq!(fold_serial(None, "AA:BB:CC"), fold_serial(Some("AA:BB:CC"), "id"));
+    }
+
+    #[test]
+    fn formats_system_ids() {
+        assert_eq!(parse_system_id(&[0x05, 0x04, 0x03, 0x02, 0x01, 0xC3, 0xB2, 0xA1]), Some("A1B2C3-0102030405".to_string()));
+        assert_eq!(parse_system_id(&[0xAB, 0x01]), Some("AB01".to_string()));
+        assert_eq!(parse_system_id(&[]), None);
+    }
+}
@@ -0,0 +1,444 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
+use std::time::Duration;
+use async_trait::async_trait;
+use btleplug::api::{CharPropFlags, Characteristic, Service, ValueNotification, WriteType};
+use chrono::{DateTime, Utc};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::time::Instant;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use uuid::Uuid;
+use super::ble_date_converter::{ble_data_to_time, time_to_ble_data};
+use super::transport::*;
+use super::*;
+
+type EventSubscribers = Arc<Mutex<Vec<UnboundedSender<TransportEvent>>>>;
+/// Answers a frame written to the control characteristic, `None` to stay silent
+type ControlResponder = Box<dyn Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync>;
+
+/// In-memory `BleTransport` that hands out scriptable `FakePeripheral`s,
+/// so the BLE pipeline can run without an adapter.
+pub struct FakeTransport {
+    peripherals: Mutex<Vec<Arc<FakePeripheral>>>,
+    scan_filter: Mutex<Option<Vec<Uuid>>>,
+    subscribers: EventSubscribers,
+}
+
+impl FakeTransport {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self {
+            peripherals: Mutex::new(vec![]),
+            scan_filter: Mutex::new(None),
+            subscribers: Arc::new(Mutex::new(vec![])),
+        })
+    }
+
+    /// Makes the peripheral known to the transport, announcing it if a matching scan is running
+    pub fn add_peripheral(&self, peripheral: Arc<FakePeripheral>) {
+        *peripheral.transport_events.lock().unwrap() = Some(self.subscribers.clone());
+        self.peripherals.lock().unwrap().push(peripheral.clone());
+        self.announce(&peripheral);
+    }
+
+    /// Advertises the peripheral again, e.g. after it went out of range
+    pub fn announce(&self, peripheral: &FakePeripheral) {
+        let scan_filter = self.scan_filter.lock().unwrap().clone();
+        if let Some(services) = scan_filter {
+            let matches = services.is_empty() || services.iter().any(|s| peripheral.advertised_services.contains(s));
+            if matches && !peripheral.connected.load(Ordering::SeqCst) {
+                emit(&self.subscribers, TransportEvent::DeviceDiscovered(peripheral.id.clone()));
+            }
+        }
+    }
+}
+
+fn emit(subscribers: &Mutex<Vec<UnboundedSender<TransportEvent>>>, event: TransportEvent) {
+    subscribers.lock().unwrap().retain(|tx| tx.send(event.clone()).is_ok());
+}
+
+#[async_trait]
+impl BleTransport for FakeTransport {
+    async fn events(&self) -> BleResult<TransportEventStream> {
+        let (tx, rx) = unbounded_channel();
+        self.subscribers.lock().unwrap().push(tx);
+        Ok(Box::pin(UnboundedReceiverStream::new(rx)))
+    }
+
+    async fn start_scan(&self, services: Vec<Uuid>) -> BleResult<()> {
+        *self.scan_filter.lock().unwrap() = Some(services);
+        let peripherals = self.peripherals.lock().unwrap().clone();
+        for peripheral in peripherals.iter() {
+            self.announce(peripheral);
+        }
+        Ok(())
+    }
+
+    async fn stop_scan(&self) -> BleResult<()> {
+        *self.scan_filter.lock().unwrap() = None;
+        Ok(())
+    }
+
+    async fn peripherals(&self) -> BleResult<Vec<Arc<dyn BlePeripheral>>> {
+        let peripherals = self.peripherals.lock().unwrap();
+        Ok(peripherals.iter().map(|p| p.clone() as Arc<dyn BlePeripheral>).collect())
+    }
+
+    async fn peripheral(&self, id: &str) -> BleResult<Arc<dyn BlePeripheral>> {
+        let peripherals = self.peripherals.lock().unwrap();
+        peripherals.iter()
+            .find(|p| p.id == id)
+            .map(|p| p.clone() as Arc<dyn BlePeripheral>)
+            .ok_or_else(|| format!("Peripheral {} not found", id).into())
+    }
+}
+
+/// A GATT peripheral living in memory. Characteristic values are static unless written,
+/// except for the current time characteristic, which runs like a real clock.
+pub struct FakePeripheral {
+    id: String,
+    local_name: Option<String>,
+    rssi: Mutex<Option<i16>>,
+    advertised_services: Vec<Uuid>,
+    services: BTreeSet<Service>,
+    values: Mutex<HashMap<Uuid, Vec<u8>>>,
+    clock: Mutex<(DateTime<Utc>, Instant)>,
+    clock_offset_us: AtomicI64,
+    clock_skew_ppm: f64,
+    latency: Duration,
+    hang_on_connect: bool,
+    control_responder: Option<ControlResponder>,
+    connected: AtomicBool,
+    connectable: AtomicBool,
+    subscriptions: Mutex<HashSet<Uuid>>,
+    notification_subscribers: Mutex<Vec<UnboundedSender<ValueNotification>>>,
+    transport_events: Mutex<Option<EventSubscribers>>,
+}
+
+pub struct FakePeripheralBuilder {
+    id: String,
+    local_name: Option<String>,
+    rssi: Option<i16>,
+    advertised_services: Vec<Uuid>,
+    characteristics: BTreeMap<Uuid, Vec<(Uuid, CharPropFlags)>>,
+    values: HashMap<Uuid, Vec<u8>>,
+    clock_offset_us: i64,
+    clock_skew_ppm: f64,
+    latency: Duration,
+    hang_on_connect: bool,
+    control_responder: Option<ControlResponder>,
+}
+
+impl FakePeripheralBuilder {
+    pub fn local_name(mut self, name: &str) -> Self {
+        self.local_name = Some(name.to_string());
+        self
+    }
+
+    pub fn rssi(mut self, rssi: i16) -> Self {
+        self.rssi = Some(rssi);
+        self
+    }
+
+    pub fn advertise(mut self, service: Uuid) -> Self {
+        self.advertised_services.push(service);
+        self
+    }
+
+    pub fn characteristic(mut self, service: Uuid, characteristic: Uuid, properties: CharPropFlags, value: Vec<u8>) -> Self {
+        self.characteristics.entry(service).or_default().push((characteristic, properties));
+        self.values.insert(characteristic, value);
+        self
+    }
+
+    pub fn device_information(self, serial: &str, model: &str) -> Self {
+        self.characteristic(SERVICE_DEVICE_INFO, CHARACTERISTIC_SERIAL, CharPropFlags::READ, serial.as_bytes().to_vec())
+            .characteristic(SERVICE_DEVICE_INFO, CHARACTERISTIC_MODEL, CharPropFlags::READ, model.as_bytes().to_vec())
+    }
+
+    pub fn battery(self, level: u8) -> Self {
+        self.characteristic(SERVICE_BATTERY, CHARACTERISTIC_BATTERY, CharPropFlags::READ | CharPropFlags::NOTIFY, vec![level])
+    }
+
+    pub fn current_time(self) -> Self {
+        self.characteristic(SERVICE_TIME, CHARACTERISTIC_TIME, CharPropFlags::READ | CharPropFlags::WRITE_WITHOUT_RESPONSE, vec![])
+    }
+
+    /// The VitalVision data service, advertised so that the scan picks the peripheral up
+    pub fn data(self) -> Self {
+        self.advertise(SERVICE_DATA)
+            .characteristic(SERVICE_DATA, CHARACTERISTIC_DATA, CharPropFlags::NOTIFY, vec![])
+    }
+
+    /// The standard Heart Rate Service of an off-the-shelf strap, advertised like the data service
+    pub fn heart_rate(self) -> Self {
+        self.advertise(SERVICE_HEART_RATE)
+            .characteristic(SERVICE_HEART_RATE, CHARACTERISTIC_HEART_RATE_MEASUREMENT, CharPropFlags::NOTIFY, vec![])
+    }
+
+    /// The standard Pulse Oximeter Service with continuous and spot-check measurements
+    pub fn pulse_oximeter(self) -> Self {
+        self.advertise(SERVICE_PULSE_OXIMETER)
+            .characteristic(SERVICE_PULSE_OXIMETER, CHARACTERISTIC_PLX_CONTINUOUS, CharPropFlags::NOTIFY, vec![])
+            .characteristic(SERVICE_PULSE_OXIMETER, CHARACTERISTIC_PLX_SPOT_CHECK, CharPropFlags::INDICATE, vec![])
+    }
+
+    /// Makes the clock run `skew_ppm` too fast and read `offset_us` ahead of the time it was set to
+    pub fn clock_error(mut self, offset_us: i64, skew_ppm: f64) -> Self {
+        self.clock_offset_us = offset_us;
+        self.clock_skew_ppm = skew_ppm;
+        self
+    }
+
+    /// Delays reads and writes of the time characteristic, reads take the time halfway through
+    pub fn latency(mut self, latency: Duration) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// Never completes connection attempts, like a peripheral that went out of range mid-connect
+    pub fn hang_on_connect(mut self) -> Self {
+        self.hang_on_connect = true;
+        self
+    }
+
+    /// Exposes the control characteristic, answering every written frame with `responder`
+    pub fn control(mut self, responder: impl Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync + 'static) -> Self {
+        self.control_responder = Some(Box::new(responder));
+        self.characteristic(SERVICE_DATA, CHARACTERISTIC_CONTROL, CharPropFlags::WRITE | CharPropFlags::NOTIFY, vec![])
+    }
+
+    /// Exposes the backlog characteristic, tests answer the requests written to it
+    pub fn backlog(self) -> Self {
+        self.characteristic(SERVICE_DATA, CHARACTERISTIC_BACKLOG, CharPropFlags::WRITE | CharPropFlags::NOTIFY, vec![])
+    }
+
+    /// Exposes a channel layout characteristic next to the data characteristic
+    pub fn channel_layout(self, layout: &ChannelLayout) -> Self {
+        self.characteristic(SERVICE_DATA, CHARACTERISTIC_CHANNEL_LAYOUT, CharPropFlags::READ, layout.encode())
+    }
+
+    pub fn build(self) -> Arc<FakePeripheral> {
+        let services = self.characteristics.into_iter().map(|(service_uuid, characteristics)| Service {
+            uuid: service_uuid,
+            primary: true,
+            characteristics: characteristics.into_iter().map(|(uuid, properties)| Characteristic {
+                uuid,
+                service_uuid,
+                properties,
+                descriptors: BTreeSet::new(),
+            }).collect(),
+        }).collect();
+
+        Arc::new(FakePeripheral {
+            id: self.id,
+            local_name: self.local_name,
+            rssi: Mutex::new(self.rssi),
+            advertised_services: self.advertised_services,
+            services,
+            values: Mutex::new(self.values),
+            clock: Mutex::new((Utc::now(), Instant::now())),
+            clock_offset_us: AtomicI64::new(self.clock_offset_us),
+            clock_skew_ppm: self.clock_skew_ppm,
+            latency: self.latency,
+            hang_on_connect: self.hang_on_connect,
+            control_responder: self.control_responder,
+            connected: AtomicBool::new(false),
+            connectable: AtomicBool::new(true),
+            subscriptions: Mutex::new(HashSet::new()),
+            notification_subscribers: Mutex::new(vec![]),
+            transport_events: Mutex::new(None),
+        })
+    }
+}
+
+impl FakePeripheral {
+    pub fn builder(id: &str) -> FakePeripheralBuilder {
+        FakePeripheralBuilder {
+            id: id.to_string(),
+            local_name: None,
+            rssi: None,
+            advertised_services: vec![],
+            characteristics: BTreeMap::new(),
+            values: HashMap::new(),
+            clock_offset_us: 0,
+            clock_skew_ppm: 0.0,
+            latency: Duration::ZERO,
+            hang_on_connect: false,
+            control_responder: None,
+        }
+    }
+
+    /// Control responder acknowledging every command, like firmware that supports them all
+    pub fn acknowledge(frame: &[u8]) -> Option<Vec<u8>> {
+        Some(vec![0x80, *frame.get(1)?, *frame.first()?, 0])
+    }
+
+    /// The value of a characteristic, as last written by the central
+    pub fn value(&self, characteristic: Uuid) -> Option<Vec<u8>> {
+        self.values.lock().unwrap().get(&characteristic).cloned()
+    }
+
+    pub fn is_subscribed(&self, characteristic: Uuid) -> bool {
+        self.subscriptions.lock().unwrap().contains(&characteristic)
+    }
+
+    /// Sends a notification if the central is subscribed, returns whether it was delivered
+    pub fn notify(&self, characteristic: Uuid, value: Vec<u8>) -> bool {
+        if !self.connected.load(Ordering::SeqCst) || !self.is_subscribed(characteristic) {
+            return false;
+        }
+
+        let notification = ValueNotification { uuid: characteristic, value };
+        let mut subscribers = self.notification_subscribers.lock().unwrap();
+        subscribers.retain(|tx| tx.send(notification.clone()).is_ok());
+        !subscribers.is_empty()
+    }
+
+    pub fn set_battery(&self, level: u8) {
+        self.values.lock().unwrap().insert(CHARACTERISTIC_BATTERY, vec![level]);
+        self.notify(CHARACTERISTIC_BATTERY, vec![level]);
+    }
+
+    /// Changes the signal strength, like the peripheral moving, and reports the new advertisement
+    pub fn set_rssi(&self, rssi: i16) {
+        *self.rssi.lock().unwrap() = Some(rssi);
+        if let Some(subscribers) = self.transport_events.lock().unwrap().as_ref() {
+            emit(subscribers, TransportEvent::DeviceUpdated(self.id.clone()));
+        }
+    }
+
+    /// Makes connection attempts fail, e.g. while the peripheral is out of range
+    pub fn set_connectable(&self, connectable: bool) {
+        self.connectable.store(connectable, Ordering::SeqCst);
+    }
+
+    /// Simulates the link dropping from the peripheral side
+    pub fn disconnect_remote(&self) {
+        if !self.connected.swap(false, Ordering::SeqCst) {
+            return;
+        }
+
+        self.subscriptions.lock().unwrap().clear();
+        // dropping the senders ends all notification streams
+        self.notification_subscribers.lock().unwrap().clear();
+
+        if let Some(subscribers) = self.transport_events.lock().unwrap().as_ref() {
+            emit(subscribers, TransportEvent::DeviceDisconnected(self.id.clone()));
+        }
+    }
+
+    /// Moves the clock by `by_us`, like a clock that jumps until it is synced again
+    pub fn jump_clock(&self, by_us: i64) {
+        self.clock_offset_us.fetch_add(by_us, Ordering::SeqCst);
+    }
+
+    fn device_time(&self) -> DateTime<Utc> {
+        let (set_to, set_at) = *self.clock.lock().unwrap();
+        let elapsed_us = set_at.elapsed().as_micros() as f64 * (1.0 + self.clock_skew_ppm / 1e6);
+        set_to + chrono::Duration::microseconds(elapsed_us as i64 + self.clock_offset_us.load(Ordering::SeqCst))
+    }
+
+    fn ensure_connected(&self) -> BleResult<()> {
+        if self.connected.load(Ordering::SeqCst) {
+            Ok(())
+        } else {
+            Err(format!("Peripheral {} not connected", self.id).into())
+        }
+    }
+}
+
+#[async_trait]
+impl BlePeripheral for FakePeripheral {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn services(&self) -> BTreeSet<Service> {
+        self.services.clone()
+    }
+
+    async fn advertisement(&self) -> BleResult<Advertisement> {
+        Ok(Advertisement {
+            local_name: self.local_name.clone(),
+            rssi: *self.rssi.lock().unwrap(),
+            services: self.advertised_services.clone(),
+        })
+    }
+
+    async fn is_connected(&self) -> BleResult<bool> {
+        Ok(self.connected.load(Ordering::SeqCst))
+    }
+
+    async fn connect(&self) -> BleResult<()> {
+        if !self.connectable.load(Ordering::SeqCst) {
+            return Err(format!("Peripheral {} not reachable", self.id).into());
+        }
+        if self.hang_on_connect {
+            std::future::pending::<()>().await;
+        }
+        self.connected.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    async fn disconnect(&self) -> BleResult<()> {
+        self.disconnect_remote();
+        Ok(())
+    }
+
+    async fn discover_services(&self) -> BleResult<()> {
+        self.ensure_connected()
+    }
+
+    async fn read(&self, characteristic: &Characteristic) -> BleResult<Vec<u8>> {
+        self.ensure_connected()?;
+        if characteristic.uuid == CHARACTERISTIC_TIME {
+            tokio::time::sleep(self.latency / 2).await;
+            let time = self.device_time();
+            tokio::time::sleep(self.latency / 2).await;
+            return Ok(time_to_ble_data(time));
+        }
+
+        self.values.lock().unwrap()
+            .get(&characteristic.uuid)
+            .cloned()
+            .ok_or_else(|| format!("Characteristic {} not found", characteristic.uuid).into())
+    }
+
+    async fn write(&self, characteristic: &Characteristic, data: &[u8], _write_type: WriteType) -> BleResult<()> {
+        self.ensure_connected()?;
+        if characteristic.uuid == CHARACTERISTIC_TIME {
+            tokio::time::sleep(self.latency).await;
+            let time = ble_data_to_time(data)?;
+            *self.clock.lock().unwrap() = (time, Instant::now());
+            return Ok(());
+        }
+
+        self.values.lock().unwrap().insert(characteristic.uuid, data.to_vec());
+        if characteristic.uuid == CHARACTERISTIC_CONTROL {
+            if let Some(response) = self.control_responder.as_ref().and_then(|respond| respond(data)) {
+                self.notify(CHARACTERISTIC_CONTROL, response);
+            }
+        }
+        Ok(())
+    }
+
+    async fn subscribe(&self, characteristic: &Characteristic) -> BleResult<()> {
+        self.ensure_connected()?;
+        self.subscriptions.lock().unwrap().insert(characteristic.uuid);
+        Ok(())
+    }
+
+    async fn unsubscribe(&self, characteristic: &Characteristic) -> BleResult<()> {
+        self.ensure_connected()?;
+        self.subscriptions.lock().unwrap().remove(&characteristic.uuid);
+        Ok(())
+    }
+
+    async fn notifications(&self) -> BleResult<NotificationStream> {
+        self.ensure_connected()?;
+        let (tx, rx) = unbounded_channel();
+        self.notification_subscribers.lock().unwrap().push(tx);
+        Ok(Box::pin(UnboundedReceiverStream::new(rx)))
+    }
+}
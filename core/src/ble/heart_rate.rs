@@ -0,0 +1,162 @@
+use super::{DatapointDecoder, DecodedPacket};
+use crate::{Channel, ChannelType};
+
+const FLAG_HEART_RATE_U16: u8 = 0x01;
+const FLAG_CONTACT_DETECTED: u8 = 0x02;
+const FLAG_CONTACT_SUPPORTED: u8 = 0x04;
+const FLAG_ENERGY_EXPENDED: u8 = 0x08;
+const FLAG_RR_INTERVALS: u8 = 0x10;
+
+/// A notification of the Heart Rate Measurement characteristic (0x2A37)
+#[derive(Debug, PartialEq, Clone)]
+pub struct HeartRateMeasurement {
+    /// in bpm
+    pub heart_rate: u16,
+    /// `None` if the sensor cannot tell whether it touches the skin
+    pub sensor_contact: Option<bool>,
+    /// in kJ since the last reset
+    pub energy_expended: Option<u16>,
+    /// in 1/1024 s, oldest first
+    pub rr_intervals: Vec<u16>,
+}
+
+impl HeartRateMeasurement {
+    /// Parses the flags byte and the fields it announces, ignoring bytes after them
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        let (&flags, mut rest) = data.split_first().ok_or("Empty heart rate measurement")?;
+        let mut take = |len: usize| -> Result<u16, String> {
+            let bytes = rest.get(..len).ok_or("Heart rate measurement too short")?;
+            rest = &rest[len..];
+            Ok(bytes.iter().rev().fold(0, |value, byte| value << 8 | *byte as u16))
+        };
+
+        let heart_rate = take(if flags & FLAG_HEART_RATE_U16 != 0 { 2 } else { 1 })?;
+        let energy_expended = match flags & FLAG_ENERGY_EXPENDED != 0 {
+            true => Some(take(2)?),
+            false => None,
+        };
+        let mut rr_intervals = vec![];
+        if flags & FLAG_RR_INTERVALS != 0 {
+            while let Ok(rr) = take(2) {
+                rr_intervals.push(rr);
+            }
+        }
+
+        Ok(Self {
+            heart_rate,
+            sensor_contact: (flags & FLAG_CONTACT_SUPPORTED != 0).then_some(flags & FLAG_CONTACT_DETECTED != 0),
+            energy_expended,
+            rr_intervals,
+        })
+    }
+
+    /// The RR intervals in ms
+    pub fn rr_intervals_ms(&self) -> Vec<i32> {
+        self.rr_intervals.iter().map(|rr| ((*rr as u32 * 1000 + 512) / 1024) as i32).collect()
+    }
+}
+
+/// Ids of the heart rate and the RR interval channel of a device
+fn channel_ids(device_id: &str) -> (String, String) {
+    (format!("{}-0", device_id), format!("{}-1", device_id))
+}
+
+pub fn channels(device_id: &str) -> Vec<Channel> {
+    let (heart_rate, rr) = channel_ids(device_id);
+    [(heart_rate, "Heart rate", ChannelType::HR), (rr, "RR interval", ChannelType::RR)].into_iter()
+        .map(|(id, name, channel_type)| Channel {
+            id,
+            name: name.to_string(),
+            channel_type,
+            signal_quality: None,
+            samples_received: 0,
+            samples_lost: 0,
+        })
+        .collect()
+}
+
+/// Builds a decoder mapping a measurement to a heart rate sample and its RR intervals. The heart
+/// rate is `None` while the sensor reports no skin contact. Malformed measurements decode to
+/// nothing.
+pub fn decoder(device_id: &str) -> DatapointDecoder {
+    let (heart_rate_id, rr_id) = channel_ids(device_id);
+    Box::new(move |value: Vec<u8>| {
+        let mut decoded = DecodedPacket::default();
+        let Ok(measurement) = HeartRateMeasurement::parse(&value) else {
+            return decoded;
+        };
+
+        let heart_rate = Some(measurement.heart_rate as i32).filter(|_| measurement.sensor_contact != Some(false));
+        decoded.samples.insert(heart_rate_id.clone(), vec![heart_rate]);
+        decoded.samples.insert(rr_id.clone(), measurement.rr_intervals_ms().into_iter().map(Some).collect());
+        decoded
+    })
+}
+
+/// Timestamps a decoded measurement that arrived at `arrival_us`. The heart rate is taken at
+/// the arrival. The RR intervals have their own timestamps, the last one ending at the arrival
+/// and each one before ending where the next one starts.
+pub fn timestamp(packet: &mut DecodedPacket, device_id: &str, arrival_us: i64) {
+    let (heart_rate_id, rr_id) = channel_ids(device_id);
+    if !packet.samples.contains_key(&heart_rate_id) {
+        return;
+    }
+    packet.timestamps_us = vec![arrival_us];
+
+    let rr_intervals = packet.samples.get(&rr_id).cloned().unwrap_or_default();
+    let mut end_us = arrival_us;
+    let mut timestamps: Vec<i64> = rr_intervals.iter().rev()
+        .map(|rr| {
+            let timestamp_us = end_us;
+            end_us -= rr.unwrap_or_default() as i64 * 1000;
+            timestamp_us
+        })
+        .collect();
+    timestamps.reverse();
+    packet.channel_timestamps_us.insert(rr_id, timestamps);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_measurements() {
+        // u8 heart rate only
+        assert_eq!(HeartRateMeasurement::parse(&[0x00, 72]), Ok(HeartRateMeasurement {
+            heart_rate: 72,
+            sensor_contact: None,
+            energy_expended: None,
+            rr_intervals: vec![],
+        }));
+
+        // u16 heart rate, contact detected, energy and two RR intervals
+        let measurement = HeartRateMeasurement::parse(&[0x1F, 0x2C, 0x01, 0x10, 0x00, 0x00, 0x04, 0x00, 0x02]).unwrap();
+        assert_eq!(measurement.heart_rate, 300);
+        assert_eq!(measurement.sensor_contact, Some(true));
+        assert_eq!(measurement.energy_expended, Some(16));
+        assert_eq!(measurement.rr_intervals, vec![1024, 512]);
+        assert_eq!(measurement.rr_intervals_ms(), vec![1000, 500]);
+
+        assert!(HeartRateMeasurement::parse(&[]).is_err());
+        assert!(HeartRateMeasurement::parse(&[0x01, 0x2C]).is_err());
+        assert!(HeartRateMeasurement::parse(&[0x08, 60, 0x10]).is_err());
+    }
+
+    #[test]
+    fn decodes_and_timestamps_measurements() {
+        let mut decoder = decoder("strap");
+        let mut decoded = decoder(vec![0x10, 60, 0x00, 0x04, 0x00, 0x02]);
+        timestamp(&mut decoded, "strap", 5_000_000);
+
+        assert_eq!(decoded.samples["strap-0"], vec![Some(60)]);
+        assert_eq!(decoded.samples["strap-1"], vec![Some(1000), Some(500)]);
+        assert_eq!(decoded.timestamps_us, vec![5_000_000]);
+        assert_eq!(decoded.channel_timestamps_us["strap-1"], vec![4_500_000, 5_000_000]);
+
+        // contact supported but lost
+        let decoded = decoder(vec![0x04, 60]);
+        assert_eq!(decoded.samples["strap-0"], vec![None]);
+        assert!(decoder(vec![]).samples.is_empty());
+    }
+}
@@ -0,0 +1,35 @@
+/// Stable id of a device, derived from its Device Information model number and serial.
+///
+/// Unlike the transport's peripheral id, it stays the same across platforms and app installs,
+/// so it can key saved per-device data. The model is lowercased with everything but letters
+/// and digits collapsed to dashes, e.g. `VitalVision ECG` with serial 71 becomes `vitalvision-ecg-71`.
+pub fn device_id(model: &str, serial: u16) -> String {
+    let mut id = String::new();
+    for c in model.chars() {
+        if c.is_ascii_alphanumeric() {
+            id.push(c.to_ascii_lowercase());
+        } else if !id.is_empty() && !id.ends_with('-') {
+            id.push('-');
+        }
+    }
+
+    if id.is_empty() {
+        id.push_str("device-");
+    } else if !id.ends_with('-') {
+        id.push('-');
+    }
+    id.push_str(&serial.to_string());
+    id
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn derives_id_from_model_and_serial() {
+        assert_eq!(device_id("VitalVision ECG", 71), "vitalvision-ecg-71");
+        assert_eq!(device_id("  EXB-2 (rev. A) ", 5), "exb-2-rev-a-5");
+        assert_eq!(device_id("", 72), "device-72");
+    }
+}
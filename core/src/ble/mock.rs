@@ -1,165 +1,246 @@
-use crate::*;
+use std::collections::VecDeque;
 use std::sync::Arc;
-use tokio::time::{sleep, Duration};
-use std::f32::consts::PI;
+use tokio::time::{interval, Duration, MissedTickBehavior};
 use rand::prelude::*;
+use slog::{debug, Logger};
+use crate::synth::{Parameters, Synthesizer};
+use super::CHARACTERISTIC_DATA;
+use super::channel_layout::ChannelLayout;
+use super::fake::{FakePeripheral, FakeTransport};
+use super::scenario::{Scenario, ScenarioAction, ScenarioDevice, ScenarioEvent};
 
-pub async fn mock_loop(delegate: Arc<dyn VVCoreDelegate>, hist_size: u32) {
-    let mut phase = 0.0;
-    let mut devices = mock_devices();
-    loop {
-        delegate.devices_changed(devices.clone());
-
-        for device in devices.iter() {
-            for channel in device.channels.iter() {
-                let data: Vec<Option<i32>> = (0..hist_size).map(|i| {
-                    let time = phase + i as f32 * 0.01;
-                    generate_waveform(time, &channel.channel_type)
-                }).map(|x| Some(x))
-                    .collect();
-                delegate.new_data(channel.id.clone(), data);
+/// Scale of the raw ECG and PPG values
+const ECG_COUNTS_PER_MV: f64 = 10_000.0;
+const PPG_OFFSET: f64 = 20_480.0;
+const PPG_COUNTS: f64 = 10_240.0;
+
+/// A wearable which streams the legacy layout, and the synthetic signals it sends. It has a
+/// channel layout characteristic only when it samples at another rate than the legacy one.
+pub struct MockDevice {
+    pub serial: u16,
+    pub peripheral: Arc<FakePeripheral>,
+    pub synthesizer: Synthesizer,
+}
+
+impl MockDevice {
+    /// `n` numbers the devices of a session from 1
+    fn new(n: usize, device: &ScenarioDevice, layout: &ChannelLayout) -> Self {
+        let name = device.name.clone().unwrap_or_else(|| format!("Device {}", device.serial));
+        let mut builder = FakePeripheral::builder(&format!("00:11:22:33:00:{:02X}", n))
+            .local_name(&name)
+            .rssi(device.rssi)
+            .device_information(&device.serial.to_string(), "VitalVision Mock")
+            .battery(device.battery)
+            .current_time()
+            .clock_error(device.clock_offset_ms * 1000, device.clock_skew_ppm)
+            .data();
+        if *layout != ChannelLayout::legacy(true) {
+            builder = builder.channel_layout(layout);
+        }
+        let peripheral = builder.build();
+
+        // the devices are worn by different people
+        let mut parameters = Parameters {
+            sampling_rate: layout.sampling_rate,
+            heart_rate_bpm: 49.0 + 13.0 * n as f64,
+            seed: n as u64,
+            ..Parameters::default()
+        };
+        device.signal.apply(&mut parameters);
+        Self { serial: device.serial, peripheral, synthesizer: Synthesizer::new(parameters) }
+    }
+}
+
+/// The two devices of a mock session without a scenario, with a random battery level and a
+/// clock a little off, like a real one
+pub fn mock_devices() -> Vec<MockDevice> {
+    let mut rng = rand::thread_rng();
+    [1, 2].into_iter()
+        .map(|serial| ScenarioDevice {
+            battery: rng.gen_range(20..100),
+            clock_offset_ms: rng.gen_range(-5..5),
+            clock_skew_ppm: rng.gen_range(-30.0..30.0),
+            ..ScenarioDevice::new(serial)
+        })
+        .enumerate()
+        .map(|(n, device)| MockDevice::new(n + 1, &device, &ChannelLayout::legacy(true)))
+        .collect()
+}
+
+/// Streams the mock devices and plays the events of a scenario on them. Its clock is the
+/// number of packets sent, so the events land on the same samples on every run.
+pub struct MockRunner {
+    devices: Vec<MockDevice>,
+    /// the events still to come, in order
+    events: VecDeque<ScenarioEvent>,
+    layout: ChannelLayout,
+    packets: u64,
+    logger: Logger,
+}
+
+impl MockRunner {
+    pub fn new(devices: Vec<MockDevice>, logger: Logger) -> Self {
+        Self { devices, events: VecDeque::new(), layout: ChannelLayout::legacy(true), packets: 0, logger }
+    }
+
+    pub fn from_scenario(scenario: Scenario, logger: Logger) -> Self {
+        let legacy = ChannelLayout::legacy(true);
+        let layout = ChannelLayout { sampling_rate: scenario.sampling_rate.unwrap_or(legacy.sampling_rate), ..legacy };
+        let devices = scenario.devices.iter().enumerate().map(|(n, device)| MockDevice::new(n + 1, device, &layout)).collect();
+        debug!(logger, "Running scenario"; "name" => &scenario.name, "events" => scenario.events.len());
+        Self { events: scenario.events.into(), layout, ..Self::new(devices, logger) }
+    }
+
+    pub fn devices(&self) -> &[MockDevice] {
+        &self.devices
+    }
+
+    pub fn devices_mut(&mut self) -> &mut [MockDevice] {
+        &mut self.devices
+    }
+
+    /// A transport with the mock devices. They go through discovery, time sync and the data
+    /// pipeline like real ones, once `mock_loop` streams their data.
+    pub fn transport(&self) -> Arc<FakeTransport> {
+        let transport = FakeTransport::new();
+        for device in self.devices.iter() {
+            transport.add_peripheral(device.peripheral.clone());
+        }
+        transport
+    }
+
+    /// Seconds of the scenario clock
+    pub fn time_sec(&self) -> f64 {
+        (self.packets * self.layout.samples_per_packet as u64) as f64 / self.layout.sampling_rate
+    }
+
+    /// Plays the events that are due and sends a packet from every device. Like real firmware,
+    /// the counter moves on while the central is not subscribed, and packets with a dropout in
+    /// them are lost.
+    pub fn step(&mut self) {
+        let time = self.time_sec();
+        while self.events.front().is_some_and(|event| event.at_sec <= time) {
+            let event = self.events.pop_front().unwrap();
+            self.play(event);
+        }
+
+        let counter = self.packets as u8;
+        for device in self.devices.iter_mut() {
+            let synthesized = device.synthesizer.generate(self.layout.samples_per_packet);
+            let frames: Option<Vec<[i32; 4]>> = synthesized.ecg.iter().zip(synthesized.ppg.iter())
+                .map(|(ecg, ppg)| Some(legacy_frame((*ecg)?, (*ppg)?)))
+                .collect();
+            if let Some(frames) = frames {
+                device.peripheral.notify(CHARACTERISTIC_DATA, legacy_packet(counter, &frames));
             }
         }
+        self.packets += 1;
+    }
 
-        phase += 0.05;
-        if phase > 2.0 * PI {
-            phase -= 2.0 * PI;
-            devices = mock_devices();
+    fn play(&mut self, event: ScenarioEvent) {
+        debug!(self.logger, "Scenario event"; "at_sec" => event.at_sec, "device" => event.device, "action" => format!("{:?}", event.action));
+        let Some(device) = self.devices.iter_mut().find(|device| device.serial == event.device) else {
+            return;
+        };
+        let peripheral = &device.peripheral;
+        match event.action {
+            ScenarioAction::Disconnect => peripheral.disconnect_remote(),
+            ScenarioAction::OutOfRange => {
+                peripheral.set_connectable(false);
+                peripheral.disconnect_remote();
+            }
+            ScenarioAction::InRange => peripheral.set_connectable(true),
+            ScenarioAction::Battery { level } => peripheral.set_battery(level),
+            ScenarioAction::Rssi { dbm } => peripheral.set_rssi(dbm),
+            ScenarioAction::ClockJump { ms } => peripheral.jump_clock(ms * 1000),
+            ScenarioAction::Signal(changes) => {
+                let mut parameters = device.synthesizer.parameters().clone();
+                changes.apply(&mut parameters);
+                device.synthesizer.set_parameters(parameters);
+            }
         }
+    }
+}
 
-        sleep(Duration::from_millis(100)).await;
+/// Steps the runner at the sampling rate of its layout
+pub async fn mock_loop(mut runner: MockRunner) {
+    let period = Duration::from_secs_f64(runner.layout.samples_per_packet as f64 / runner.layout.sampling_rate);
+    let mut ticks = interval(period);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Skip);
+    loop {
+        ticks.tick().await;
+        runner.step();
     }
 }
 
-fn generate_waveform(time: f32, channel_type: &ChannelType) -> i32 {
-    let base_wave = match channel_type {
-        ChannelType::PPG => {
-            // Generate a smoother, slower waveform for PPG
-            20480.0 + (10240.0 * (2.0 * PI * time * 1.0).sin())
-        },
-        ChannelType::ECG => {
-            // Generate a faster, more complex waveform for ECG
-            //20480.0 + (10240.0 * (2.0 * PI * time * 4.0).sin() + 5120.0 * (2.0 * PI * time * 20.0).sin())
-            generate_ecg_waveform(time)
-        },
-        _ => 0.0,
-    };
-
-    // Add random noise and ensure the waveform stays within the u16 range
-    let noise: f32 = rand::random::<f32>() * 200.0 - 100.0; // Random noise between -100 and +100
-    let waveform_value = base_wave + noise;
-    waveform_value.clamp(0.0, u16::MAX as f32) as i32
+/// Raw ECG and PPG green, red and IR values of a synthetic sample
+fn legacy_frame(ecg_mv: f64, ppg: f64) -> [i32; 4] {
+    let ecg = (ecg_mv * ECG_COUNTS_PER_MV).clamp(i16::MIN as f64, i16::MAX as f64) as i32;
+    let green = (PPG_OFFSET + ppg * PPG_COUNTS).clamp(0.0, u16::MAX as f64) as i32;
+    [ecg, green, green / 2, green * 3 / 4]
 }
 
-fn generate_ecg_waveform(time: f32) -> f32 {
-    // Constants for waveform characteristics
-    let p_wave_height = 0.1;
-    let qrs_complex_height = 0.5;
-    let t_wave_height = 0.3;
-
-    let cycle_time = time % 1.0; // Simulate a repeating cycle every 1 second
-
-    let ecg_value = if cycle_time < 0.1 {
-        // P wave
-        (cycle_time / 0.1) * p_wave_height
-    } else if cycle_time < 0.2 {
-        // Downturn to Q
-        ((0.15 - cycle_time) / 0.05) * p_wave_height
-    } else if cycle_time < 0.25 {
-        0.0
-    } else if cycle_time < 0.35 {
-        // QRS Complex
-        ((cycle_time - 0.25) / 0.05) * qrs_complex_height
-    } else if cycle_time < 0.40 {
-        // S wave
-        ((0.35 - cycle_time) / 0.05) * qrs_complex_height
-    } else if cycle_time < 0.55 {
-        0.0
-    } else if cycle_time < 0.70 {
-        // T wave
-        ((cycle_time - 0.45) / 0.25) * t_wave_height - ((cycle_time - 0.45) / 0.25) * t_wave_height * (cycle_time - 0.45) / 0.25
-    } else {
-        // Baseline
-        0.0
-    };
-
-    // Normalize to fit into the u16 range, assuming ecg_value varies from -1 to 1
-    let normalized_value = ((ecg_value + 1.0) / 2.0) * (u16::MAX as f32);
-    normalized_value.clamp(0.0, u16::MAX as f32)
+/// A counter byte and frames of ECG (i16), PPG green, red and IR (u16)
+fn legacy_packet(counter: u8, frames: &[[i32; 4]]) -> Vec<u8> {
+    let mut packet = vec![counter];
+    for [ecg, green, red, ir] in frames {
+        packet.extend_from_slice(&(*ecg as i16).to_le_bytes());
+        for ppg in [green, red, ir] {
+            packet.extend_from_slice(&(*ppg as u16).to_le_bytes());
+        }
+    }
+    packet
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble::{CHARACTERISTIC_BATTERY, CHARACTERISTIC_CHANNEL_LAYOUT};
 
+    #[test]
+    fn plays_scenario_events_on_the_packet_clock() {
+        let scenario = Scenario::from_toml(r#"
+            name = "Fading"
 
-fn mock_devices() -> Vec<Device> {
-    let mut rng = rand::thread_rng();
-    vec![
-        Device {
-            id: "00:11:22:33:00:01".to_string(),
-            serial: 1,
-            name: "Device 1".to_string(),
-            battery: rng.gen_range(0..100),
-            drift_us: 30,
-            connected: true,
-            channels: vec![
-                Channel {
-                    id: "00:11:22:33:00:01-1".to_string(),
-                    name: "PPG".to_string(),
-                    channel_type: ChannelType::PPG,
-                    signal_quality: Some(1.0),
-                },
-                Channel {
-                    id: "00:11:22:33:00:01-2".to_string(),
-                    name: "PPG".to_string(),
-                    channel_type: ChannelType::PPG,
-                    signal_quality: Some(0.8),
-                },
-                Channel {
-                    id: "00:11:22:33:00:01-3".to_string(),
-                    name: "PPG".to_string(),
-                    channel_type: ChannelType::PPG,
-                    signal_quality: Some(0.6),
-                },
-                Channel {
-                    id: "00:11:22:33:00:01-4".to_string(),
-                    name: "ECG".to_string(),
-                    channel_type: ChannelType::ECG,
-                    signal_quality: Some(0.9),
-                },
-            ],
-        },
-        Device {
-            id: "00:11:22:33:00:02".to_string(),
-            serial: 2,
-            name: "Device 2".to_string(),
-            battery: rng.gen_range(0..100),
-            drift_us: 30,
-            connected: true,
-            channels: vec![
-                Channel {
-                    id: "00:11:22:33:00:02-1".to_string(),
-                    name: "PPG".to_string(),
-                    channel_type: ChannelType::PPG,
-                    signal_quality: Some(0.9),
-                },
-                Channel {
-                    id: "00:11:22:33:00:02-2".to_string(),
-                    name: "PPG".to_string(),
-                    channel_type: ChannelType::PPG,
-                    signal_quality: Some(0.7),
-                },
-                Channel {
-                    id: "00:11:22:33:00:02-3".to_string(),
-                    name: "PPG".to_string(),
-                    channel_type: ChannelType::PPG,
-                    signal_quality: Some(0.5),
-                },
-                Channel {
-                    id: "00:11:22:33:00:02-4".to_string(),
-                    name: "ECG".to_string(),
-                    channel_type: ChannelType::ECG,
-                    signal_quality: Some(0.8),
-                },
-            ],
-        },
-    ]
+            [[events]]
+            at_sec = 1.0
+            device = 2
+            action = "battery"
+            level = 5
+
+            [[events]]
+            at_sec = 1.0
+            device = 1
+            action = "signal"
+            perfusion = 0.1
+        "#).unwrap();
+        let mut runner = MockRunner::from_scenario(scenario, Logger::root(slog::Discard, slog::o!()));
+        let battery = |runner: &MockRunner| runner.devices()[1].peripheral.value(CHARACTERISTIC_BATTERY);
+
+        // packets of 3 samples at 32 Hz, the 11th starts at 1.03 s
+        for _ in 0..11 {
+            runner.step();
+        }
+        assert_eq!(battery(&runner), Some(vec![100]));
+        assert_eq!(runner.devices()[0].synthesizer.parameters().perfusion, 1.0);
+        runner.step();
+        assert_eq!(battery(&runner), Some(vec![5]));
+        assert_eq!(runner.devices()[0].synthesizer.parameters().perfusion, 0.1);
+        assert_eq!(runner.devices()[1].synthesizer.parameters().perfusion, 1.0);
+    }
+
+    #[test]
+    fn describes_other_sampling_rates_in_a_channel_layout() {
+        let layout = |runner: &MockRunner| runner.devices()[0].peripheral.value(CHARACTERISTIC_CHANNEL_LAYOUT);
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let legacy = MockRunner::from_scenario(Scenario::from_toml("name = \"Legacy\"").unwrap(), logger.clone());
+        assert_eq!(layout(&legacy), None);
+
+        let mut slow = MockRunner::from_scenario(Scenario::from_toml("name = \"Slow\"\nsampling_rate = 4.0").unwrap(), logger);
+        let slow_layout = ChannelLayout::parse(&layout(&slow).unwrap()).unwrap();
+        assert_eq!(slow_layout, ChannelLayout { sampling_rate: 4.0, ..ChannelLayout::legacy(true) });
+        assert_eq!(slow.devices()[0].synthesizer.parameters().sampling_rate, 4.0);
+        slow.step();
+        assert_eq!(slow.time_sec(), 0.75);
+    }
 }
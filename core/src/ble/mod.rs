@@ -1,28 +1,130 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use super::*;
 use ble_date_converter::*;
-use btleplug::api::{
-    Central, CentralEvent, Manager as _, Peripheral,
-    ScanFilter, ValueNotification, WriteType,
-};
-use btleplug::platform::{Manager};
-use chrono::Utc;
+use btleplug::api::{Characteristic, ValueNotification, WriteType};
+use crate::clock::Clock;
+use futures::Future;
 use futures::stream::{StreamExt, select};
-use std::error::Error;
-use std::pin::Pin;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
 use slog::{error, warn, trace, o, debug};
-use tokio::sync::mpsc::Sender;
-use tokio::sync::{Mutex};
-use tokio_stream::Stream;
+use tokio::sync::mpsc::{unbounded_channel, Sender, UnboundedSender};
+use tokio::sync::{Mutex, Notify};
 use uuid::Uuid;
 use tokio_stream::wrappers::ReceiverStream;
-
+use channel_layout::ChannelLayout;
+use clock_sync::{ClockEstimate, ClockModel, SyncRound};
+use control::{CommandId, ControlChannel, ControlCommand};
+use device_info::DeviceInformation;
+use profile::{DataProtocol, SensorProfile};
+use reconnect::ReconnectPolicy;
+use timestamps::SampleClock;
+use auto_gain::AutoGain;
+use backlog::{BacklogEvent, BacklogTransfer, Gap, StreamPosition};
+use watchdog::{StallAction, StallWatchdog};
+use transport::{Advertisement, BlePeripheral, BleResult, BleTransport, NotificationStream, TransportEvent};
+
+// kept in its original style
+#[allow(clippy::needless_return, clippy::single_match)]
 mod ble_date_converter;
+pub mod auto_gain;
+pub mod backlog;
+pub mod btleplug_transport;
+pub mod capture;
+pub mod channel_layout;
+pub mod clock_sync;
+pub mod control;
+pub mod device_info;
+pub mod fake;
+pub mod heart_rate;
+pub mod identity;
 pub mod mock;
+pub mod profile;
+pub mod pulse_oximeter;
+pub mod reconnect;
+pub mod replay;
+pub mod scenario;
+pub mod sequence;
+pub mod timestamps;
+pub mod transport;
+pub mod watchdog;
+
+/// Settings of the BLE loop, taken from `VVCoreConfig`
+#[derive(Debug, Clone)]
+pub struct BleSettings {
+    pub max_initial_rtt_ms: u32,
+    pub reconnect_policy: ReconnectPolicy,
+    /// limit for every step of setting up a connection
+    pub step_timeout: Duration,
+    /// time without data after which a device counts as stalled, `None` to never check
+    pub stall_timeout: Option<Duration>,
+    /// connect discovered devices without waiting for `connect_device`
+    pub auto_connect: bool,
+    /// time a control command waits for its response
+    pub command_timeout: Duration,
+    /// how often the RSSI of connected devices is read, `None` to never read it
+    pub rssi_interval: Option<Duration>,
+    /// if not empty, only devices with these serials are auto-connected
+    pub auto_connect_serials: Vec<u16>,
+    /// PPG auto-gain of devices with a control characteristic, `None` to leave their LEDs alone
+    pub auto_gain: Option<auto_gain::Parameters>,
+    /// download the data recorded while disconnected from devices with a backlog characteristic
+    pub download_backlog: bool,
+}
+
+impl BleSettings {
+    pub fn from_config(config: &VVCoreConfig) -> Self {
+        Self {
+            max_initial_rtt_ms: config.max_initial_rtt_ms,
+            reconnect_policy: ReconnectPolicy::new(
+                config.reconnect_initial_delay_ms,
+                config.reconnect_max_delay_ms,
+                config.reconnect_max_attempts,
+            ),
+            step_timeout: Duration::from_millis(config.step_timeout_ms as u64),
+            stall_timeout: Some(Duration::from_millis(config.stall_timeout_ms as u64)).filter(|t| !t.is_zero()),
+            command_timeout: Duration::from_millis(config.command_timeout_ms as u64),
+            rssi_interval: Some(Duration::from_millis(config.rssi_interval_ms as u64)).filter(|t| !t.is_zero()),
+            auto_connect: config.auto_connect,
+            auto_connect_serials: config.auto_connect_serials.clone(),
+            auto_gain: config.ppg_auto_gain.clone(),
+            download_backlog: config.download_backlog,
+        }
+    }
+}
 
 pub struct Ble {
-    max_initial_rtt_ms: u32,
+    transport: Arc<dyn BleTransport>,
+    profiles: Arc<Vec<SensorProfile>>,
+    settings: BleSettings,
+    /// transport ids of devices that were connected before, these are reconnected when they drop
+    known_ids: Mutex<Vec<String>>,
+    /// stable device id of every transport id in `known_ids`, all events carry the stable ids
+    device_ids: Mutex<HashMap<String, String>>,
+    /// serial of every transport id seen with device information, to apply the allowlist before connecting
+    serials: Mutex<HashMap<String, u16>>,
+    /// transport ids the app asked to connect, overriding auto-connect
+    requested: Mutex<HashSet<String>>,
+    /// transport ids the app disconnected or the allowlist rejected, these are left alone
+    released: Mutex<HashSet<String>>,
+    /// transport ids of devices whose data the app paused, kept paused across reconnects
+    paused_devices: Mutex<HashSet<String>>,
+    /// pauses the data of every connected device and of devices connecting meanwhile
+    streaming_paused: AtomicBool,
+    /// commands to the running sessions, by transport id
+    sessions: Mutex<HashMap<String, UnboundedSender<SessionCommand>>>,
+    /// advertisements by transport id of every device the scan found
+    nearby: Mutex<HashMap<String, Advertisement>>,
+    /// clock models by device id, kept across reconnects until the time is set again
+    clocks: Mutex<HashMap<String, ClockModel>>,
+    /// the latest packet of every device id, where its backlog starts after a reconnect
+    stream_positions: Mutex<HashMap<String, StreamPosition>>,
+    /// transport ids of devices with a running connection task, notified when the transport reports a disconnect
+    links: Mutex<HashMap<String, Arc<Notify>>>,
+    paused: AtomicBool,
+    /// the wall clock time, for timestamps and device clocks
+    clock: Arc<dyn Clock>,
     event_publisher: Sender<ExternalBleEvent>,
     tx: Arc<Mutex<Option<Sender<InternalBleEvent>>>>,
     logger: Logger,
@@ -30,53 +132,146 @@ pub struct Ble {
 
 #[derive(Clone, Debug)]
 enum InternalBleEvent {
-    CentralEvent(CentralEvent),
+    TransportEvent(TransportEvent),
     ForwardedEvent(VVCoreInternalEvent),
 }
 
 #[derive(Clone, Debug)]
 pub enum ExternalBleEvent {
+    /// device id, transport id and the new state, the device id is the transport id until the
+    /// device information is read
+    StateChanged(String, String, DeviceState),
     DeviceConnected(Device),
     DeviceDisconnected(String),
+    /// transport id of a device not on the allowlist, which is dropped again
+    DeviceIgnored(String),
+    NearbyDevicesChanged(Vec<NearbyDevice>),
+    Reconnecting(String, u32),
+    ReconnectFailed(String),
     BatteryLevelChanged(String, u8),
-    DriftChanged(String, i64),
-    DataReceived(HashMap<String, Vec<i32>>),
+    RssiChanged(String, Option<i16>),
+    ClockSynced(String, ClockEstimate),
+    /// device id, command id and how the command went
+    CommandCompleted(String, u32, CommandOutcome),
+    /// device id and the decoded packet
+    DataReceived(String, DecodedPacket),
+    /// device id, packets of the backlog received and the total
+    BacklogProgress(String, u32, u32),
+    /// device id and samples of the backlog, older than the ones received before
+    BacklogReceived(String, DecodedPacket),
+    BacklogCompleted(String, BacklogOutcome),
+    Error(VVCoreError),
 }
 
-type DatapointDecoder = Box<dyn Fn(Vec<u8>) -> HashMap<String, Vec<i32>> + Send + Sync>;
+/// Samples of every channel id, with the samples of lost packets as `None`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct DecodedPacket {
+    pub samples: HashMap<String, Vec<Option<i32>>>,
+    /// packets lost between the previous packet and this one
+    pub lost_packets: u32,
+    /// host time of every sample in us since the epoch, shared by all channels
+    pub timestamps_us: Vec<i64>,
+    /// channel ids whose samples were taken around an LED current change, not to be analyzed
+    pub settling: HashSet<String>,
+    /// sequence counter of the packet, if the layout has one
+    pub sequence: Option<u32>,
+    /// timestamps of channels sampled apart from the others, e.g. RR intervals
+    pub channel_timestamps_us: HashMap<String, Vec<i64>>,
+}
 
-const SERVICE_DEVICE_INFO: Uuid = Uuid::from_u128(0x0000180A00001000800000805F9B34FB);          // 0000180A-0000-1000-8000-00805F9B34FB
-const CHARACTERISTIC_SERIAL: Uuid = Uuid::from_u128(0x00002A2500001000800000805F9B34FB);        // 00002A25-0000-1000-8000-00805F9B34FB
-const CHARACTERISTIC_MODEL: Uuid = Uuid::from_u128(0x00002A2400001000800000805F9B34FB);         // 00002A24-0000-1000-8000-00805F9B34FB
-const SERVICE_BATTERY: Uuid = Uuid::from_u128(0x0000180F00001000800000805F9B34FB);              // 0000180F-0000-1000-8000-00805F9B34FB
-const CHARACTERISTIC_BATTERY: Uuid = Uuid::from_u128(0x00002A1900001000800000805F9B34FB);       // 00002A19-0000-1000-8000-00805F9B34FB
-const SERVICE_TIME: Uuid = Uuid::from_u128(0x0000180600001000800000805F9B34FB);                 // 00001806-0000-1000-8000-00805F9B34FB
-const CHARACTERISTIC_TIME: Uuid = Uuid::from_u128(0x00002A2D00001000800000805F9B34FB);          // 00002A2D-0000-1000-8000-00805F9B34FB
-const SERVICE_DATA: Uuid = Uuid::from_u128(0xDCF31A27A904F3A3AA4E5AE42F1217B6);                 // DCF31A27-A904-F3A3-AA4E-5AE42F1217B6
-const CHARACTERISTIC_DATA: Uuid = Uuid::from_u128(0xDCF31A27A904F4A3A24E5AE42F8617B6);          // DCF31A27-A904-F4A3-A24E-5AE42F8617B6
+/// Changes to a running session, applied by its task
+#[derive(Clone, Debug, PartialEq)]
+enum SessionCommand {
+    /// unsubscribes from the data, keeping the link
+    PauseData,
+    ResumeData,
+    /// command id and the command to write to the control characteristic
+    Control(u32, ControlCommand),
+}
+
+/// How a session that did not fail ended
+#[derive(Debug, PartialEq)]
+enum Session {
+    /// the device disconnected after streaming
+    Ended,
+    /// the device is not on the allowlist and was disconnected after reading its serial
+    Rejected,
+}
+
+type DatapointDecoder = Box<dyn FnMut(Vec<u8>) -> DecodedPacket + Send + Sync>;
+
+/// reads of the device time per sync
+const SYNC_ROUNDS: usize = 8;
+/// batches of rounds per sync, if the round trip times stay above `max_initial_rtt_ms`
+const SYNC_ATTEMPTS: usize = 3;
+
+pub(crate) const SERVICE_DEVICE_INFO: Uuid = Uuid::from_u128(0x0000180A00001000800000805F9B34FB);               // 0000180A-0000-1000-8000-00805F9B34FB
+pub(crate) const CHARACTERISTIC_SERIAL: Uuid = Uuid::from_u128(0x00002A2500001000800000805F9B34FB);             // 00002A25-0000-1000-8000-00805F9B34FB
+pub(crate) const CHARACTERISTIC_MODEL: Uuid = Uuid::from_u128(0x00002A2400001000800000805F9B34FB);              // 00002A24-0000-1000-8000-00805F9B34FB
+pub(crate) const CHARACTERISTIC_MANUFACTURER: Uuid = Uuid::from_u128(0x00002A2900001000800000805F9B34FB);       // 00002A29-0000-1000-8000-00805F9B34FB
+pub(crate) const CHARACTERISTIC_HARDWARE_REVISION: Uuid = Uuid::from_u128(0x00002A2700001000800000805F9B34FB);  // 00002A27-0000-1000-8000-00805F9B34FB
+pub(crate) const CHARACTERISTIC_FIRMWARE_REVISION: Uuid = Uuid::from_u128(0x00002A2600001000800000805F9B34FB);  // 00002A26-0000-1000-8000-00805F9B34FB
+pub(crate) const CHARACTERISTIC_SOFTWARE_REVISION: Uuid = Uuid::from_u128(0x00002A2800001000800000805F9B34FB);  // 00002A28-0000-1000-8000-00805F9B34FB
+pub(crate) const CHARACTERISTIC_SYSTEM_ID: Uuid = Uuid::from_u128(0x00002A2300001000800000805F9B34FB);          // 00002A23-0000-1000-8000-00805F9B34FB
+pub(crate) const SERVICE_BATTERY: Uuid = Uuid::from_u128(0x0000180F00001000800000805F9B34FB);                   // 0000180F-0000-1000-8000-00805F9B34FB
+pub(crate) const CHARACTERISTIC_BATTERY: Uuid = Uuid::from_u128(0x00002A1900001000800000805F9B34FB);            // 00002A19-0000-1000-8000-00805F9B34FB
+pub(crate) const SERVICE_HEART_RATE: Uuid = Uuid::from_u128(0x0000180D00001000800000805F9B34FB);                // 0000180D-0000-1000-8000-00805F9B34FB
+pub(crate) const CHARACTERISTIC_HEART_RATE_MEASUREMENT: Uuid = Uuid::from_u128(0x00002A3700001000800000805F9B34FB); // 00002A37-0000-1000-8000-00805F9B34FB
+pub(crate) const SERVICE_PULSE_OXIMETER: Uuid = Uuid::from_u128(0x0000182200001000800000805F9B34FB);            // 00001822-0000-1000-8000-00805F9B34FB
+pub(crate) const CHARACTERISTIC_PLX_SPOT_CHECK: Uuid = Uuid::from_u128(0x00002A5E00001000800000805F9B34FB);     // 00002A5E-0000-1000-8000-00805F9B34FB
+pub(crate) const CHARACTERISTIC_PLX_CONTINUOUS: Uuid = Uuid::from_u128(0x00002A5F00001000800000805F9B34FB);     // 00002A5F-0000-1000-8000-00805F9B34FB
+pub(crate) const SERVICE_TIME: Uuid = Uuid::from_u128(0x0000180600001000800000805F9B34FB);                      // 00001806-0000-1000-8000-00805F9B34FB
+pub(crate) const CHARACTERISTIC_TIME: Uuid = Uuid::from_u128(0x00002A2D00001000800000805F9B34FB);               // 00002A2D-0000-1000-8000-00805F9B34FB
+pub(crate) const SERVICE_DATA: Uuid = Uuid::from_u128(0xDCF31A27A904F3A3AA4E5AE42F1217B6);                      // DCF31A27-A904-F3A3-AA4E-5AE42F1217B6
+pub(crate) const CHARACTERISTIC_DATA: Uuid = Uuid::from_u128(0xDCF31A27A904F4A3A24E5AE42F8617B6);               // DCF31A27-A904-F4A3-A24E-5AE42F8617B6
+pub(crate) const CHARACTERISTIC_CHANNEL_LAYOUT: Uuid = Uuid::from_u128(0xDCF31A27A904F5A3A24E5AE42F8617B6);     // DCF31A27-A904-F5A3-A24E-5AE42F8617B6
+pub(crate) const CHARACTERISTIC_CONTROL: Uuid = Uuid::from_u128(0xDCF31A27A904F6A3A24E5AE42F8617B6);            // DCF31A27-A904-F6A3-A24E-5AE42F8617B6
+pub(crate) const CHARACTERISTIC_BACKLOG: Uuid = Uuid::from_u128(0xDCF31A27A904F7A3A24E5AE42F8617B6);            // DCF31A27-A904-F7A3-A24E-5AE42F8617B6
 
 impl Ble {
-    pub fn new(event_publisher: Sender<ExternalBleEvent>, max_initial_rtt_ms: u32, logger: Logger) -> Self {
+    pub fn new(
+        transport: Arc<dyn BleTransport>,
+        profiles: Vec<SensorProfile>,
+        settings: BleSettings,
+        known_devices: Vec<KnownDevice>,
+        clock: Arc<dyn Clock>,
+        event_publisher: Sender<ExternalBleEvent>,
+        logger: Logger,
+    ) -> Self {
         let logger = logger.new(o!("module" => "ble"));
         Self {
-            max_initial_rtt_ms,
+            transport,
+            profiles: Arc::new(profiles),
+            settings,
+            known_ids: Mutex::new(known_devices.iter().map(|d| d.transport_id.clone()).collect()),
+            serials: Mutex::new(known_devices.iter().map(|d| (d.transport_id.clone(), d.serial)).collect()),
+            device_ids: Mutex::new(known_devices.into_iter().map(|d| (d.transport_id, d.id)).collect()),
+            requested: Mutex::new(HashSet::new()),
+            released: Mutex::new(HashSet::new()),
+            paused_devices: Mutex::new(HashSet::new()),
+            streaming_paused: AtomicBool::new(false),
+            sessions: Mutex::new(HashMap::new()),
+            nearby: Mutex::new(HashMap::new()),
+            clocks: Mutex::new(HashMap::new()),
+            stream_positions: Mutex::new(HashMap::new()),
+            links: Mutex::new(HashMap::new()),
+            paused: AtomicBool::new(false),
+            clock,
             event_publisher,
             tx: Arc::new(Mutex::new(None)),
             logger,
         }
     }
 
-    pub async fn run_loop(&self) -> Result<(), Box<dyn Error>> {
-        let manager = Manager::new().await?;
-        let adapters = manager.adapters().await?;
-        let central = adapters.into_iter().next().ok_or("No adapter found")?;
+    pub async fn run_loop(self: Arc<Self>) -> BleResult<()> {
+        let central = self.transport.clone();
 
-        central
-            .start_scan(ScanFilter { services: vec![SERVICE_DATA] })
-            .await?;
-        debug!(self.logger, "Scanning for devices");
+        // subscribe before scanning, so no discovery gets lost
+        let event_stream = central.events().await?.map(InternalBleEvent::TransportEvent);
 
-        let event_stream = central.events().await?.map(InternalBleEvent::CentralEvent);
+        let scan_services = self.scan_services();
+        central.start_scan(scan_services.clone()).await?;
+        debug!(self.logger, "Scanning for devices"; "services" => format!("{:?}", scan_services));
 
         let (tx, rx) = tokio::sync::mpsc::channel(100);
         let notification_stream = ReceiverStream::new(rx);
@@ -85,220 +280,963 @@ impl Ble {
         *tx_lock = Some(tx);
         drop(tx_lock);
 
+        self.reconnect_known().await;
+
         let mut combined_stream = select(event_stream, notification_stream);
 
-        let event_publisher = self.event_publisher.clone();
-        let max_initial_rtt_ms = self.max_initial_rtt_ms;
         let logger = self.logger.clone();
         while let Some(event) = combined_stream.next().await {
-            let event_publisher = event_publisher.clone();
             let logger = logger.clone();
             match event {
-                InternalBleEvent::CentralEvent(CentralEvent::DeviceDiscovered(id)) => {
+                InternalBleEvent::TransportEvent(TransportEvent::DeviceDiscovered(id)) => {
                     trace!(logger, "Device discovered"; "id" => format!("{:?}", id));
-                    let device = central.peripheral(&id).await.unwrap();
-                    tokio::spawn(async move {
-                        Ble::handle_discovered_device(&device, event_publisher, max_initial_rtt_ms, &logger.clone())
-                            .await.unwrap();
-                        trace!(logger, "Device handling done"; "id" => format!("{:?}", id));
-                    });
+                    self.update_nearby(&id).await;
+                    if !self.paused.load(Ordering::SeqCst) && self.should_connect(&id).await {
+                        self.maintain_connection(id).await;
+                    }
+                }
+                InternalBleEvent::TransportEvent(TransportEvent::DeviceUpdated(id)) => {
+                    self.update_nearby(&id).await;
                 }
-                InternalBleEvent::CentralEvent(CentralEvent::DeviceDisconnected(id)) => {
+                InternalBleEvent::TransportEvent(TransportEvent::DeviceDisconnected(id)) => {
                     trace!(logger, "Device disconnected"; "id" => format!("{:?}", id));
-                    event_publisher.send(ExternalBleEvent::DeviceDisconnected(id.to_string())).await.unwrap();
+                    if let Some(disconnected) = self.links.lock().await.get(&id) {
+                        disconnected.notify_one();
+                    }
                 }
                 InternalBleEvent::ForwardedEvent(VVCoreInternalEvent::SyncTime) => {
                     trace!(logger, "Syncing time for all devices");
 
-                    for device in central.peripherals().await.unwrap() {
-                        let logger = logger.clone();
-
-                        if !device.is_connected().await.unwrap() {
+                    let peripherals = match central.peripherals().await {
+                        Ok(peripherals) => peripherals,
+                        Err(e) => {
+                            self.report(VVCoreError::bluetooth(e)).await;
                             continue;
                         }
+                    };
+                    for device in peripherals {
+                        if !device.is_connected().await.unwrap_or(false) {
+                            continue;
+                        }
+                        let Some(id) = self.device_id(&device.id()).await else {
+                            continue;
+                        };
 
-                        let event_publisher = event_publisher.clone();
+                        let ble = self.clone();
                         tokio::spawn(async move {
-                            let id = device.id().to_string();
-                            trace!(logger, "Syncing time for device"; "device_id" => id.clone());
-                            let drift = Ble::sync_time_for_device(&device, max_initial_rtt_ms, &logger).await.unwrap_or_else(|e| {
-                                error!(logger, "Failed to sync time for device"; "device_id" => id.clone(), "error" => format!("{:?}", e));
-                                0
-                            });
-
-                            event_publisher.send(ExternalBleEvent::DriftChanged(id, drift)).await.unwrap();
+                            trace!(ble.logger, "Syncing time for device"; "device_id" => id.clone());
+                            match ble.sync_clock(device.as_ref(), &id, false).await {
+                                Ok(estimate) => {
+                                    let _ = ble.event_publisher.send(ExternalBleEvent::ClockSynced(id, estimate)).await;
+                                }
+                                Err(e) => {
+                                    error!(ble.logger, "Failed to sync time for device"; "device_id" => id.clone(), "error" => format!("{:?}", e));
+                                    ble.report(VVCoreError::device(&id, e)).await;
+                                }
+                            }
                         });
                     }
                 }
                 InternalBleEvent::ForwardedEvent(VVCoreInternalEvent::Pause) => {
                     trace!(logger, "Pausing BLE");
-                    central.stop_scan().await.unwrap();
+                    // stops reconnecting, the connection tasks end once their devices are disconnected
+                    self.paused.store(true, Ordering::SeqCst);
+                    if let Err(e) = central.stop_scan().await {
+                        self.report(VVCoreError::bluetooth(e)).await;
+                    }
                     // disconnect all devices
-                    for device in central.peripherals().await.unwrap() {
-                        device.disconnect().await.unwrap();
+                    match central.peripherals().await {
+                        Ok(peripherals) => for device in peripherals {
+                            if let Some(id) = self.device_id(&device.id()).await {
+                                self.set_state(&id, &device.id(), DeviceState::Disconnecting).await;
+                            }
+                            if let Err(e) = device.disconnect().await {
+                                warn!(logger, "Failed to disconnect device"; "transport_id" => device.id(), "error" => e.to_string());
+                            }
+                        },
+                        Err(e) => self.report(VVCoreError::bluetooth(e)).await,
                     }
                 }
                 InternalBleEvent::ForwardedEvent(VVCoreInternalEvent::Resume) => {
                     trace!(logger, "Resuming BLE");
-                    central.start_scan(ScanFilter { services: vec![SERVICE_DATA] }).await.unwrap();
+                    self.paused.store(false, Ordering::SeqCst);
+                    if let Err(e) = central.start_scan(scan_services.clone()).await {
+                        self.report(VVCoreError::bluetooth(e)).await;
+                    }
+                    self.reconnect_known().await;
+                }
+                InternalBleEvent::ForwardedEvent(VVCoreInternalEvent::Connect(id)) => {
+                    let transport_id = self.transport_id(&id).await;
+                    trace!(logger, "Connecting device on request"; "transport_id" => transport_id.clone());
+                    self.released.lock().await.remove(&transport_id);
+                    self.requested.lock().await.insert(transport_id.clone());
+                    // a paused loop connects it on resume
+                    if !self.paused.load(Ordering::SeqCst) {
+                        self.maintain_connection(transport_id).await;
+                    }
+                }
+                InternalBleEvent::ForwardedEvent(VVCoreInternalEvent::Disconnect(id)) => {
+                    let transport_id = self.transport_id(&id).await;
+                    trace!(logger, "Disconnecting device on request"; "transport_id" => transport_id.clone());
+                    self.requested.lock().await.remove(&transport_id);
+                    self.released.lock().await.insert(transport_id.clone());
+                    match central.peripheral(&transport_id).await {
+                        Ok(device) => {
+                            let id = self.device_id(&transport_id).await.unwrap_or_else(|| transport_id.clone());
+                            self.set_state(&id, &transport_id, DeviceState::Disconnecting).await;
+                            if let Err(e) = device.disconnect().await {
+                                self.report(VVCoreError::device(&id, e)).await;
+                            }
+                        }
+                        Err(e) => self.report(VVCoreError::device(&id, e)).await,
+                    }
+                }
+                InternalBleEvent::ForwardedEvent(VVCoreInternalEvent::PauseDevice(id)) => {
+                    let transport_id = self.transport_id(&id).await;
+                    trace!(logger, "Pausing device data"; "transport_id" => transport_id.clone());
+                    self.paused_devices.lock().await.insert(transport_id.clone());
+                    self.command(&transport_id, SessionCommand::PauseData).await;
+                }
+                InternalBleEvent::ForwardedEvent(VVCoreInternalEvent::ResumeDevice(id)) => {
+                    let transport_id = self.transport_id(&id).await;
+                    trace!(logger, "Resuming device data"; "transport_id" => transport_id.clone());
+                    self.paused_devices.lock().await.remove(&transport_id);
+                    self.command(&transport_id, SessionCommand::ResumeData).await;
+                }
+                InternalBleEvent::ForwardedEvent(VVCoreInternalEvent::Command(id, command_id, command)) => {
+                    let transport_id = self.transport_id(&id).await;
+                    let session = self.sessions.lock().await.get(&transport_id).cloned();
+                    let sent = session.is_some_and(|session| session.send(SessionCommand::Control(command_id, command)).is_ok());
+                    if !sent {
+                        let id = self.device_id(&transport_id).await.unwrap_or(id);
+                        self.complete_command(&id, command_id, CommandOutcome::Failed { message: "Device not connected".to_string() }).await;
+                    }
+                }
+                InternalBleEvent::ForwardedEvent(VVCoreInternalEvent::PauseStreaming) => {
+                    trace!(logger, "Pausing data of all devices");
+                    self.streaming_paused.store(true, Ordering::SeqCst);
+                    let transport_ids: Vec<String> = self.sessions.lock().await.keys().cloned().collect();
+                    self.paused_devices.lock().await.extend(transport_ids.iter().cloned());
+                    for transport_id in transport_ids {
+                        self.command(&transport_id, SessionCommand::PauseData).await;
+                    }
+                }
+                InternalBleEvent::ForwardedEvent(VVCoreInternalEvent::ResumeStreaming) => {
+                    trace!(logger, "Resuming data of all devices");
+                    self.streaming_paused.store(false, Ordering::SeqCst);
+                    self.paused_devices.lock().await.clear();
+                    let transport_ids: Vec<String> = self.sessions.lock().await.keys().cloned().collect();
+                    for transport_id in transport_ids {
+                        self.command(&transport_id, SessionCommand::ResumeData).await;
+                    }
                 }
-                _ => {}
             }
         }
         Ok(())
     }
 
+    /// Passes a command to the session of a device, devices without one get it applied when they connect
+    async fn command(&self, transport_id: &str, command: SessionCommand) {
+        if let Some(session) = self.sessions.lock().await.get(transport_id) {
+            if session.send(command).is_err() {
+                trace!(self.logger, "Session ended, dropping command"; "transport_id" => transport_id);
+            }
+        }
+    }
+
+    /// Whether to connect a discovered device: on request, or by auto-connect unless the allowlist
+    /// rules it out by a serial seen before. Devices with unknown serials are checked once connected.
+    async fn should_connect(&self, transport_id: &str) -> bool {
+        if self.requested.lock().await.contains(transport_id) {
+            return true;
+        }
+        if !self.settings.auto_connect || self.released.lock().await.contains(transport_id) {
+            return false;
+        }
+        match self.serials.lock().await.get(transport_id) {
+            Some(serial) => self.allows_serial(*serial),
+            None => true,
+        }
+    }
+
+    fn allows_serial(&self, serial: u16) -> bool {
+        self.settings.auto_connect_serials.is_empty() || self.settings.auto_connect_serials.contains(&serial)
+    }
+
+    /// Transport id of a known device by its stable id, other ids are taken as transport ids
+    async fn transport_id(&self, id: &str) -> String {
+        self.device_ids.lock().await.iter()
+            .find(|(_, device_id)| *device_id == id)
+            .map_or_else(|| id.to_string(), |(transport_id, _)| transport_id.clone())
+    }
+
+    /// Reads the advertisement of a device and reports all nearby devices, strongest signal first
+    async fn update_nearby(&self, transport_id: &str) {
+        let advertisement = match self.transport.peripheral(transport_id).await {
+            Ok(device) => device.advertisement().await,
+            Err(e) => Err(e),
+        };
+        let advertisement = match advertisement {
+            Ok(advertisement) => advertisement,
+            Err(e) => {
+                warn!(self.logger, "Failed to read advertisement"; "transport_id" => transport_id, "error" => e.to_string());
+                return;
+            }
+        };
+
+        let mut nearby = self.nearby.lock().await;
+        if nearby.get(transport_id) == Some(&advertisement) {
+            return;
+        }
+        nearby.insert(transport_id.to_string(), advertisement);
+
+        let device_ids = self.device_ids.lock().await;
+        let mut devices: Vec<NearbyDevice> = nearby.iter().map(|(transport_id, advertisement)| NearbyDevice {
+            transport_id: transport_id.clone(),
+            device_id: device_ids.get(transport_id).cloned(),
+            name: advertisement.local_name.clone(),
+            rssi: advertisement.rssi,
+            services: advertisement.services.iter().map(|s| s.to_string()).collect(),
+        }).collect();
+        drop(device_ids);
+        drop(nearby);
+        devices.sort_by(|a, b| b.rssi.cmp(&a.rssi).then_with(|| a.transport_id.cmp(&b.transport_id)));
+
+        if self.event_publisher.send(ExternalBleEvent::NearbyDevicesChanged(devices)).await.is_err() {
+            error!(self.logger, "Failed to send nearby devices to event publisher");
+        }
+    }
+
+    /// Connects the known and requested devices the transport already knows about, the others are
+    /// picked up by the scan
+    async fn reconnect_known(self: &Arc<Self>) {
+        let mut transport_ids = self.known_ids.lock().await.clone();
+        transport_ids.extend(self.requested.lock().await.iter().cloned());
+        for transport_id in transport_ids {
+            if !self.should_connect(&transport_id).await {
+                continue;
+            }
+            if self.transport.peripheral(&transport_id).await.is_ok() {
+                self.maintain_connection(transport_id).await;
+            }
+        }
+    }
+
+    /// Spawns the task that connects the device and keeps it connected, unless one is already running
+    async fn maintain_connection(self: &Arc<Self>, transport_id: String) {
+        let disconnected = Arc::new(Notify::new());
+        let mut links = self.links.lock().await;
+        if links.contains_key(&transport_id) {
+            trace!(self.logger, "Device already handled"; "transport_id" => transport_id);
+            return;
+        }
+        links.insert(transport_id.clone(), disconnected.clone());
+        drop(links);
+
+        let id = self.device_id(&transport_id).await.unwrap_or_else(|| transport_id.clone());
+        self.set_state(&id, &transport_id, DeviceState::Discovered).await;
+
+        let ble = self.clone();
+        tokio::spawn(async move {
+            ble.connection_loop(&transport_id, &disconnected).await;
+            ble.links.lock().await.remove(&transport_id);
+            trace!(ble.logger, "Device handling done"; "transport_id" => transport_id);
+        });
+    }
+
+    /// Runs connection sessions until the device drops for good. Devices that were connected
+    /// before are reconnected with exponential backoff, others are left to the next discovery.
+    async fn connection_loop(&self, transport_id: &str, disconnected: &Notify) {
+        let mut attempt = 0;
+        loop {
+            let result = match self.transport.peripheral(transport_id).await {
+                Ok(device) => {
+                    let result = self.handle_discovered_device(device.as_ref(), disconnected).await;
+                    self.sessions.lock().await.remove(transport_id);
+                    if result.is_err() {
+                        let _ = device.disconnect().await;
+                    }
+                    result
+                }
+                Err(e) => Err(e),
+            };
+
+            let id = self.device_id(transport_id).await;
+            match result {
+                Ok(Session::Rejected) => {
+                    let _ = self.event_publisher.send(ExternalBleEvent::DeviceIgnored(transport_id.to_string())).await;
+                    return;
+                }
+                Ok(Session::Ended) => {
+                    attempt = 0;
+                    let id = id.clone().unwrap_or_else(|| transport_id.to_string());
+                    self.event_publisher.send(ExternalBleEvent::DeviceDisconnected(id)).await.unwrap_or_else(|e| {
+                        error!(self.logger, "Failed to send disconnect to event publisher"; "error" => format!("{:?}", e));
+                    });
+                }
+                Err(e) => {
+                    warn!(self.logger, "Failed to connect device"; "transport_id" => transport_id, "error" => e.to_string());
+                    let device_id = id.as_deref().unwrap_or(transport_id);
+                    self.report(VVCoreError::device(device_id, e)).await;
+                }
+            }
+
+            // devices that never connected are left to the next discovery, released ones for good
+            if self.released.lock().await.contains(transport_id) {
+                return;
+            }
+            let Some(id) = id.filter(|_| !self.paused.load(Ordering::SeqCst)) else {
+                return;
+            };
+
+            attempt += 1;
+            if attempt > self.settings.reconnect_policy.max_attempts {
+                warn!(self.logger, "Giving up reconnecting device"; "device_id" => id.clone());
+                let _ = self.event_publisher.send(ExternalBleEvent::ReconnectFailed(id)).await;
+                return;
+            }
+
+            let delay = self.settings.reconnect_policy.delay(attempt);
+            debug!(self.logger, "Reconnecting device"; "device_id" => id.clone(), "attempt" => attempt, "delay" => format!("{:?}", delay));
+            let _ = self.event_publisher.send(ExternalBleEvent::Reconnecting(id, attempt)).await;
+            tokio::time::sleep(delay).await;
+
+            if self.paused.load(Ordering::SeqCst) || self.released.lock().await.contains(transport_id) {
+                return;
+            }
+        }
+    }
+
+    /// Stable id of a device connected before, by its transport id
+    async fn device_id(&self, transport_id: &str) -> Option<String> {
+        self.device_ids.lock().await.get(transport_id).cloned()
+    }
+
+    /// Union of the scan services of all profiles
+    fn scan_services(&self) -> Vec<Uuid> {
+        let mut services: Vec<Uuid> = self.profiles.iter().flat_map(|p| p.scan_services.clone()).collect();
+        services.sort();
+        services.dedup();
+        services
+    }
+
     pub(crate) async fn forward_event(&self, event: VVCoreInternalEvent) {
         let tx_lock = self.tx.lock().await;
         if let Some(tx) = &*tx_lock {
-            tx.send(InternalBleEvent::ForwardedEvent(event)).await.unwrap();
+            if tx.send(InternalBleEvent::ForwardedEvent(event)).await.is_err() {
+                error!(self.logger, "BLE loop ended, dropping event");
+            }
+        }
+    }
+
+    /// Passes a failure on to the delegate
+    async fn report(&self, error: VVCoreError) {
+        warn!(self.logger, "Reporting error"; "error" => error.to_string());
+        if self.event_publisher.send(ExternalBleEvent::Error(error)).await.is_err() {
+            error!(self.logger, "Failed to send error to event publisher");
         }
     }
 
-    async fn handle_value_notification(device_id: String, decoder: Arc<DatapointDecoder>, uuid: Uuid, value: Vec<u8>, event_publisher: Sender<ExternalBleEvent>, logger: Logger) {
+    /// Handles a battery or data notification. Returns the LED current change the auto-gain
+    /// asks for, if any.
+    async fn handle_value_notification(&self, device_id: String, profile: &SensorProfile, decoder: &mut DatapointDecoder, sample_clock: &mut SampleClock, auto_gain: Option<&mut AutoGain>, notification: ValueNotification) -> Option<(CommandId, ControlCommand)> {
+        let ValueNotification { uuid, value, .. } = notification;
+        let logger = &self.logger;
+        let event_publisher = &self.event_publisher;
         match uuid {
             uuid if uuid == CHARACTERISTIC_BATTERY => {
                 if let Some(&battery_level) = value.first() {
                     trace!(logger, "Battery level changed"; "device_id" => device_id.clone(), "battery_level" => battery_level);
-                    event_publisher.send(ExternalBleEvent::BatteryLevelChanged(device_id, battery_level)).await.unwrap();
+                    event_publisher.send(ExternalBleEvent::BatteryLevelChanged(device_id, battery_level)).await.unwrap_or_else(|e| {
+                        error!(logger, "Failed to send battery level to event publisher"; "error" => format!("{:?}", e));
+                    });
                 }
             }
-            uuid if uuid == CHARACTERISTIC_DATA => {
+            uuid if uuid == profile.data_characteristic => {
                 // device id and hex formated data
                 trace!(logger, "Data received"; "device_id" => device_id.clone(), "data" => format!("{:?}", value.iter().map(|x| format!("{:02x} ", x)).collect::<String>()));
-                let decoded = decoder(value);
+                let arrival_us = self.clock.now().timestamp_micros();
+                let mut decoded = decoder(value);
+                if decoded.lost_packets > 0 {
+                    debug!(logger, "Packets lost"; "device_id" => device_id.clone(), "count" => decoded.lost_packets);
+                }
+
+                match profile.protocol {
+                    DataProtocol::Packets => {
+                        let count = decoded.samples.values().next().map_or(0, |samples| samples.len());
+                        let skew_ppm = self.clocks.lock().await.get(&device_id)
+                            .and_then(|model| model.estimate())
+                            .map_or(0.0, |estimate| estimate.skew_ppm);
+                        decoded.timestamps_us = sample_clock.timestamps(arrival_us, count, skew_ppm);
+                        if let (Some(sequence), Some(&timestamp_us)) = (decoded.sequence, decoded.timestamps_us.last()) {
+                            self.stream_positions.lock().await.insert(device_id.clone(), StreamPosition { sequence, timestamp_us });
+                        }
+                    }
+                    DataProtocol::HeartRate => heart_rate::timestamp(&mut decoded, &device_id, arrival_us),
+                    DataProtocol::PulseOximeter => {
+                        let count = decoded.samples.values().next().map_or(0, |samples| samples.len());
+                        decoded.timestamps_us = vec![arrival_us; count];
+                    }
+                }
 
-                event_publisher.send(ExternalBleEvent::DataReceived(decoded)).await.unwrap_or_else(|e| {
+                let mut gain_change = None;
+                if let Some(auto_gain) = auto_gain {
+                    let now = tokio::time::Instant::now();
+                    if auto_gain.settling(now) {
+                        decoded.settling = auto_gain.channel_ids();
+                    }
+                    gain_change = auto_gain.measure(&decoded, now);
+                }
+
+                event_publisher.send(ExternalBleEvent::DataReceived(device_id, decoded)).await.unwrap_or_else(|e| {
+                    error!(logger, "Failed to send data to event publisher"; "error" => format!("{:?}", e));
+                });
+                return gain_change;
+            }
+            uuid if uuid == CHARACTERISTIC_PLX_SPOT_CHECK && profile.protocol == DataProtocol::PulseOximeter => {
+                let mut decoded = pulse_oximeter::decode_spot_check(&device_id, &value);
+                if decoded.samples.is_empty() {
+                    warn!(logger, "Invalid spot-check measurement"; "device_id" => device_id.clone(), "value" => format!("{:?}", value));
+                    return None;
+                }
+                // the time the device keeps is not synced, the measurement is placed at its arrival
+                decoded.timestamps_us = vec![self.clock.now().timestamp_micros()];
+                event_publisher.send(ExternalBleEvent::DataReceived(device_id, decoded)).await.unwrap_or_else(|e| {
                     error!(logger, "Failed to send data to event publisher"; "error" => format!("{:?}", e));
                 });
             }
             _ => warn!(logger, "Unhandled notification"; "device_id" => device_id.clone(), "uuid" => format!("{:?}", uuid)),
         }
+        None
     }
 
-    async fn handle_discovered_device(
-        device: &impl Peripheral,
-        event_publisher: Sender<ExternalBleEvent>,
-        max_initial_rtt_ms: u32,
-        logger: &Logger,
-    ) -> Result<(), Box<dyn Error>> {
-        device.connect().await?;
-        device.discover_services().await?;
+    /// Sets up a connected session and handles its notifications until the device disconnects.
+    /// A failed session leaves the device in the `Failed` state.
+    async fn handle_discovered_device(&self, device: &dyn BlePeripheral, disconnected: &Notify) -> BleResult<Session> {
+        let transport_id = device.id();
+        // until its information is read, a device new to us goes by its transport id
+        let mut id = self.device_id(&transport_id).await.unwrap_or_else(|| transport_id.clone());
+
+        let result = self.run_session(device, disconnected, &mut id).await;
+        if let Err(e) = &result {
+            self.set_state(&id, &transport_id, DeviceState::Failed { reason: e.to_string() }).await;
+        }
+        result
+    }
 
-        let id = device.id().to_string();
+    async fn run_session(&self, device: &dyn BlePeripheral, disconnected: &Notify, id: &mut String) -> BleResult<Session> {
+        let logger = &self.logger;
+        let transport_id = device.id();
+
+        self.step(id, &transport_id, DeviceState::Connecting, device.connect()).await?;
+
+        // the notification stream is obtained before subscribing, so the first notifications are not missed
+        let mut notification_stream: NotificationStream = self.step(id, &transport_id, DeviceState::DiscoveringServices, async {
+            device.discover_services().await?;
+            device.notifications().await
+        }).await?;
         
-        let drift = Ble::sync_time_for_device(device, max_initial_rtt_ms, logger).await.unwrap_or_else(|e| {
+        let (info, battery) = self.step(id, &transport_id, DeviceState::ReadingInfo, Ble::get_device_information(device, logger)).await?;
+        let serial = info.serial;
+        *id = identity::device_id(&info.model, serial);
+        self.serials.lock().await.insert(transport_id.clone(), serial);
+
+        if !self.requested.lock().await.contains(&transport_id) && !self.allows_serial(serial) {
+            debug!(logger, "Serial not allowed for auto-connect"; "transport_id" => transport_id.clone(), "serial" => serial);
+            self.released.lock().await.insert(transport_id.clone());
+            device.disconnect().await?;
+            return Ok(Session::Rejected);
+        }
+
+        let clock = self.step(id, &transport_id, DeviceState::SyncingTime, self.sync_clock(device, id, true)).await.unwrap_or_else(|e| {
             error!(logger, "Failed to sync time for device"; "device_id" => id.clone(), "error" => format!("{:?}", e));
-            0
+            ClockEstimate { offset_us: 0, skew_ppm: 0.0, uncertainty_us: 0, rtt_us: 0 }
         });
 
-        let (serial, model, battery, first_data) =
-            Ble::get_device_information_and_subscribe(device, logger).await?;
+        let services: Vec<Uuid> = device.services().iter().map(|s| s.uuid).collect();
+        let profile = SensorProfile::select(&self.profiles, &info.model, &services).ok_or("No sensor profile matches device")?;
+        debug!(logger, "Using sensor profile"; "device_id" => id.clone(), "profile" => profile.name.clone());
+
+        // commands sent from here on are queued for the notification loop
+        let (commands_tx, mut commands) = unbounded_channel();
+        self.sessions.lock().await.insert(transport_id.clone(), commands_tx);
+        if self.streaming_paused.load(Ordering::SeqCst) {
+            self.paused_devices.lock().await.insert(transport_id.clone());
+        }
+        let mut paused = self.paused_devices.lock().await.contains(&transport_id);
 
-        // TODO: move channel mapping to a separate characteristic
-        // for now, we hardcode the channel mapping and differentiate ECG from non-ECG devices by
-        // reading the initial data value, whose ECG data is constant 0 for non-ECG devices
-        let (channels, datapoint_decoder) = Ble::temp_create_channels(id.clone(), &first_data, logger).unwrap();
+        let (layout, data_characteristic) = self.step(id, &transport_id, DeviceState::Subscribing, Ble::subscribe_data(device, profile, !paused, logger)).await?;
+        let control_characteristic = self.step(id, &transport_id, DeviceState::Subscribing, Ble::subscribe_optional(device, profile, CHARACTERISTIC_CONTROL, logger)).await?;
+        let backlog_characteristic = match self.settings.download_backlog {
+            true => self.step(id, &transport_id, DeviceState::Subscribing, Ble::subscribe_optional(device, profile, CHARACTERISTIC_BACKLOG, logger)).await?,
+            false => None,
+        };
+        if profile.protocol == DataProtocol::PulseOximeter {
+            self.step(id, &transport_id, DeviceState::Subscribing, Ble::subscribe_optional(device, profile, CHARACTERISTIC_PLX_SPOT_CHECK, logger)).await?;
+        }
+
+        // standard measurements describe themselves and carry their own timing
+        let layout = match profile.protocol {
+            // an explicit layout in the profile takes precedence over the one the device reports
+            DataProtocol::Packets => Some(profile.layout.clone().or(layout).unwrap_or_else(|| {
+                // HACK: firmware without the layout characteristic does not tell us whether it has an
+                // ECG front end, our only PPG-only board is serial 72
+                debug!(logger, "No channel layout, using legacy layout"; "device_id" => id.clone());
+                ChannelLayout::legacy(serial != 72)
+            })),
+            DataProtocol::HeartRate | DataProtocol::PulseOximeter => None,
+        };
+        let channels = match (&layout, profile.protocol) {
+            (Some(layout), _) => layout.channels(id),
+            (None, DataProtocol::PulseOximeter) => pulse_oximeter::channels(id),
+            (None, _) => heart_rate::channels(id),
+        };
+        let decoder = |id: &str| match (&layout, profile.protocol) {
+            (Some(layout), _) => layout.decoder(id),
+            (None, DataProtocol::PulseOximeter) => pulse_oximeter::decoder(id),
+            (None, _) => heart_rate::decoder(id),
+        };
+        // the rate of the layout until a control command changes it
+        let mut current_rate = layout.as_ref().map_or(1.0, |layout| layout.sampling_rate);
+        let mut datapoint_decoder = decoder(id);
+        let mut sample_clock = SampleClock::new(current_rate);
+
+        let mut rssi = self.read_rssi(device).await;
 
         let device_struct = Device {
             id: id.clone(),
+            transport_id: transport_id.clone(),
             serial,
-            name: model,
+            name: info.model,
+            manufacturer: info.manufacturer,
+            hardware_revision: info.hardware_revision,
+            firmware_revision: info.firmware_revision,
+            software_revision: info.software_revision,
+            system_id: info.system_id,
+            rssi,
             battery,
-            drift_us: drift,
+            drift_us: clock.rtt_us,
+            clock_offset_us: clock.offset_us,
+            clock_skew_ppm: clock.skew_ppm,
+            clock_uncertainty_us: clock.uncertainty_us,
+            state: if paused { DeviceState::Paused } else { DeviceState::Streaming },
             connected: true,
+            reconnect_attempts: 0,
+            reconnect_failed: false,
+            packets_received: 0,
+            packets_lost: 0,
             channels,
         };
 
-        let datapoint_decoder = Arc::new(datapoint_decoder);
+        self.event_publisher.send(ExternalBleEvent::DeviceConnected(device_struct)).await?;
+
+        // the device may have had another transport id, after a reinstall or under a new address
+        let mut device_ids = self.device_ids.lock().await;
+        let stale_ids: Vec<String> = device_ids.iter()
+            .filter(|(other, device_id)| device_id.as_str() == id.as_str() && other.as_str() != transport_id.as_str())
+            .map(|(other, _)| other.clone())
+            .collect();
+        for stale_id in stale_ids.iter() {
+            device_ids.remove(stale_id);
+        }
+        device_ids.insert(transport_id.clone(), id.clone());
+        drop(device_ids);
 
-        event_publisher.send(ExternalBleEvent::DeviceConnected(device_struct)).await?;
+        let mut known_ids = self.known_ids.lock().await;
+        known_ids.retain(|known_id| !stale_ids.contains(known_id));
+        if !known_ids.contains(&transport_id) {
+            known_ids.push(transport_id.clone());
+        }
+        drop(known_ids);
+
+        let mut watchdog = StallWatchdog::new(self.settings.stall_timeout);
+        let mut control = ControlChannel::new(self.settings.command_timeout);
+        // told to stop by a control command, so silence is expected
+        let mut sensor_stopped = false;
+        let mut rssi_polls = self.settings.rssi_interval.map(|interval| tokio::time::interval_at(tokio::time::Instant::now() + interval, interval));
+
+        // the previous stream of the device, whose gap is downloaded once the current one starts
+        let mut stream_end = match backlog_characteristic {
+            Some(_) => self.stream_positions.lock().await.get(id.as_str()).copied(),
+            None => None,
+        };
+        let mut backlog: Option<BacklogTransfer> = None;
+
+        let mut auto_gain = None;
+        if let (Some(parameters), Some(characteristic), Some(layout)) = (&self.settings.auto_gain, &control_characteristic, &layout) {
+            match AutoGain::new(parameters, layout, id) {
+                Some(mut gain) => {
+                    let change = gain.initial_command();
+                    self.write_gain(device, characteristic, &mut control, &mut gain, id, change).await;
+                    auto_gain = Some(gain);
+                }
+                None => debug!(logger, "No PPG channel with a known LED, auto-gain disabled"; "device_id" => id.clone()),
+            }
+        }
 
         // handle notifications, blocking the task until device disconnects
-        let mut notification_stream: Pin<Box<dyn Stream<Item=ValueNotification> + Send>> = device.notifications().await?;
+        let streamed: BleResult<()> = async {
+            loop {
+                tokio::select! {
+                    notification = notification_stream.next() => match notification {
+                        // data queued before unsubscribing is dropped
+                        Some(notification) if paused && [profile.data_characteristic, CHARACTERISTIC_PLX_SPOT_CHECK].contains(&notification.uuid) => {}
+                        Some(notification) if notification.uuid == CHARACTERISTIC_CONTROL => {
+                            let Some((command_id, command, outcome)) = control.receive(&notification.value) else {
+                                warn!(logger, "Unexpected control response"; "device_id" => id.clone(), "value" => format!("{:?}", notification.value));
+                                continue;
+                            };
+                            match (&outcome, &command, auto_gain.as_mut()) {
+                                (CommandOutcome::Acknowledged, ControlCommand::SetSamplingRate(rate), _) => {
+                                    current_rate = *rate as f64;
+                                    sample_clock = SampleClock::new(current_rate);
+                                }
+                                (CommandOutcome::Acknowledged, ControlCommand::SetLedCurrents(green, red, ir), Some(auto_gain)) => {
+                                    auto_gain.applied(command_id, *green, *red, *ir, tokio::time::Instant::now());
+                                }
+                                _ => {}
+                            }
+                            self.finish_command(id, auto_gain.as_mut(), command_id, outcome).await;
+                        }
+                        Some(notification) if notification.uuid == CHARACTERISTIC_BACKLOG => {
+                            let Some(transfer) = backlog.as_mut() else {
+                                warn!(logger, "Unexpected backlog notification"; "device_id" => id.clone());
+                                continue;
+                            };
+                            match transfer.receive(&notification.value) {
+                                Ok(BacklogEvent::Started(total)) => self.publish_backlog(ExternalBleEvent::BacklogProgress(id.clone(), 0, total)).await,
+                                Ok(BacklogEvent::Data(packet, received, total)) => {
+                                    self.publish_backlog(ExternalBleEvent::BacklogReceived(id.clone(), packet)).await;
+                                    self.publish_backlog(ExternalBleEvent::BacklogProgress(id.clone(), received, total)).await;
+                                }
+                                Ok(BacklogEvent::Completed(packets)) => {
+                                    debug!(logger, "Backlog downloaded"; "device_id" => id.clone(), "packets" => packets);
+                                    backlog = None;
+                                    self.publish_backlog(ExternalBleEvent::BacklogCompleted(id.clone(), BacklogOutcome::Completed { packets })).await;
+                                }
+                                Err(message) => {
+                                    warn!(logger, "Backlog download failed"; "device_id" => id.clone(), "error" => message.clone());
+                                    backlog = None;
+                                    self.publish_backlog(ExternalBleEvent::BacklogCompleted(id.clone(), BacklogOutcome::Failed { message })).await;
+                                }
+                            }
+                        }
+                        Some(notification) => {
+                            if notification.uuid == profile.data_characteristic && watchdog.feed() {
+                                debug!(logger, "Data flowing again"; "device_id" => id.clone());
+                                self.set_state(id, &transport_id, DeviceState::Streaming).await;
+                            }
+                            let gain_change = self.handle_value_notification(id.clone(), profile, &mut datapoint_decoder, &mut sample_clock, auto_gain.as_mut(), notification).await;
+                            if let (Some(change), Some(characteristic), Some(auto_gain)) = (gain_change, &control_characteristic, auto_gain.as_mut()) {
+                                self.write_gain(device, characteristic, &mut control, auto_gain, id, change).await;
+                            }
+                            if let (Some(end), Some(characteristic), Some(layout)) = (stream_end, &backlog_characteristic, &layout) {
+                                let first = self.stream_positions.lock().await.get(id.as_str()).copied();
+                                if let Some(first) = first.filter(|first| *first != end) {
+                                    stream_end = None;
+                                    backlog = self.request_backlog(device, characteristic, layout, id, end, first).await;
+                                }
+                            }
+                        }
+                        None => break,
+                    },
+                    Some(command) = commands.recv() => match command {
+                        SessionCommand::PauseData if !paused => {
+                            match device.unsubscribe(&data_characteristic).await {
+                                Ok(()) => {
+                                    paused = true;
+                                    // data missed while paused is not wanted later
+                                    stream_end = None;
+                                    self.stream_positions.lock().await.remove(id.as_str());
+                                    self.set_state(id, &transport_id, DeviceState::Paused).await;
+                                }
+                                Err(e) => self.report(VVCoreError::device(id, e)).await,
+                            }
+                        }
+                        SessionCommand::ResumeData if paused => {
+                            match device.subscribe(&data_characteristic).await {
+                                Ok(()) => {
+                                    paused = false;
+                                    // the sequence counter and the sample schedule continued without us
+                                    datapoint_decoder = decoder(id);
+                                    sample_clock = SampleClock::new(current_rate);
+                                    watchdog.reset();
+                                    self.set_state(id, &transport_id, DeviceState::Streaming).await;
+                                }
+                                Err(e) => self.report(VVCoreError::device(id, e)).await,
+                            }
+                        }
+                        SessionCommand::Control(command_id, command) => {
+                            let Some(characteristic) = &control_characteristic else {
+                                self.complete_command(id, command_id, CommandOutcome::Failed { message: "Device has no control characteristic".to_string() }).await;
+                                continue;
+                            };
+                            let command_id = CommandId::App(command_id);
+                            trace!(logger, "Sending control command"; "device_id" => id.clone(), "command" => format!("{:?}", command));
+                            match (&command, auto_gain.as_mut()) {
+                                (ControlCommand::StopStreaming, _) => sensor_stopped = true,
+                                (ControlCommand::StartStreaming, _) => {
+                                    sensor_stopped = false;
+                                    watchdog.reset();
+                                }
+                                // the samples are marked as with the auto-gain's own changes
+                                (ControlCommand::SetLedCurrents(..), Some(auto_gain)) => auto_gain.begin_change(command_id),
+                                _ => {}
+                            }
+                            let frame = control.send(command_id, command);
+                            if let Err(e) = device.write(characteristic, &frame, WriteType::WithResponse).await {
+                                control.cancel(command_id);
+                                self.finish_command(id, auto_gain.as_mut(), command_id, CommandOutcome::Failed { message: e.to_string() }).await;
+                            }
+                        }
+                        _ => {}
+                    },
+                    _ = tokio::time::sleep_until(control.next_deadline().unwrap_or_else(tokio::time::Instant::now)), if control.next_deadline().is_some() => {
+                        for command_id in control.expire(tokio::time::Instant::now()) {
+                            warn!(logger, "Control command timed out"; "device_id" => id.clone(), "command_id" => format!("{:?}", command_id));
+                            self.finish_command(id, auto_gain.as_mut(), command_id, CommandOutcome::TimedOut).await;
+                        }
+                    },
+                    _ = tokio::time::sleep_until(backlog.as_ref().map_or_else(tokio::time::Instant::now, |transfer| transfer.deadline())), if backlog.is_some() => {
+                        warn!(logger, "Backlog download timed out"; "device_id" => id.clone());
+                        backlog = None;
+                        self.publish_backlog(ExternalBleEvent::BacklogCompleted(id.clone(), BacklogOutcome::Failed { message: "Backlog download timed out".to_string() })).await;
+                    },
+                    _ = tokio::time::sleep_until(watchdog.deadline().unwrap_or_else(tokio::time::Instant::now)), if !paused && !sensor_stopped && watchdog.deadline().is_some() => {
+                        let timeout = self.settings.stall_timeout.unwrap_or_default();
+                        match watchdog.expire() {
+                            StallAction::Resubscribe => {
+                                warn!(logger, "Data stalled, resubscribing"; "device_id" => id.clone());
+                                self.set_state(id, &transport_id, DeviceState::Stalled).await;
+                                self.report(VVCoreError::device(id, format!("No data for {:?}, resubscribing", timeout))).await;
+                                device.unsubscribe(&data_characteristic).await
+                                    .map_err(|e| format!("Failed to resubscribe: {}", e))?;
+                                device.subscribe(&data_characteristic).await
+                                    .map_err(|e| format!("Failed to resubscribe: {}", e))?;
+                            }
+                            // the failed session is reported and reconnected
+                            StallAction::Reconnect => return Err(format!("No data for {:?} after resubscribing, reconnecting", timeout).into()),
+                        }
+                    },
+                    _ = async { rssi_polls.as_mut().unwrap().tick().await }, if rssi_polls.is_some() => {
+                        let polled = self.read_rssi(device).await;
+                        if polled != rssi {
+                            rssi = polled;
+                            trace!(logger, "RSSI changed"; "device_id" => id.clone(), "rssi" => format!("{:?}", rssi));
+                            if self.event_publisher.send(ExternalBleEvent::RssiChanged(id.clone(), rssi)).await.is_err() {
+                                error!(logger, "Failed to send RSSI to event publisher");
+                            }
+                        }
+                    },
+                    // not every transport ends the notification stream on disconnect
+                    _ = disconnected.notified() => {
+                        if !device.is_connected().await.unwrap_or(false) {
+                            break;
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }.await;
 
-        while let Some(notification) = notification_stream.next().await {
-            let ValueNotification { uuid, value, .. } = notification;
-            Self::handle_value_notification(id.clone(), datapoint_decoder.clone(), uuid, value, event_publisher.clone(), logger.clone()).await;
+        for command_id in control.drain() {
+            self.finish_command(id, None, command_id, CommandOutcome::Failed { message: "Device disconnected".to_string() }).await;
+        }
+        if backlog.is_some() {
+            self.publish_backlog(ExternalBleEvent::BacklogCompleted(id.clone(), BacklogOutcome::Failed { message: "Device disconnected".to_string() })).await;
         }
+        streamed?;
 
         debug!(logger, "Device disconnected"; "device_id" => id.clone());
 
-        Ok(())
+        Ok(Session::Ended)
     }
 
-    async fn sync_time_for_device(device: &impl Peripheral, max_initial_rtt_ms: u32, logger: &Logger) -> Result<i64, Box<dyn Error>> {
+    /// Asks the device for the packets between the end of its previous stream and the start of
+    /// the current one, `None` if none are missing or the request fails
+    async fn request_backlog(&self, device: &dyn BlePeripheral, characteristic: &Characteristic, layout: &ChannelLayout, id: &str, end: StreamPosition, first: StreamPosition) -> Option<BacklogTransfer> {
+        let gap = Gap::between(end, first, layout)?;
+        debug!(self.logger, "Requesting backlog"; "device_id" => id, "packets" => gap.packets());
+        if let Err(e) = device.write(characteristic, &gap.request(), WriteType::WithResponse).await {
+            self.publish_backlog(ExternalBleEvent::BacklogCompleted(id.to_string(), BacklogOutcome::Failed { message: e.to_string() })).await;
+            return None;
+        }
+        Some(BacklogTransfer::new(gap, layout, id, self.settings.command_timeout))
+    }
 
-        if !device.is_connected().await? {
-            return Err("Device disconnected".into());
+    async fn publish_backlog(&self, event: ExternalBleEvent) {
+        if self.event_publisher.send(event).await.is_err() {
+            error!(self.logger, "Failed to send backlog to event publisher");
         }
+    }
 
-        for service in device.services() {
-            if service.uuid == SERVICE_TIME {
-                for characteristic in &service.characteristics {
-                    if characteristic.uuid == CHARACTERISTIC_TIME {
-                        let mut rtt = -1;
+    /// Writes an LED current change of the auto-gain, which gives up on it if the write fails
+    async fn write_gain(&self, device: &dyn BlePeripheral, characteristic: &Characteristic, control: &mut ControlChannel, auto_gain: &mut AutoGain, id: &str, (command_id, command): (CommandId, ControlCommand)) {
+        debug!(self.logger, "Adjusting LED currents"; "device_id" => id, "command" => format!("{:?}", command));
+        let frame = control.send(command_id, command);
+        if let Err(e) = device.write(characteristic, &frame, WriteType::WithResponse).await {
+            control.cancel(command_id);
+            self.finish_command(id, Some(auto_gain), command_id, CommandOutcome::Failed { message: e.to_string() }).await;
+        }
+    }
 
-                        // try at max 5 times
-                        for _ in 0..5 {
-                            let time_to_set = Utc::now();
-                            let data_to_set = time_to_ble_data(time_to_set);
+    /// Ends a command for the auto-gain and reports its outcome, unless the auto-gain sent it
+    async fn finish_command(&self, id: &str, auto_gain: Option<&mut AutoGain>, command_id: CommandId, outcome: CommandOutcome) {
+        if let Some(auto_gain) = auto_gain {
+            auto_gain.end_change(command_id, tokio::time::Instant::now());
+        }
+        match command_id {
+            CommandId::App(command_id) => self.complete_command(id, command_id, outcome).await,
+            CommandId::AutoGain(_) if outcome != CommandOutcome::Acknowledged => {
+                warn!(self.logger, "Failed to adjust LED currents"; "device_id" => id, "outcome" => format!("{:?}", outcome));
+            }
+            CommandId::AutoGain(_) => {}
+        }
+    }
 
-                            debug!(logger, "Syncing time"; "device_id" => device.id().to_string(), "data" => format!("{:?}", data_to_set.iter().map(|x| format!("{:02x} ", x)).collect::<String>()));
-                            // may hang here if the device does not accept the time
-                            device
-                                .write(characteristic, &data_to_set, WriteType::WithoutResponse)
-                                .await?;
-                            trace!(logger, "Time set"; "device_id" => device.id().to_string());
+    async fn complete_command(&self, id: &str, command_id: u32, outcome: CommandOutcome) {
+        trace!(self.logger, "Control command completed"; "device_id" => id, "command_id" => command_id, "outcome" => format!("{:?}", outcome));
+        if self.event_publisher.send(ExternalBleEvent::CommandCompleted(id.to_string(), command_id, outcome)).await.is_err() {
+            error!(self.logger, "Failed to send command outcome to event publisher");
+        }
+    }
 
-                            let data_read = device.read(characteristic).await?;
-                            let time_to_compare = Utc::now();
+    /// The latest RSSI the platform has for the device, `None` if it has none or fails to tell
+    async fn read_rssi(&self, device: &dyn BlePeripheral) -> Option<i16> {
+        match device.advertisement().await {
+            Ok(advertisement) => advertisement.rssi,
+            Err(e) => {
+                debug!(self.logger, "Failed to read RSSI"; "transport_id" => device.id(), "error" => e.to_string());
+                None
+            }
+        }
+    }
 
-                            let time_read = ble_data_to_time(&data_read)?;
+    /// Reports `state` and runs the connection step, failing it after the step timeout
+    async fn step<T>(&self, id: &str, transport_id: &str, state: DeviceState, step: impl Future<Output = BleResult<T>>) -> BleResult<T> {
+        self.set_state(id, transport_id, state.clone()).await;
+        match tokio::time::timeout(self.settings.step_timeout, step).await {
+            Ok(result) => result,
+            Err(_) => Err(format!("{:?} timed out after {:?}", state, self.settings.step_timeout).into()),
+        }
+    }
 
-                            rtt = time_to_compare.timestamp_micros() - time_read.timestamp_micros();
+    async fn set_state(&self, id: &str, transport_id: &str, state: DeviceState) {
+        trace!(self.logger, "Device state changed"; "device_id" => id, "state" => format!("{:?}", state));
+        let event = ExternalBleEvent::StateChanged(id.to_string(), transport_id.to_string(), state);
+        if self.event_publisher.send(event).await.is_err() {
+            error!(self.logger, "Failed to send state to event publisher");
+        }
+    }
 
-                            if rtt.abs() < (max_initial_rtt_ms * 1000) as i64 {
-                                return Ok(rtt);
-                            }
-                        }
+    /// Measures the device clock and adds the result to the device's clock model. Setting the
+    /// time first starts a new model, as the device clock jumps.
+    async fn sync_clock(&self, device: &dyn BlePeripheral, id: &str, set_time: bool) -> BleResult<ClockEstimate> {
+        let sync = Ble::sync_time_for_device(device, set_time, self.settings.max_initial_rtt_ms, self.clock.as_ref(), &self.logger).await?;
 
-                        return Ok(rtt);
-                    }
-                }
+        let mut clocks = self.clocks.lock().await;
+        let model = clocks.entry(id.to_string()).or_default();
+        if set_time {
+            *model = ClockModel::default();
+        }
+        if model.add(sync) {
+            debug!(self.logger, "Device clock jumped, clock model restarted"; "device_id" => id);
+        }
+        let estimate = model.estimate().ok_or("Clock model without syncs")?;
+
+        debug!(self.logger, "Clock synced"; "device_id" => id, "estimate" => format!("{:?}", estimate));
+        Ok(estimate)
+    }
+
+    /// Optionally sets the device time, then reads it back in rounds of `SYNC_ROUNDS` until the
+    /// best round trip time is below `max_rtt_ms`, at most `SYNC_ATTEMPTS` times
+    async fn sync_time_for_device(device: &dyn BlePeripheral, set_time: bool, max_rtt_ms: u32, clock: &dyn Clock, logger: &Logger) -> BleResult<SyncRound> {
+        if !device.is_connected().await? {
+            return Err("Device disconnected".into());
+        }
+
+        let characteristic = Ble::find_characteristic(device, SERVICE_TIME, CHARACTERISTIC_TIME)
+            .ok_or("Time service or characteristic not found")?;
+
+        if set_time {
+            let data_to_set = time_to_ble_data(clock.now());
+            debug!(logger, "Setting time"; "device_id" => device.id(), "data" => format!("{:?}", data_to_set.iter().map(|x| format!("{:02x} ", x)).collect::<String>()));
+            // may hang here if the device does not accept the time
+            device.write(&characteristic, &data_to_set, WriteType::WithoutResponse).await?;
+            trace!(logger, "Time set"; "device_id" => device.id());
+        }
+
+        let mut best = None;
+        for _ in 0..SYNC_ATTEMPTS {
+            let mut rounds = Vec::with_capacity(SYNC_ROUNDS);
+            for _ in 0..SYNC_ROUNDS {
+                let sent = clock.now();
+                let data_read = device.read(&characteristic).await?;
+                let received = clock.now();
+                rounds.push(SyncRound::new(sent, ble_data_to_time(&data_read)?, received));
+            }
+            trace!(logger, "Sync rounds"; "device_id" => device.id(), "rounds" => format!("{:?}", rounds));
+
+            let estimate = clock_sync::estimate(&rounds).ok_or("No sync rounds")?;
+            best = Some(estimate);
+            if estimate.rtt_us < max_rtt_ms as i64 * 1000 {
+                break;
             }
         }
 
-        Err("Time service or characteristic not found".into())
+        best.ok_or_else(|| "No sync rounds".into())
+    }
+
+    fn find_characteristic(device: &dyn BlePeripheral, service_uuid: Uuid, characteristic_uuid: Uuid) -> Option<Characteristic> {
+        device.services().into_iter()
+            .filter(|s| s.uuid == service_uuid)
+            .flat_map(|s| s.characteristics)
+            .find(|c| c.uuid == characteristic_uuid)
     }
 
-    async fn get_device_information_and_subscribe(
-        device: &impl Peripheral,
+    /// Reads the Device Information Service and the battery level, subscribing to the latter.
+    /// Fields besides the serial and the model are optional and left out if they fail to read.
+    async fn get_device_information(
+        device: &dyn BlePeripheral,
         logger: &Logger
-    ) -> Result<(u16, String, u8, Vec<u8>), Box<dyn Error>> {
-        let mut serial: u16 = 0;
-        let mut model = "".to_string();
+    ) -> BleResult<(DeviceInformation, u8)> {
+        let mut info = DeviceInformation::default();
         let mut battery: u8 = 0;
-
-        // TODO: move channel mapping to a separate characteristic
-        // for now, we hardcode the channel mapping and differentiate ECG from non-ECG devices by
-        // reading the initial data value, whose ECG data is constant 0 for non-ECG devices
-        let mut first_data = vec![];
+        // third-party sensors get a serial derived from whatever identifies them
+        let ours = device.services().iter().any(|service| service.uuid == SERVICE_DATA);
+        let mut serial = None;
 
         for service in device.services() {
             if service.uuid == SERVICE_DEVICE_INFO {
                 for characteristic in &service.characteristics {
-                    if characteristic.uuid == CHARACTERISTIC_SERIAL {
-                        let serial_data = device.read(characteristic).await?;
-                        let serial_str = String::from_utf8(serial_data);
-                        serial = u16::from_str_radix(&serial_str.unwrap(), 10).unwrap();
-
-                        trace!(logger, "Serial: {:?}", serial);
-                    }
-                    if characteristic.uuid == CHARACTERISTIC_MODEL {
-                        let model_data = device.read(characteristic).await?;
-                        model = String::from_utf8(model_data)?;
-                        trace!(logger, "Model: {:?}", model);
+                    let read = || Ble::read_optional(device, characteristic, logger);
+                    match characteristic.uuid {
+                        uuid if uuid == CHARACTERISTIC_SERIAL => {
+                            let data = device.read(characteristic).await?;
+                            serial = match ours {
+                                true => Some(device_info::parse_serial(&data)?),
+                                false => device_info::parse_string(&data).map(|serial| device_info::fold_serial(Some(&serial), "")),
+                            };
+                        }
+                        uuid if uuid == CHARACTERISTIC_MODEL => {
+                            info.model = device_info::parse_string(&device.read(characteristic).await?).unwrap_or_default();
+                        }
+                        uuid if uuid == CHARACTERISTIC_MANUFACTURER => {
+                            info.manufacturer = read().await.and_then(|data| device_info::parse_string(&data));
+                        }
+                        uuid if uuid == CHARACTERISTIC_HARDWARE_REVISION => {
+                            info.hardware_revision = read().await.and_then(|data| device_info::parse_string(&data));
+                        }
+                        uuid if uuid == CHARACTERISTIC_FIRMWARE_REVISION => {
+                            info.firmware_revision = read().await.and_then(|data| device_info::parse_string(&data));
+                        }
+                        uuid if uuid == CHARACTERISTIC_SOFTWARE_REVISION => {
+                            info.software_revision = read().await.and_then(|data| device_info::parse_string(&data));
+                        }
+                        uuid if uuid == CHARACTERISTIC_SYSTEM_ID => {
+                            info.system_id = read().await.and_then(|data| device_info::parse_system_id(&data));
+                        }
+                        _ => {}
                     }
                 }
             }
@@ -307,148 +1245,88 @@ impl Ble {
                 for characteristic in &service.characteristics {
                     if characteristic.uuid == CHARACTERISTIC_BATTERY {
                         let battery_data = device.read(characteristic).await?;
-                        battery = battery_data[0];
+                        battery = *battery_data.first().ok_or("Empty battery level")?;
                         trace!(logger, "Battery: {:?}", battery);
 
                         device.subscribe(characteristic).await?;
                     }
                 }
             }
+        }
 
-            if service.uuid == SERVICE_DATA {
-                for characteristic in &service.characteristics {
-                    if characteristic.uuid == CHARACTERISTIC_DATA {
-                        // HACK: first_data should REALLY be readable, dummy value for now
-                        first_data = if serial == 72 { vec![1; 25] } else { vec![0; 25] };
+        info.serial = match (serial, ours) {
+            (Some(serial), _) => serial,
+            (None, true) => 0,
+            (None, false) => device_info::fold_serial(None, &device.id()),
+        };
+        if info.model.is_empty() && !ours {
+            info.model = device.advertisement().await.ok().and_then(|advertisement| advertisement.local_name).unwrap_or_default();
+        }
 
-                        //first_data = device.read(characteristic).await?;
-                        trace!(logger, "First data: {:?}", first_data);
+        debug!(logger, "Device information read"; "info" => format!("{:?}", info), "battery" => battery);
+        Ok((info, battery))
+    }
 
-                        device.subscribe(characteristic).await?;
+    async fn read_optional(device: &dyn BlePeripheral, characteristic: &Characteristic, logger: &Logger) -> Option<Vec<u8>> {
+        match device.read(characteristic).await {
+            Ok(data) => Some(data),
+            Err(e) => {
+                warn!(logger, "Failed to read optional characteristic"; "uuid" => characteristic.uuid.to_string(), "error" => e.to_string());
+                None
+            }
+        }
+    }
+
+    /// Subscribes to a characteristic of the data service answering writes with notifications,
+    /// e.g. the control characteristic, if the device has it
+    async fn subscribe_optional(
+        device: &dyn BlePeripheral,
+        profile: &SensorProfile,
+        characteristic_uuid: Uuid,
+        logger: &Logger
+    ) -> BleResult<Option<Characteristic>> {
+        let Some(characteristic) = Ble::find_characteristic(device, profile.data_service, characteristic_uuid) else {
+            return Ok(None);
+        };
+        device.subscribe(&characteristic).await?;
+        debug!(logger, "Subscribed to optional characteristic"; "uuid" => characteristic_uuid.to_string());
+        Ok(Some(characteristic))
+    }
+
+    /// Finds the profile's data characteristic, subscribing to it unless the device starts paused,
+    /// and reads the channel layout if the device has one
+    async fn subscribe_data(
+        device: &dyn BlePeripheral,
+        profile: &SensorProfile,
+        subscribe: bool,
+        logger: &Logger
+    ) -> BleResult<(Option<ChannelLayout>, Characteristic)> {
+        let mut layout = None;
+        let mut data_characteristic = None;
+
+        for service in device.services() {
+            if service.uuid == profile.data_service {
+                for characteristic in &service.characteristics {
+                    if characteristic.uuid == CHARACTERISTIC_CHANNEL_LAYOUT {
+                        let layout_data = device.read(characteristic).await?;
+                        layout = ChannelLayout::parse(&layout_data).map_err(|e| {
+                            warn!(logger, "Invalid channel layout"; "error" => e.to_string());
+                        }).ok();
+                        trace!(logger, "Channel layout: {:?}", layout);
+                    }
+                    if characteristic.uuid == profile.data_characteristic {
+                        if subscribe {
+                            device.subscribe(characteristic).await?;
+                        }
+                        data_characteristic = Some(characteristic.clone());
                     }
                 }
             }
         }
 
-        debug!(logger, "Device information read"; "serial" => serial, "model" => model.clone(), "battery" => battery, "first_data" => format!("{:?}", first_data.iter().map(|x| format!("{:02x} ", x)).collect::<String>()));
-        Ok((serial, model, battery, first_data))
-    }
-
-    fn temp_create_channels(id: String, first_data: &Vec<u8>, logger: &Logger) -> Result<(Vec<Channel>, DatapointDecoder), Box<dyn Error>> {
-        if first_data.len() != 25 {
-            error!(logger, "Invalid first data length"; "length" => first_data.len());
-            return Err("Invalid first data length".into());
-        }
-
-        // we differentiate ECG from non-ECG devices by the initial ECG data value
-        let has_ecg = first_data[1] == 0 && first_data[2] == 0 && first_data[9] == 0 && first_data[10] == 0 && first_data[17] == 0 && first_data[18] == 0;
-
-        return if has_ecg {
-            let channels = vec![
-                Channel {
-                    id: format!("{}-0", id),
-                    name: "ECG".to_string(),
-                    channel_type: ChannelType::ECG,
-                    signal_quality: None,
-                },
-                Channel {
-                    id: format!("{}-1", id),
-                    name: "PPG green".to_string(),
-                    channel_type: ChannelType::PPG,
-                    signal_quality: None,
-                },
-                Channel {
-                    id: format!("{}-2", id),
-                    name: "PPG red".to_string(),
-                    channel_type: ChannelType::PPG,
-                    signal_quality: None,
-                },
-                Channel {
-                    id: format!("{}-3", id),
-                    name: "PPG IR".to_string(),
-                    channel_type: ChannelType::PPG,
-                    signal_quality: None,
-                },
-            ];
-
-            let decoder = Box::new(move |value: Vec<u8>| {
-                let mut data_points = HashMap::new();
-
-                let mut ecg = vec![];
-                ecg.push(i16::from_le_bytes([value[1], value[2]]) as i32);
-                ecg.push(i16::from_le_bytes([value[9], value[10]]) as i32);
-                ecg.push(i16::from_le_bytes([value[17], value[18]]) as i32);
-                data_points.insert(format!("{}-0", id), ecg);
-
-                let mut ppg_green = vec![];
-                ppg_green.push(u16::from_le_bytes([value[3], value[4]]) as i32);
-                ppg_green.push(u16::from_le_bytes([value[11], value[12]]) as i32);
-                ppg_green.push(u16::from_le_bytes([value[19], value[20]]) as i32);
-                data_points.insert(format!("{}-1", id), ppg_green);
-
-                let mut ppg_red = vec![];
-                ppg_red.push(u16::from_le_bytes([value[5], value[6]]) as i32);
-                ppg_red.push(u16::from_le_bytes([value[13], value[14]]) as i32);
-                ppg_red.push(u16::from_le_bytes([value[21], value[22]]) as i32);
-                data_points.insert(format!("{}-2", id), ppg_red);
-
-                let mut ppg_ir = vec![];
-                ppg_ir.push(u16::from_le_bytes([value[7], value[8]]) as i32);
-                ppg_ir.push(u16::from_le_bytes([value[15], value[16]]) as i32);
-                ppg_ir.push(u16::from_le_bytes([value[23], value[24]]) as i32);
-                data_points.insert(format!("{}-3", id), ppg_ir);
-
-                data_points
-            });
-
-            Ok((channels, decoder))
-        } else {
-            let channels = vec![
-                Channel {
-                    id: format!("{}-0", id),
-                    name: "PPG green".to_string(),
-                    channel_type: ChannelType::PPG,
-                    signal_quality: None,
-                },
-                Channel {
-                    id: format!("{}-1", id),
-                    name: "PPG red".to_string(),
-                    channel_type: ChannelType::PPG,
-                    signal_quality: None,
-                },
-                Channel {
-                    id: format!("{}-2", id),
-                    name: "PPG IR".to_string(),
-                    channel_type: ChannelType::PPG,
-                    signal_quality: None,
-                },
-            ];
-
-            let decoder = Box::new(move |value: Vec<u8>| {
-                let mut data_points = HashMap::new();
-
-                let mut ppg_grn = vec![];
-                ppg_grn.push(u16::from_le_bytes([value[3], value[4]]) as i32);
-                ppg_grn.push(u16::from_le_bytes([value[11], value[12]]) as i32);
-                ppg_grn.push(u16::from_le_bytes([value[19], value[20]]) as i32);
-                data_points.insert(format!("{}-0", id), ppg_grn);
-
-                let mut ppg_red = vec![];
-                ppg_red.push(u16::from_le_bytes([value[5], value[6]]) as i32);
-                ppg_red.push(u16::from_le_bytes([value[13], value[14]]) as i32);
-                ppg_red.push(u16::from_le_bytes([value[21], value[22]]) as i32);
-                data_points.insert(format!("{}-1", id), ppg_red);
-
-                let mut ppg_ir = vec![];
-                ppg_ir.push(u16::from_le_bytes([value[7], value[8]]) as i32);
-                ppg_ir.push(u16::from_le_bytes([value[15], value[16]]) as i32);
-                ppg_ir.push(u16::from_le_bytes([value[23], value[24]]) as i32);
-                data_points.insert(format!("{}-2", id), ppg_ir);
-
-                data_points
-            });
-
-            Ok((channels, decoder))
-        };
+        let data_characteristic = data_characteristic.ok_or("Data characteristic not found")?;
+
+        debug!(logger, "Data characteristic found"; "profile" => profile.name.clone(), "subscribed" => subscribe, "layout" => format!("{:?}", layout));
+        Ok((layout, data_characteristic))
     }
 }
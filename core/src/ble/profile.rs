@@ -0,0 +1,266 @@
+use serde::Deserialize;
+use uuid::Uuid;
+use super::channel_layout::{ChannelField, ChannelLayout};
+use super::transport::BleResult;
+use crate::ChannelType;
+
+const BUILTIN_PROFILES: [&str; 3] = [
+    include_str!("profiles/vitalvision.toml"),
+    include_str!("profiles/heart_rate.toml"),
+    include_str!("profiles/pulse_oximeter.toml"),
+];
+
+/// Describes a wearable model: how to find it, where its data comes from and,
+/// optionally, how its data packets are laid out.
+///
+/// ```toml
+/// name = "Example Band"
+/// model = "EXB"                     # substring of the Device Information model number
+/// protocol = "packets"              # default, "heart_rate" or "pulse_oximeter"
+/// scan_services = ["..."]
+/// data_service = "..."
+/// data_characteristic = "..."
+///
+/// [layout]                          # optional, see below
+/// packet_length = 12
+/// samples_per_packet = 2
+/// sampling_rate = 50.0
+///
+/// [[layout.fields]]
+/// name = "PPG green"
+/// type = "PPG"                      # CNT, ECG, PPG, HR, RR, SPO2 or STATUS
+/// format = "u24"                    # u8, i8, u16, i16, u24, i24, u32 or i32
+/// endianness = "big"                # default "little"
+/// offset = 0
+/// stride = 6                        # bytes between consecutive samples
+/// ```
+///
+/// Without a layout, the channel layout characteristic or the legacy layouts are used.
+/// Heart rate and pulse oximeter profiles take no layout, their measurements describe themselves.
+#[derive(Debug, PartialEq, Clone)]
+pub struct SensorProfile {
+    pub name: String,
+    pub model: Option<String>,
+    pub protocol: DataProtocol,
+    pub scan_services: Vec<Uuid>,
+    pub data_service: Uuid,
+    pub data_characteristic: Uuid,
+    pub layout: Option<ChannelLayout>,
+}
+
+/// How the notifications of the data characteristic are encoded
+#[derive(Debug, PartialEq, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DataProtocol {
+    /// packets of samples in a channel layout
+    #[default]
+    Packets,
+    /// the Heart Rate Measurement of the Bluetooth Heart Rate Service
+    HeartRate,
+    /// the PLX Continuous Measurement of the Bluetooth Pulse Oximeter Service, spot-checks
+    /// are received too if the device has them
+    PulseOximeter,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileFile {
+    name: String,
+    model: Option<String>,
+    #[serde(default)]
+    protocol: DataProtocol,
+    scan_services: Vec<Uuid>,
+    data_service: Uuid,
+    data_characteristic: Uuid,
+    layout: Option<LayoutTable>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LayoutTable {
+    packet_length: usize,
+    samples_per_packet: usize,
+    sampling_rate: f64,
+    fields: Vec<FieldTable>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FieldTable {
+    name: String,
+    #[serde(rename = "type")]
+    channel_type: ChannelType,
+    format: String,
+    #[serde(default)]
+    endianness: Endianness,
+    offset: usize,
+    #[serde(default)]
+    stride: usize,
+}
+
+#[derive(Deserialize, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Endianness {
+    #[default]
+    Little,
+    Big,
+}
+
+impl FieldTable {
+    fn into_field(self) -> BleResult<ChannelField> {
+        let (signed, bits) = if let Some(bits) = self.format.strip_prefix('i') {
+            (true, bits)
+        } else if let Some(bits) = self.format.strip_prefix('u') {
+            (false, bits)
+        } else {
+            return Err(format!("Invalid sample format {}", self.format).into());
+        };
+        let width = match bits {
+            "8" => 1,
+            "16" => 2,
+            "24" => 3,
+            "32" => 4,
+            _ => return Err(format!("Invalid sample format {}", self.format).into()),
+        };
+
+        Ok(ChannelField {
+            name: self.name,
+            channel_type: self.channel_type,
+            width,
+            signed,
+            big_endian: self.endianness == Endianness::Big,
+            offset: self.offset,
+            stride: self.stride,
+        })
+    }
+}
+
+impl SensorProfile {
+    pub fn from_toml(source: &str) -> BleResult<Self> {
+        let file: ProfileFile = toml::from_str(source)?;
+        if file.protocol != DataProtocol::Packets && file.layout.is_some() {
+            return Err(format!("Profile {} has a layout but its protocol is {:?}", file.name, file.protocol).into());
+        }
+
+        let layout = match file.layout {
+            Some(table) => {
+                let fields = table.fields.into_iter()
+                    .map(FieldTable::into_field)
+                    .collect::<BleResult<Vec<_>>>()?;
+                let layout = ChannelLayout {
+                    packet_length: table.packet_length,
+                    samples_per_packet: table.samples_per_packet,
+                    sampling_rate: table.sampling_rate,
+                    fields,
+                };
+                layout.validate()?;
+                Some(layout)
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            name: file.name,
+            model: file.model,
+            protocol: file.protocol,
+            scan_services: file.scan_services,
+            data_service: file.data_service,
+            data_characteristic: file.data_characteristic,
+            layout,
+        })
+    }
+
+    pub fn builtin() -> Vec<Self> {
+        BUILTIN_PROFILES.iter()
+            .map(|source| Self::from_toml(source).expect("builtin profiles are valid"))
+            .collect()
+    }
+
+    /// Picks the first profile whose model matches the model number, otherwise the
+    /// first one whose services the peripheral offers
+    pub fn select<'a>(profiles: &'a [SensorProfile], model: &str, services: &[Uuid]) -> Option<&'a SensorProfile> {
+        profiles.iter()
+            .find(|p| p.model.as_ref().is_some_and(|m| model.contains(m.as_str())))
+            .or_else(|| profiles.iter().find(|p| {
+                services.contains(&p.data_service) || p.scan_services.iter().any(|s| services.contains(s))
+            }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble::{CHARACTERISTIC_DATA, SERVICE_DATA, SERVICE_HEART_RATE, SERVICE_PULSE_OXIMETER};
+
+    const BAND: &str = r#"
+        name = "Example Band"
+        model = "EXB"
+        scan_services = ["0000FEE0-0000-1000-8000-00805F9B34FB"]
+        data_service = "0000FEE0-0000-1000-8000-00805F9B34FB"
+        data_characteristic = "0000FEE1-0000-1000-8000-00805F9B34FB"
+
+        [layout]
+        packet_length = 13
+        samples_per_packet = 2
+        sampling_rate = 50.0
+
+        [[layout.fields]]
+        name = "Counter"
+        type = "CNT"
+        format = "u8"
+        offset = 0
+
+        [[layout.fields]]
+        name = "PPG green"
+        type = "PPG"
+        format = "u24"
+        endianness = "big"
+        offset = 1
+        stride = 6
+    "#;
+
+    #[test]
+    fn parses_builtin_profile() {
+        let profiles = SensorProfile::builtin();
+        assert_eq!(profiles[0].data_service, SERVICE_DATA);
+        assert_eq!(profiles[0].data_characteristic, CHARACTERISTIC_DATA);
+        assert_eq!(profiles[0].layout, None);
+        assert_eq!(profiles[1].protocol, DataProtocol::HeartRate);
+        assert_eq!(profiles[2].protocol, DataProtocol::PulseOximeter);
+    }
+
+    #[test]
+    fn parses_layout() {
+        let profile = SensorProfile::from_toml(BAND).unwrap();
+        let layout = profile.layout.unwrap();
+
+        assert_eq!(profile.model, Some("EXB".to_string()));
+        assert_eq!(layout.sampling_rate, 50.0);
+        assert_eq!(layout.fields[1].width, 3);
+        assert!(layout.fields[1].big_endian);
+        assert!(!layout.fields[1].signed);
+    }
+
+    #[test]
+    fn rejects_invalid_profiles() {
+        assert!(SensorProfile::from_toml(&BAND.replace("u24", "f32")).is_err());
+        assert!(SensorProfile::from_toml(&BAND.replace("\"u24\"", "\"\"")).is_err());
+        assert!(SensorProfile::from_toml(&BAND.replace("u24", "ü24")).is_err());
+        assert!(SensorProfile::from_toml(&BAND.replace("stride = 6", "stride = 0")).unwrap_err().to_string().contains("stride"));
+        assert!(SensorProfile::from_toml(&BAND.replace("stride = 6", "stride = 12")).is_err());
+        assert!(SensorProfile::from_toml(&BAND.replace("model", "modell")).is_err());
+        assert!(SensorProfile::from_toml(&BAND.replace("[layout]", "protocol = \"heart_rate\"\n[layout]")).is_err());
+    }
+
+    #[test]
+    fn selects_by_model_then_services() {
+        let mut profiles = SensorProfile::builtin();
+        profiles.push(SensorProfile::from_toml(BAND).unwrap());
+
+        assert_eq!(SensorProfile::select(&profiles, "EXB-2 rev A", &[]).unwrap().name, "Example Band");
+        assert_eq!(SensorProfile::select(&profiles, "", &[SERVICE_DATA]).unwrap().name, "VitalVision Wearable");
+        assert_eq!(SensorProfile::select(&profiles, "H10", &[SERVICE_HEART_RATE]).unwrap().name, "Heart Rate Sensor");
+        assert_eq!(SensorProfile::select(&profiles, "", &[SERVICE_PULSE_OXIMETER]).unwrap().name, "Pulse Oximeter");
+        assert!(SensorProfile::select(&profiles, "Other", &[]).is_none());
+    }
+}
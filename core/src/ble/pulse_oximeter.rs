@@ -0,0 +1,183 @@
+use super::{DatapointDecoder, DecodedPacket};
+use crate::{Channel, ChannelType};
+
+const CONTINUOUS_FAST: u8 = 0x01;
+const CONTINUOUS_SLOW: u8 = 0x02;
+const CONTINUOUS_STATUS: u8 = 0x04;
+const SPOT_CHECK_TIMESTAMP: u8 = 0x01;
+const SPOT_CHECK_STATUS: u8 = 0x02;
+
+/// Measurement status bits after which the SpO2 and the pulse rate are not kept
+const STATUS_UNAVAILABLE: u16 = 1 << 13;
+const STATUS_INVALID: u16 = 1 << 15;
+
+/// A PLX Continuous (0x2A5F) or Spot-Check (0x2A5E) Measurement of the Pulse Oximeter Service
+#[derive(Debug, PartialEq, Clone)]
+pub struct PlxMeasurement {
+    /// in %, `None` for the special values of SFLOAT
+    pub spo2: Option<f64>,
+    /// in bpm, `None` for the special values of SFLOAT
+    pub pulse_rate: Option<f64>,
+    /// the measurement status flags, if the device sends them
+    pub status: Option<u16>,
+}
+
+/// Reads an IEEE 11073 16 bit float, a 4 bit exponent and a 12 bit mantissa, both signed.
+/// NaN, NRes, the infinities and the reserved value are `None`.
+fn sfloat(raw: u16) -> Option<f64> {
+    if (0x07FE..=0x0802).contains(&raw) {
+        return None;
+    }
+    let mantissa = ((raw << 4) as i16) >> 4;
+    let exponent = (raw as i16) >> 12;
+    Some(mantissa as f64 * 10f64.powi(exponent as i32))
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn skip(&mut self, len: usize) -> Result<(), String> {
+        self.0 = self.0.get(len..).ok_or("PLX measurement too short")?;
+        Ok(())
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.0.get(..2).ok_or("PLX measurement too short")?;
+        let value = u16::from_le_bytes([bytes[0], bytes[1]]);
+        self.skip(2)?;
+        Ok(value)
+    }
+}
+
+impl PlxMeasurement {
+    /// Parses a continuous measurement, keeping the normal SpO2 and pulse rate and skipping
+    /// the fast and slow ones. Fields after the status are ignored.
+    pub fn parse_continuous(data: &[u8]) -> Result<Self, String> {
+        let (&flags, rest) = data.split_first().ok_or("Empty PLX measurement")?;
+        let mut reader = Reader(rest);
+        let spo2 = sfloat(reader.u16()?);
+        let pulse_rate = sfloat(reader.u16()?);
+        if flags & CONTINUOUS_FAST != 0 {
+            reader.skip(4)?;
+        }
+        if flags & CONTINUOUS_SLOW != 0 {
+            reader.skip(4)?;
+        }
+        let status = match flags & CONTINUOUS_STATUS != 0 {
+            true => Some(reader.u16()?),
+            false => None,
+        };
+        Ok(Self { spo2, pulse_rate, status })
+    }
+
+    /// Parses a spot-check measurement. Its timestamp is skipped, the time the device keeps is
+    /// not synced.
+    pub fn parse_spot_check(data: &[u8]) -> Result<Self, String> {
+        let (&flags, rest) = data.split_first().ok_or("Empty PLX measurement")?;
+        let mut reader = Reader(rest);
+        let spo2 = sfloat(reader.u16()?);
+        let pulse_rate = sfloat(reader.u16()?);
+        if flags & SPOT_CHECK_TIMESTAMP != 0 {
+            reader.skip(7)?;
+        }
+        let status = match flags & SPOT_CHECK_STATUS != 0 {
+            true => Some(reader.u16()?),
+            false => None,
+        };
+        Ok(Self { spo2, pulse_rate, status })
+    }
+
+    /// Whether the device flagged the SpO2 and the pulse rate as unavailable or invalid
+    pub fn is_invalid(&self) -> bool {
+        self.status.is_some_and(|status| status & (STATUS_UNAVAILABLE | STATUS_INVALID) != 0)
+    }
+}
+
+/// Ids of the SpO2, the pulse rate and the status channel of a device
+fn channel_ids(device_id: &str) -> [String; 3] {
+    [0, 1, 2].map(|n| format!("{}-{}", device_id, n))
+}
+
+pub fn channels(device_id: &str) -> Vec<Channel> {
+    let names = [("SpO2", ChannelType::SPO2), ("Pulse rate", ChannelType::HR), ("Measurement status", ChannelType::STATUS)];
+    channel_ids(device_id).into_iter().zip(names)
+        .map(|(id, (name, channel_type))| Channel {
+            id,
+            name: name.to_string(),
+            channel_type,
+            signal_quality: None,
+            samples_received: 0,
+            samples_lost: 0,
+        })
+        .collect()
+}
+
+/// One sample per channel, the SpO2 and the pulse rate rounded to whole % and bpm. Both are
+/// `None` if the device flagged them as unavailable or invalid, the status keeps the flags.
+fn packet(device_id: &str, measurement: Result<PlxMeasurement, String>) -> DecodedPacket {
+    let mut decoded = DecodedPacket::default();
+    let Ok(measurement) = measurement else {
+        return decoded;
+    };
+
+    let valid = |value: Option<f64>| value.filter(|_| !measurement.is_invalid()).map(|value| value.round() as i32);
+    let [spo2_id, pulse_rate_id, status_id] = channel_ids(device_id);
+    decoded.samples.insert(spo2_id, vec![valid(measurement.spo2)]);
+    decoded.samples.insert(pulse_rate_id, vec![valid(measurement.pulse_rate)]);
+    decoded.samples.insert(status_id, vec![measurement.status.map(i32::from)]);
+    decoded
+}
+
+/// Builds a decoder for continuous measurements. Malformed measurements decode to nothing.
+pub fn decoder(device_id: &str) -> DatapointDecoder {
+    let device_id = device_id.to_string();
+    Box::new(move |value: Vec<u8>| packet(&device_id, PlxMeasurement::parse_continuous(&value)))
+}
+
+/// Decodes a spot-check measurement into the channels of the continuous ones
+pub fn decode_spot_check(device_id: &str, value: &[u8]) -> DecodedPacket {
+    packet(device_id, PlxMeasurement::parse_spot_check(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sfloats_and_measurements() {
+        assert_eq!(sfloat(0x0062), Some(98.0));
+        // 975 * 10^-1
+        assert_eq!(sfloat(0xF3CF), Some(97.5));
+        // -1 * 10^1
+        assert_eq!(sfloat(0x1FFF), Some(-10.0));
+        assert_eq!(sfloat(0x07FF), None);
+        assert_eq!(sfloat(0x0800), None);
+
+        // fast values and status
+        let measurement = PlxMeasurement::parse_continuous(&[0x05, 0x62, 0x00, 0x48, 0x00, 0x61, 0x00, 0x49, 0x00, 0x20, 0x00]).unwrap();
+        assert_eq!(measurement, PlxMeasurement { spo2: Some(98.0), pulse_rate: Some(72.0), status: Some(0x0020) });
+        assert!(!measurement.is_invalid());
+
+        // timestamp and an invalid measurement
+        let measurement = PlxMeasurement::parse_spot_check(&[0x03, 0xFF, 0x07, 0x48, 0x00, 0xE8, 0x07, 1, 1, 0, 0, 0, 0x00, 0x80]).unwrap();
+        assert_eq!(measurement, PlxMeasurement { spo2: None, pulse_rate: Some(72.0), status: Some(0x8000) });
+        assert!(measurement.is_invalid());
+
+        assert!(PlxMeasurement::parse_continuous(&[]).is_err());
+        assert!(PlxMeasurement::parse_continuous(&[0x04, 0x62, 0x00, 0x48, 0x00]).is_err());
+    }
+
+    #[test]
+    fn decodes_measurements() {
+        let mut decoder = decoder("oximeter");
+        let decoded = decoder(vec![0x04, 0xCF, 0xF3, 0x48, 0x00, 0x80, 0x00]);
+        assert_eq!(decoded.samples["oximeter-0"], vec![Some(98)]);
+        assert_eq!(decoded.samples["oximeter-1"], vec![Some(72)]);
+        assert_eq!(decoded.samples["oximeter-2"], vec![Some(0x80)]);
+
+        let decoded = decode_spot_check("oximeter", &[0x02, 0x62, 0x00, 0x48, 0x00, 0x00, 0x20]);
+        assert_eq!(decoded.samples["oximeter-0"], vec![None]);
+        assert_eq!(decoded.samples["oximeter-2"], vec![Some(0x2000)]);
+        assert!(decoder(vec![0x00]).samples.is_empty());
+    }
+}
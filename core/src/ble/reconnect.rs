@@ -0,0 +1,40 @@
+use std::time::Duration;
+
+/// Exponential backoff between reconnect attempts of a dropped device
+#[derive(Debug, PartialEq, Clone)]
+pub struct ReconnectPolicy {
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    pub max_attempts: u32,
+}
+
+impl ReconnectPolicy {
+    pub fn new(initial_delay_ms: u32, max_delay_ms: u32, max_attempts: u32) -> Self {
+        Self {
+            initial_delay: Duration::from_millis(initial_delay_ms as u64),
+            max_delay: Duration::from_millis(max_delay_ms as u64),
+            max_attempts,
+        }
+    }
+
+    /// Delay before the given attempt, starting at 1: doubles every attempt, capped at `max_delay`
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial_delay.saturating_mul(factor).min(self.max_delay)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doubles_delay_up_to_max() {
+        let policy = ReconnectPolicy::new(500, 3000, 10);
+        assert_eq!(policy.delay(1), Duration::from_millis(500));
+        assert_eq!(policy.delay(2), Duration::from_millis(1000));
+        assert_eq!(policy.delay(3), Duration::from_millis(2000));
+        assert_eq!(policy.delay(4), Duration::from_millis(3000));
+        assert_eq!(policy.delay(40), Duration::from_millis(3000));
+    }
+}
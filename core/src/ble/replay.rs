@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use async_trait::async_trait;
+use btleplug::api::CharPropFlags;
+use slog::{debug, Logger};
+use tokio::time::Instant;
+use uuid::Uuid;
+use super::SERVICE_TIME;
+use super::capture::CaptureRecord;
+use super::fake::{FakePeripheral, FakeTransport};
+use super::transport::*;
+
+/// How long the playback waits for the devices to subscribe before it starts anyway
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Plays a session file back as virtual devices, so a captured session runs through the same
+/// decoding, storage and analysis as it did live.
+///
+/// Each captured device becomes a `FakePeripheral` with its GATT table, answering reads with
+/// the values read during the capture. The time service is left out, the captured clock reads
+/// would sync the virtual devices to the past. Once every device subscribed to its data, the
+/// notifications and disconnects are played at their captured pace, divided by `speed`.
+/// Samples are timestamped when they are played, not when they were captured.
+pub struct ReplayTransport {
+    fake: Arc<FakeTransport>,
+    peripherals: HashMap<String, Arc<FakePeripheral>>,
+    /// notifications and disconnects, until the playback takes them
+    timeline: Mutex<Option<Vec<CaptureRecord>>>,
+    speed: f64,
+    logger: Logger,
+}
+
+impl ReplayTransport {
+    pub fn open(path: &str, speed: f64, logger: Logger) -> BleResult<Arc<Self>> {
+        let source = std::fs::read_to_string(path).map_err(|e| format!("Failed to read session file {}: {}", path, e))?;
+        Self::parse(&source, speed, logger)
+    }
+
+    pub fn parse(source: &str, speed: f64, logger: Logger) -> BleResult<Arc<Self>> {
+        if !(speed.is_finite() && speed > 0.0) {
+            return Err(format!("Invalid replay speed {}", speed).into());
+        }
+
+        let mut devices = HashMap::new();
+        let mut reads: HashMap<(String, Uuid), Vec<u8>> = HashMap::new();
+        let mut timeline = vec![];
+        for (n, line) in source.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            let record: CaptureRecord = serde_json::from_str(line).map_err(|e| format!("Invalid session file line {}: {}", n + 1, e))?;
+            match &record {
+                CaptureRecord::Device { device, local_name, services, .. } => {
+                    devices.insert(device.clone(), (local_name.clone(), services.clone()));
+                }
+                // the first read is the one the session started with
+                CaptureRecord::Read { device, characteristic, value, .. } => {
+                    reads.entry((device.clone(), *characteristic)).or_insert_with(|| value.clone());
+                }
+                CaptureRecord::Notification { .. } | CaptureRecord::Disconnected { .. } => timeline.push(record),
+            }
+        }
+        timeline.sort_by_key(|record| record.time_us());
+
+        let fake = FakeTransport::new();
+        let mut peripherals = HashMap::new();
+        for (id, (local_name, services)) in devices {
+            let mut builder = FakePeripheral::builder(&id);
+            if let Some(local_name) = local_name {
+                builder = builder.local_name(&local_name);
+            }
+            for service in services.iter().filter(|service| service.uuid != SERVICE_TIME) {
+                // advertising every service lets the scan filter of any profile match
+                builder = builder.advertise(service.uuid);
+                for characteristic in service.characteristics.iter() {
+                    let value = reads.get(&(id.clone(), characteristic.uuid)).cloned().unwrap_or_default();
+                    builder = builder.characteristic(service.uuid, characteristic.uuid, CharPropFlags::from_bits_truncate(characteristic.properties), value);
+                }
+            }
+            let peripheral = builder.build();
+            fake.add_peripheral(peripheral.clone());
+            peripherals.insert(id, peripheral);
+        }
+
+        Ok(Arc::new(Self { fake, peripherals, timeline: Mutex::new(Some(timeline)), speed, logger }))
+    }
+
+    async fn play(peripherals: HashMap<String, Arc<FakePeripheral>>, timeline: Vec<CaptureRecord>, speed: f64, logger: Logger) {
+        // the first notification of each device tells what it has to subscribe to
+        let mut first_notifications: HashMap<&str, Uuid> = HashMap::new();
+        for record in timeline.iter() {
+            if let CaptureRecord::Notification { device, characteristic, .. } = record {
+                first_notifications.entry(device.as_str()).or_insert(*characteristic);
+            }
+        }
+        let subscribed = || first_notifications.iter().all(|(device, characteristic)| {
+            peripherals.get(*device).is_some_and(|peripheral| peripheral.is_subscribed(*characteristic))
+        });
+        let waiting_since = Instant::now();
+        while !subscribed() && waiting_since.elapsed() < START_TIMEOUT {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let Some(first_us) = timeline.iter().find(|record| matches!(record, CaptureRecord::Notification { .. })).map(CaptureRecord::time_us) else {
+            debug!(logger, "Session file has no notifications");
+            return;
+        };
+        debug!(logger, "Replaying session"; "records" => timeline.len(), "speed" => speed);
+        let start = Instant::now();
+        for record in timeline {
+            let offset_us = (record.time_us() - first_us).max(0) as f64 / speed;
+            tokio::time::sleep_until(start + Duration::from_micros(offset_us as u64)).await;
+            match record {
+                CaptureRecord::Notification { device, characteristic, value, .. } => {
+                    if let Some(peripheral) = peripherals.get(&device) {
+                        peripheral.notify(characteristic, value);
+                    }
+                }
+                CaptureRecord::Disconnected { device, .. } => {
+                    if let Some(peripheral) = peripherals.get(&device) {
+                        peripheral.disconnect_remote();
+                    }
+                }
+                _ => {}
+            }
+        }
+        debug!(logger, "Replay finished");
+    }
+}
+
+#[async_trait]
+impl BleTransport for ReplayTransport {
+    async fn events(&self) -> BleResult<TransportEventStream> {
+        self.fake.events().await
+    }
+
+    /// Starts the playback with the first scan
+    async fn start_scan(&self, services: Vec<Uuid>) -> BleResult<()> {
+        self.fake.start_scan(services).await?;
+        if let Some(timeline) = self.timeline.lock().unwrap().take() {
+            tokio::spawn(Self::play(self.peripherals.clone(), timeline, self.speed, self.logger.clone()));
+        }
+        Ok(())
+    }
+
+    async fn stop_scan(&self) -> BleResult<()> {
+        self.fake.stop_scan().await
+    }
+
+    async fn peripherals(&self) -> BleResult<Vec<Arc<dyn BlePeripheral>>> {
+        self.fake.peripherals().await
+    }
+
+    async fn peripheral(&self, id: &str) -> BleResult<Arc<dyn BlePeripheral>> {
+        self.fake.peripheral(id).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ble::{CHARACTERISTIC_DATA, CHARACTERISTIC_SERIAL, SERVICE_DATA, SERVICE_DEVICE_INFO};
+
+    #[test]
+    fn rebuilds_devices_from_the_session_file() {
+        let source = format!(
+            "{}\n{}\n{}\n\n{}\n",
+            format_args!(r#"{{"type":"device","time_us":0,"device":"dev","local_name":"Band","services":[{{"uuid":"{}","characteristics":[{{"uuid":"{}","properties":2}}]}},{{"uuid":"{}","characteristics":[{{"uuid":"{}","properties":16}}]}},{{"uuid":"{}","characteristics":[]}}]}}"#,
+                SERVICE_DEVICE_INFO, CHARACTERISTIC_SERIAL, SERVICE_DATA, CHARACTERISTIC_DATA, SERVICE_TIME),
+            format_args!(r#"{{"type":"read","time_us":1,"device":"dev","characteristic":"{}","value":"3731"}}"#, CHARACTERISTIC_SERIAL),
+            format_args!(r#"{{"type":"read","time_us":2,"device":"dev","characteristic":"{}","value":"3732"}}"#, CHARACTERISTIC_SERIAL),
+            format_args!(r#"{{"type":"notification","time_us":3,"device":"dev","characteristic":"{}","value":"00"}}"#, CHARACTERISTIC_DATA),
+        );
+        let logger = Logger::root(slog::Discard, slog::o!());
+        let replay = ReplayTransport::parse(&source, 10.0, logger.clone()).unwrap();
+
+        let peripheral = &replay.peripherals["dev"];
+        let services: Vec<Uuid> = peripheral.services().iter().map(|service| service.uuid).collect();
+        assert_eq!(services, vec![SERVICE_DEVICE_INFO, SERVICE_DATA]);
+        assert_eq!(peripheral.value(CHARACTERISTIC_SERIAL), Some(b"71".to_vec()));
+        assert_eq!(replay.timeline.lock().unwrap().as_ref().unwrap().len(), 1);
+
+        assert!(ReplayTransport::parse(&source, 0.0, logger.clone()).is_err());
+        assert!(ReplayTransport::parse("{}", 1.0, logger).err().unwrap().to_string().contains("line 1"));
+    }
+}
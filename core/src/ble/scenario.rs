@@ -0,0 +1,276 @@
+use std::collections::HashSet;
+use serde::Deserialize;
+use super::transport::BleResult;
+use crate::synth;
+
+/// A scripted mock session: the mock devices and what happens to them when. Times are seconds
+/// of the scenario clock, which runs with the samples the devices send, so a scenario plays
+/// out the same on every run.
+///
+/// ```toml
+/// name = "Low battery"
+/// sampling_rate = 8.0               # default 32, the legacy rate
+///
+/// [[devices]]                       # optional, devices 1 and 2 otherwise
+/// serial = 1                        # the device id is vitalvision-mock-<serial>
+/// name = "Device 1"                 # default "Device <serial>"
+/// battery = 80                      # default 100
+/// rssi = -70                        # default -60
+/// clock_offset_ms = 20              # default 0
+/// clock_skew_ppm = 15.0             # default 0
+/// signal = { heart_rate_bpm = 72.0 } # changes to the default `synth::Parameters`
+///
+/// [[events]]
+/// at_sec = 30.0
+/// device = 2
+/// action = "disconnect"             # the link drops, the core reconnects
+///
+/// [[events]]
+/// at_sec = 90.0
+/// device = 1
+/// action = "signal"
+/// perfusion = 0.2
+/// motion_artifacts_per_min = 6.0
+/// ```
+///
+/// The other actions are `out_of_range` and `in_range`, after which connection attempts fail
+/// and succeed again, `battery` with `level`, `rssi` with `dbm` and `clock_jump` with `ms`.
+///
+/// The mock devices stream the legacy layout. At another `sampling_rate` they describe it in a
+/// channel layout characteristic, like firmware sampling at other rates does, so a scenario can
+/// cover those devices, and a long one can run on fewer samples.
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    pub name: String,
+    pub sampling_rate: Option<f64>,
+    #[serde(default)]
+    pub devices: Vec<ScenarioDevice>,
+    #[serde(default)]
+    pub events: Vec<ScenarioEvent>,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScenarioDevice {
+    pub serial: u16,
+    pub name: Option<String>,
+    #[serde(default = "default_battery")]
+    pub battery: u8,
+    #[serde(default = "default_rssi")]
+    pub rssi: i16,
+    #[serde(default)]
+    pub clock_offset_ms: i64,
+    #[serde(default)]
+    pub clock_skew_ppm: f64,
+    #[serde(default)]
+    pub signal: SignalChanges,
+}
+
+fn default_battery() -> u8 {
+    100
+}
+
+fn default_rssi() -> i16 {
+    -60
+}
+
+impl ScenarioDevice {
+    pub fn new(serial: u16) -> Self {
+        Self {
+            serial,
+            name: None,
+            battery: default_battery(),
+            rssi: default_rssi(),
+            clock_offset_ms: 0,
+            clock_skew_ppm: 0.0,
+            signal: SignalChanges::default(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+pub struct ScenarioEvent {
+    pub at_sec: f64,
+    /// serial of the device
+    pub device: u16,
+    #[serde(flatten)]
+    pub action: ScenarioAction,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum ScenarioAction {
+    Disconnect,
+    OutOfRange,
+    InRange,
+    Battery { level: u8 },
+    Rssi { dbm: i16 },
+    ClockJump { ms: i64 },
+    Signal(Box<SignalChanges>),
+}
+
+/// Changes to the parameters of the synthetic signals of a device, see `synth::Parameters`
+#[derive(Debug, PartialEq, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SignalChanges {
+    pub heart_rate_bpm: Option<f64>,
+    pub hrv_ms: Option<f64>,
+    pub respiration_rate_bpm: Option<f64>,
+    pub respiration_modulation: Option<f64>,
+    pub pulse_transit_ms: Option<f64>,
+    pub perfusion: Option<f64>,
+    pub baseline_wander: Option<f64>,
+    pub powerline_hz: Option<f64>,
+    pub powerline_noise: Option<f64>,
+    pub noise: Option<f64>,
+    pub motion_artifacts_per_min: Option<f64>,
+    pub motion_artifact: Option<f64>,
+    pub motion_artifact_ms: Option<f64>,
+    pub clipping: Option<f64>,
+    pub dropouts_per_min: Option<f64>,
+    pub dropout_ms: Option<f64>,
+    pub seed: Option<u64>,
+}
+
+impl SignalChanges {
+    pub fn apply(&self, parameters: &mut synth::Parameters) {
+        let changes = [
+            (self.heart_rate_bpm, &mut parameters.heart_rate_bpm),
+            (self.hrv_ms, &mut parameters.hrv_ms),
+            (self.respiration_rate_bpm, &mut parameters.respiration_rate_bpm),
+            (self.respiration_modulation, &mut parameters.respiration_modulation),
+            (self.pulse_transit_ms, &mut parameters.pulse_transit_ms),
+            (self.perfusion, &mut parameters.perfusion),
+            (self.baseline_wander, &mut parameters.baseline_wander),
+            (self.powerline_hz, &mut parameters.powerline_hz),
+            (self.powerline_noise, &mut parameters.powerline_noise),
+            (self.noise, &mut parameters.noise),
+            (self.motion_artifacts_per_min, &mut parameters.motion_artifacts_per_min),
+            (self.motion_artifact, &mut parameters.motion_artifact),
+            (self.motion_artifact_ms, &mut parameters.motion_artifact_ms),
+            (self.clipping, &mut parameters.clipping),
+            (self.dropouts_per_min, &mut parameters.dropouts_per_min),
+            (self.dropout_ms, &mut parameters.dropout_ms),
+        ];
+        for (change, parameter) in changes {
+            if let Some(value) = change {
+                *parameter = value;
+            }
+        }
+        if let Some(seed) = self.seed {
+            parameters.seed = seed;
+        }
+    }
+}
+
+impl Scenario {
+    pub fn open(path: &str) -> BleResult<Self> {
+        let source = std::fs::read_to_string(path).map_err(|e| format!("Failed to read scenario file {}: {}", path, e))?;
+        Self::from_toml(&source)
+    }
+
+    /// Parses a scenario, with the events in the order they happen
+    pub fn from_toml(source: &str) -> BleResult<Self> {
+        let mut scenario: Scenario = toml::from_str(source)?;
+        if scenario.devices.is_empty() {
+            scenario.devices = vec![ScenarioDevice::new(1), ScenarioDevice::new(2)];
+        }
+
+        if let Some(rate) = scenario.sampling_rate.filter(|rate| !(rate.is_finite() && *rate > 0.0)) {
+            return Err(format!("Scenario {} has a sampling rate of {} Hz", scenario.name, rate).into());
+        }
+
+        let mut serials = HashSet::new();
+        if let Some(device) = scenario.devices.iter().find(|device| !serials.insert(device.serial)) {
+            return Err(format!("Scenario {} has device {} twice", scenario.name, device.serial).into());
+        }
+        for event in scenario.events.iter() {
+            if !serials.contains(&event.device) {
+                return Err(format!("Scenario {} has an event for unknown device {}", scenario.name, event.device).into());
+            }
+            if !(event.at_sec.is_finite() && event.at_sec >= 0.0) {
+                return Err(format!("Scenario {} has an event at {} s", scenario.name, event.at_sec).into());
+            }
+        }
+        scenario.events.sort_by(|a, b| a.at_sec.total_cmp(&b.at_sec));
+        Ok(scenario)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCENARIO: &str = r#"
+        name = "Bad day"
+        sampling_rate = 8.0
+
+        [[devices]]
+        serial = 1
+        battery = 80
+        signal = { heart_rate_bpm = 72, seed = 3 }
+
+        [[devices]]
+        serial = 2
+
+        [[events]]
+        at_sec = 120
+        device = 1
+        action = "clock_jump"
+        ms = 500
+
+        [[events]]
+        at_sec = 30
+        device = 2
+        action = "disconnect"
+
+        [[events]]
+        at_sec = 60
+        device = 1
+        action = "battery"
+        level = 5
+
+        [[events]]
+        at_sec = 90
+        device = 1
+        action = "signal"
+        perfusion = 0.2
+        motion_artifacts_per_min = 6.0
+    "#;
+
+    #[test]
+    fn parses_scenarios() {
+        let scenario = Scenario::from_toml(SCENARIO).unwrap();
+        assert_eq!(scenario.sampling_rate, Some(8.0));
+        assert_eq!(scenario.devices[0].battery, 80);
+        assert_eq!(scenario.devices[1].rssi, -60);
+
+        let mut parameters = synth::Parameters::default();
+        scenario.devices[0].signal.apply(&mut parameters);
+        assert_eq!((parameters.heart_rate_bpm, parameters.seed, parameters.hrv_ms), (72.0, 3, 25.0));
+
+        let actions: Vec<(f64, u16, ScenarioAction)> = scenario.events.into_iter().map(|e| (e.at_sec, e.device, e.action)).collect();
+        assert_eq!(actions[..3], [
+            (30.0, 2, ScenarioAction::Disconnect),
+            (60.0, 1, ScenarioAction::Battery { level: 5 }),
+            (90.0, 1, ScenarioAction::Signal(Box::new(SignalChanges { perfusion: Some(0.2), motion_artifacts_per_min: Some(6.0), ..SignalChanges::default() }))),
+        ]);
+        assert_eq!(actions[3], (120.0, 1, ScenarioAction::ClockJump { ms: 500 }));
+
+        // the default devices
+        let scenario = Scenario::from_toml("name = \"Quiet\"").unwrap();
+        assert_eq!(scenario.devices.iter().map(|device| device.serial).collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn rejects_invalid_scenarios() {
+        let event = |event: &str| format!("name = \"Broken\"\n[[events]]\n{}", event);
+        assert!(Scenario::from_toml(&event("at_sec = 1\ndevice = 3\naction = \"disconnect\"")).unwrap_err().to_string().contains("unknown device 3"));
+        assert!(Scenario::from_toml(&event("at_sec = -1\ndevice = 1\naction = \"disconnect\"")).is_err());
+        assert!(Scenario::from_toml(&event("at_sec = 1\ndevice = 1\naction = \"explode\"")).is_err());
+        assert!(Scenario::from_toml(&event("at_sec = 1\ndevice = 1\naction = \"signal\"\nheart_rate = 70")).is_err());
+        assert!(Scenario::from_toml("name = \"Still\"\nsampling_rate = 0").unwrap_err().to_string().contains("sampling rate"));
+        assert!(Scenario::from_toml("name = \"Twins\"\n[[devices]]\nserial = 1\n[[devices]]\nserial = 1").unwrap_err().to_string().contains("twice"));
+    }
+}
@@ -0,0 +1,81 @@
+/// Packets a counter may step back by and still count as a late or repeated packet rather than
+/// a wrap after a long loss
+const REORDER_WINDOW: u64 = 32;
+
+/// Detects lost packets from the wrapping sequence counter a device puts into every packet.
+///
+/// Losing a multiple of the counter range in a row looks like no loss at all, with an 8 bit
+/// counter this takes 256 consecutive packets.
+pub struct SequenceTracker {
+    modulus: u64,
+    last: Option<u32>,
+}
+
+impl SequenceTracker {
+    /// Tracks a counter of `width` bytes
+    pub fn new(width: usize) -> Self {
+        Self {
+            modulus: 1 << (8 * width.min(4)),
+            last: None,
+        }
+    }
+
+    /// Returns how many packets went missing between the previous sequence number and this one,
+    /// or `None` for a packet to drop: a repeated one or one arriving after its successors, a
+    /// step back of up to `REORDER_WINDOW` packets or half the counter range.
+    pub fn track(&mut self, sequence: u32) -> Option<u32> {
+        let sequence = sequence as u64 % self.modulus;
+        let lost = match self.last {
+            Some(last) => {
+                let back = (last as u64 + self.modulus - sequence) % self.modulus;
+                if back < REORDER_WINDOW.min(self.modulus / 2) {
+                    return None;
+                }
+                (sequence + self.modulus - last as u64 - 1) % self.modulus
+            }
+            None => 0,
+        };
+        self.last = Some(sequence as u32);
+        Some(lost as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counts_missing_sequence_numbers() {
+        let mut tracker = SequenceTracker::new(1);
+        assert_eq!(tracker.track(10), Some(0));
+        assert_eq!(tracker.track(11), Some(0));
+        assert_eq!(tracker.track(14), Some(2));
+        assert_eq!(tracker.track(14), None);
+    }
+
+    #[test]
+    fn handles_wrapping_counters() {
+        let mut tracker = SequenceTracker::new(1);
+        tracker.track(254);
+        assert_eq!(tracker.track(1), Some(2));
+
+        let mut tracker = SequenceTracker::new(2);
+        tracker.track(65535);
+        assert_eq!(tracker.track(0), Some(0));
+    }
+
+    #[test]
+    fn drops_reordered_and_repeated_packets() {
+        let mut tracker = SequenceTracker::new(1);
+        tracker.track(254);
+        assert_eq!(tracker.track(253), None);
+        assert_eq!(tracker.track(254), None);
+        assert_eq!(tracker.track(255), Some(0));
+        assert_eq!(tracker.track(0), Some(0));
+        assert_eq!(tracker.track(255), None);
+        assert_eq!(tracker.track(1), Some(0));
+
+        // further back, the counter wrapped after a long loss
+        assert_eq!(tracker.track(200), Some(198));
+    }
+}
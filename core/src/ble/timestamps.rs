@@ -0,0 +1,92 @@
+/// Schedules lagging further behind the packet arrival start over, e.g. after a stall
+const MAX_LATENCY_US: f64 = 500_000.0;
+
+/// Assigns host timestamps to the samples of a data stream.
+///
+/// Samples are spaced at the nominal sampling rate of the device, corrected by the skew of its
+/// clock, and continue from the previous packet, lost samples included. The first packet anchors
+/// its last sample at its arrival. A sample cannot be taken after its packet arrived, so a
+/// schedule running late is pulled back gradually, by at most half the sample spacing per
+/// packet. Samples of a queued packet that would still come after its arrival are spread
+/// between the previous sample and the arrival instead. A schedule lagging more than
+/// `MAX_LATENCY_US` behind the arrival starts over.
+pub struct SampleClock {
+    period_us: f64,
+    /// timestamp of the previous sample
+    last_us: Option<f64>,
+}
+
+impl SampleClock {
+    pub fn new(sampling_rate: f64) -> Self {
+        Self {
+            period_us: 1e6 / sampling_rate,
+            last_us: None,
+        }
+    }
+
+    /// Timestamps in us since the epoch for `count` samples of a packet that arrived at `arrival_us`
+    pub fn timestamps(&mut self, arrival_us: i64, count: usize, skew_ppm: f64) -> Vec<i64> {
+        if count == 0 {
+            return vec![];
+        }
+
+        // a fast device clock samples faster in host time
+        let period = self.period_us / (1.0 + skew_ppm * 1e-6);
+        let span = (count - 1) as f64 * period;
+        let arrival = arrival_us as f64;
+
+        let (first, pull_back) = match self.last_us.map(|last| last + period) {
+            Some(next) if arrival - (next + span) <= MAX_LATENCY_US => {
+                let late = next + span - arrival;
+                (next, late.clamp(0.0, count as f64 * period / 2.0))
+            }
+            _ => (arrival - span, 0.0),
+        };
+
+        let mut timestamps: Vec<f64> = (0..count)
+            .map(|n| first + n as f64 * period - pull_back * (n + 1) as f64 / count as f64)
+            .collect();
+        if let Some(last) = self.last_us.filter(|_| timestamps[count - 1] > arrival) {
+            let spacing = (arrival - last).max(0.0) / count as f64;
+            timestamps = (1..=count).map(|n| last + n as f64 * spacing).collect();
+        }
+        self.last_us = timestamps.last().copied();
+
+        timestamps.iter().map(|t| t.round() as i64).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spaces_samples_at_the_sampling_rate() {
+        let mut clock = SampleClock::new(100.0);
+        assert_eq!(clock.timestamps(1_000_000, 3, 0.0), vec![980_000, 990_000, 1_000_000]);
+        // arrival jitter does not move the schedule
+        assert_eq!(clock.timestamps(1_045_000, 3, 0.0), vec![1_010_000, 1_020_000, 1_030_000]);
+        // nor do lost samples
+        assert_eq!(clock.timestamps(1_070_000, 4, 0.0), vec![1_040_000, 1_050_000, 1_060_000, 1_070_000]);
+    }
+
+    #[test]
+    fn pulls_late_schedules_back_gradually() {
+        let mut clock = SampleClock::new(100.0);
+        clock.timestamps(1_000_000, 3, 0.0);
+        assert_eq!(clock.timestamps(1_025_000, 3, 0.0), vec![1_008_333, 1_016_667, 1_025_000]);
+        // a queued packet right behind it ends at its arrival, not in the future
+        assert_eq!(clock.timestamps(1_026_000, 3, 0.0), vec![1_025_333, 1_025_667, 1_026_000]);
+        // then the sampling rate resumes
+        assert_eq!(clock.timestamps(1_060_000, 3, 0.0), vec![1_036_000, 1_046_000, 1_056_000]);
+
+        // after a stall the schedule starts over
+        assert_eq!(clock.timestamps(3_000_000, 2, 0.0), vec![2_990_000, 3_000_000]);
+    }
+
+    #[test]
+    fn corrects_the_period_by_the_skew() {
+        let mut clock = SampleClock::new(100.0);
+        assert_eq!(clock.timestamps(1_000_000, 2, 1_000_000.0), vec![995_000, 1_000_000]);
+    }
+}
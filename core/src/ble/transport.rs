@@ -0,0 +1,59 @@
+use std::collections::BTreeSet;
+use std::error::Error;
+use std::pin::Pin;
+use std::sync::Arc;
+use async_trait::async_trait;
+use btleplug::api::{Characteristic, Service, ValueNotification, WriteType};
+use tokio_stream::Stream;
+use uuid::Uuid;
+
+pub type BleResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+pub type TransportEventStream = Pin<Box<dyn Stream<Item=TransportEvent> + Send>>;
+pub type NotificationStream = Pin<Box<dyn Stream<Item=ValueNotification> + Send>>;
+
+/// Central events, keyed by the transport's peripheral id
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransportEvent {
+    DeviceDiscovered(String),
+    /// new advertisement data, e.g. a changed RSSI
+    DeviceUpdated(String),
+    DeviceDisconnected(String),
+}
+
+/// What a peripheral advertises, as far as the platform reports it
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Advertisement {
+    pub local_name: Option<String>,
+    pub rssi: Option<i16>,
+    pub services: Vec<Uuid>,
+}
+
+/// The central side of the BLE stack, i.e. the adapter we scan and connect with.
+/// Implemented by `btleplug` for real hardware and by `fake::FakeTransport` for tests.
+#[async_trait]
+pub trait BleTransport: Send + Sync {
+    /// Must be subscribed to before `start_scan`, otherwise discoveries may be missed
+    async fn events(&self) -> BleResult<TransportEventStream>;
+    async fn start_scan(&self, services: Vec<Uuid>) -> BleResult<()>;
+    async fn stop_scan(&self) -> BleResult<()>;
+    async fn peripherals(&self) -> BleResult<Vec<Arc<dyn BlePeripheral>>>;
+    async fn peripheral(&self, id: &str) -> BleResult<Arc<dyn BlePeripheral>>;
+}
+
+/// A single GATT peripheral, mirroring the subset of `btleplug::api::Peripheral` we use
+#[async_trait]
+pub trait BlePeripheral: Send + Sync {
+    fn id(&self) -> String;
+    fn services(&self) -> BTreeSet<Service>;
+    async fn advertisement(&self) -> BleResult<Advertisement>;
+    async fn is_connected(&self) -> BleResult<bool>;
+    async fn connect(&self) -> BleResult<()>;
+    async fn disconnect(&self) -> BleResult<()>;
+    async fn discover_services(&self) -> BleResult<()>;
+    async fn read(&self, characteristic: &Characteristic) -> BleResult<Vec<u8>>;
+    async fn write(&self, characteristic: &Characteristic, data: &[u8], write_type: WriteType) -> BleResult<()>;
+    async fn subscribe(&self, characteristic: &Characteristic) -> BleResult<()>;
+    async fn unsubscribe(&self, characteristic: &Characteristic) -> BleResult<()>;
+    async fn notifications(&self) -> BleResult<NotificationStream>;
+}
@@ -0,0 +1,79 @@
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// What to do about a data stream that stopped
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum StallAction {
+    /// first stall: mark the device stalled and subscribe to the data again
+    Resubscribe,
+    /// still no data after resubscribing: drop the link and reconnect
+    Reconnect,
+}
+
+/// Watches the data stream of a device, escalating each time `timeout` passes without data
+#[derive(Debug)]
+pub struct StallWatchdog {
+    timeout: Option<Duration>,
+    deadline: Option<Instant>,
+    stalled: bool,
+}
+
+impl StallWatchdog {
+    /// A `None` timeout never expires
+    pub fn new(timeout: Option<Duration>) -> Self {
+        let mut watchdog = Self {
+            timeout,
+            deadline: None,
+            stalled: false,
+        };
+        watchdog.reset();
+        watchdog
+    }
+
+    /// Restarts the timeout, e.g. after resuming a paused stream
+    pub fn reset(&mut self) {
+        self.deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        self.stalled = false;
+    }
+
+    /// Data arrived, returns whether the stream was stalled before
+    pub fn feed(&mut self) -> bool {
+        let stalled = self.stalled;
+        self.reset();
+        stalled
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// The deadline passed without data
+    pub fn expire(&mut self) -> StallAction {
+        self.deadline = self.timeout.map(|timeout| Instant::now() + timeout);
+        if self.stalled {
+            StallAction::Reconnect
+        } else {
+            self.stalled = true;
+            StallAction::Resubscribe
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn escalates_until_fed() {
+        let mut watchdog = StallWatchdog::new(Some(Duration::from_secs(5)));
+        assert!(watchdog.deadline().is_some());
+        assert_eq!(watchdog.expire(), StallAction::Resubscribe);
+        assert!(watchdog.feed());
+        assert!(!watchdog.feed());
+
+        assert_eq!(watchdog.expire(), StallAction::Resubscribe);
+        assert_eq!(watchdog.expire(), StallAction::Reconnect);
+
+        assert!(StallWatchdog::new(None).deadline().is_none());
+    }
+}
@@ -0,0 +1,133 @@
+//! Where the core takes the time from.
+//!
+//! The wall clock time, for timestamps and for setting and syncing device clocks, comes from a
+//! `Clock`. Deadlines, sleeps and intervals use `tokio::time`, which follows the runtime the
+//! clock builds, so a `VirtualClock` moves both at once.
+
+use std::sync::Arc;
+use chrono::{DateTime, Utc};
+use tokio::runtime::{Runtime, RuntimeFlavor};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+pub trait Clock: Send + Sync {
+    /// The wall clock time
+    fn now(&self) -> DateTime<Utc>;
+
+    /// Builds the runtime the core runs its tasks on
+    fn runtime(&self) -> std::io::Result<Runtime>;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+
+    fn runtime(&self) -> std::io::Result<Runtime> {
+        Runtime::new()
+    }
+}
+
+/// Simulated time, starting at `start` on a runtime whose timers are paused. Whenever all tasks
+/// wait, the time jumps to the next timer, so timeouts, sync intervals and hours of a mock
+/// session pass as fast as the tasks run. Runs deterministically as long as nothing outside
+/// the runtime, like a real transport, feeds it.
+#[cfg(any(test, feature = "virtual-clock"))]
+pub struct VirtualClock {
+    start: DateTime<Utc>,
+    /// the runtime, whose paused time is read from any thread, and its instant `start`
+    /// corresponds to, once it is built
+    runtime: std::sync::OnceLock<(tokio::runtime::Handle, tokio::time::Instant)>,
+}
+
+#[cfg(any(test, feature = "virtual-clock"))]
+impl VirtualClock {
+    pub fn new(start: DateTime<Utc>) -> Arc<Self> {
+        Arc::new(Self { start, runtime: std::sync::OnceLock::new() })
+    }
+}
+
+#[cfg(any(test, feature = "virtual-clock"))]
+impl Clock for VirtualClock {
+    /// The start time until the runtime is built
+    fn now(&self) -> DateTime<Utc> {
+        match self.runtime.get() {
+            Some((handle, origin)) => {
+                let _context = handle.enter();
+                let elapsed = tokio::time::Instant::now().saturating_duration_since(*origin);
+                self.start + chrono::Duration::from_std(elapsed).unwrap_or_default()
+            }
+            None => self.start,
+        }
+    }
+
+    /// A current-thread runtime, only those can be paused. Building it a second time fails.
+    fn runtime(&self) -> std::io::Result<Runtime> {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().start_paused(true).build()?;
+        let origin = runtime.block_on(async { tokio::time::Instant::now() });
+        self.runtime.set((runtime.handle().clone(), origin)).map_err(|_| std::io::Error::other("The virtual clock already has a runtime"))?;
+        Ok(runtime)
+    }
+}
+
+/// The runtime of a core. A current-thread runtime gets a thread that drives its tasks until
+/// the core is dropped.
+pub(crate) struct CoreRuntime {
+    runtime: Arc<Runtime>,
+    _stop: Option<oneshot::Sender<()>>,
+}
+
+impl CoreRuntime {
+    pub fn new(runtime: Runtime) -> std::io::Result<Self> {
+        let runtime = Arc::new(runtime);
+        let stop = match runtime.handle().runtime_flavor() {
+            RuntimeFlavor::CurrentThread => {
+                let (stop, stopped) = oneshot::channel::<()>();
+                let driven = runtime.clone();
+                std::thread::Builder::new()
+                    .name("vvcore-runtime".to_string())
+                    .spawn(move || {
+                        driven.block_on(stopped).ok();
+                    })?;
+                Some(stop)
+            }
+            _ => None,
+        };
+        Ok(Self { runtime, _stop: stop })
+    }
+
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: std::future::Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.runtime.spawn(future)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn virtual_time_jumps_while_tasks_wait() {
+        let start = DateTime::parse_from_rfc3339("2024-03-01T12:00:00Z").unwrap().with_timezone(&Utc);
+        let clock = VirtualClock::new(start);
+        let runtime = clock.runtime().unwrap();
+        assert!(clock.runtime().is_err());
+
+        let began = std::time::Instant::now();
+        let now = runtime.block_on(async {
+            let mut ticks = tokio::time::interval(Duration::from_secs(60));
+            for _ in 0..=24 * 60 {
+                ticks.tick().await;
+            }
+            clock.now()
+        });
+        assert_eq!(now, start + chrono::Duration::days(1));
+        assert!(began.elapsed() < Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,50 @@
+use std::fmt;
+
+/// Errors returned by `VVCore` calls and reported through `VVCoreDelegate::error_occurred`
+#[derive(Debug, PartialEq, Clone)]
+pub enum VVCoreError {
+    /// the configuration cannot be used, e.g. a sensor profile does not parse
+    InvalidConfig { message: String },
+    /// an argument of the call is out of range
+    InvalidArgument { message: String },
+    /// the call needs the BLE loop, which was not started
+    NotStarted,
+    /// the BLE loop runs already
+    AlreadyStarted,
+    /// the Bluetooth adapter or the transport failed
+    Bluetooth { message: String },
+    /// a device failed, e.g. it reported malformed information or dropped during setup
+    Device { device_id: String, message: String },
+    /// the core itself failed, e.g. a background task ended unexpectedly
+    Internal { message: String },
+}
+
+impl VVCoreError {
+    pub(crate) fn bluetooth(error: impl fmt::Display) -> Self {
+        Self::Bluetooth { message: error.to_string() }
+    }
+
+    pub(crate) fn device(device_id: &str, error: impl fmt::Display) -> Self {
+        Self::Device { device_id: device_id.to_string(), message: error.to_string() }
+    }
+
+    pub(crate) fn internal(error: impl fmt::Display) -> Self {
+        Self::Internal { message: error.to_string() }
+    }
+}
+
+impl fmt::Display for VVCoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidConfig { message } => write!(f, "Invalid config: {}", message),
+            Self::InvalidArgument { message } => write!(f, "Invalid argument: {}", message),
+            Self::NotStarted => write!(f, "BLE loop not started"),
+            Self::AlreadyStarted => write!(f, "BLE loop already started"),
+            Self::Bluetooth { message } => write!(f, "Bluetooth error: {}", message),
+            Self::Device { device_id, message } => write!(f, "Device {} failed: {}", device_id, message),
+            Self::Internal { message } => write!(f, "Internal error: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for VVCoreError {}
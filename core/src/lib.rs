@@ -1,41 +1,195 @@
+// the generated UniFFI scaffolding trips this lint
+#![allow(clippy::empty_line_after_doc_comments)]
+
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 use slog::{debug, error, Logger, trace};
 use tokio::sync::RwLock;
 use crate::analysis::{ppg, ecg};
 use crate::ble::ExternalBleEvent;
+use crate::ble::control::ControlCommand;
+use crate::ble::profile::SensorProfile;
+use crate::ble::transport::BleTransport;
+use crate::clock::{Clock, CoreRuntime, SystemClock};
+pub use crate::error::VVCoreError;
+pub use crate::storage::known_devices::{KnownDevice, KnownDeviceStore};
 
 uniffi::include_scaffolding!("vvcore");
 
 pub mod ble;
+pub mod clock;
 pub mod storage;
+pub mod synth;
 mod analysis;
+mod error;
+// predates the lint gate
+#[allow(clippy::let_and_return)]
 mod log;
+#[cfg(test)]
+mod tests;
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Device {
     id: String,
+    transport_id: String,
     serial: u16,
+    /// model number
     name: String,
+    manufacturer: Option<String>,
+    hardware_revision: Option<String>,
+    firmware_revision: Option<String>,
+    software_revision: Option<String>,
+    /// hex, `OUI-manufacturer identifier` if the device sends the standard 8 bytes
+    system_id: Option<String>,
+    /// dBm, as last polled
+    rssi: Option<i16>,
     battery: u8,
+    /// round trip time of the latest time sync
     drift_us: i64,
+    clock_offset_us: i64,
+    clock_skew_ppm: f64,
+    clock_uncertainty_us: i64,
+    state: DeviceState,
+    /// streaming, stalled or paused, kept along `state` for existing clients
     connected: bool,
+    reconnect_attempts: u32,
+    reconnect_failed: bool,
+    packets_received: u64,
+    packets_lost: u64,
     channels: Vec<Channel>,
 }
 
+/// Where a device is in setting up or running its connection
+#[derive(Debug, PartialEq, Clone)]
+pub enum DeviceState {
+    /// found by the scan or known from before, no connection attempted yet
+    Discovered,
+    Connecting,
+    DiscoveringServices,
+    /// reading the Device Information and the battery level
+    ReadingInfo,
+    SyncingTime,
+    /// subscribing to the data characteristic and reading its channel layout
+    Subscribing,
+    Streaming,
+    /// connected, but data stopped arriving
+    Stalled,
+    /// connected, with the data unsubscribed by the app
+    Paused,
+    Disconnecting,
+    Disconnected,
+    /// a connection step failed or timed out
+    Failed { reason: String },
+}
+
+impl Device {
+    /// Stands in for a device until its information is read
+    fn placeholder(id: &str, transport_id: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            transport_id: transport_id.to_string(),
+            serial: 0,
+            name: String::new(),
+            manufacturer: None,
+            hardware_revision: None,
+            firmware_revision: None,
+            software_revision: None,
+            system_id: None,
+            rssi: None,
+            battery: 0,
+            drift_us: 0,
+            clock_offset_us: 0,
+            clock_skew_ppm: 0.0,
+            clock_uncertainty_us: 0,
+            state: DeviceState::Discovered,
+            connected: false,
+            reconnect_attempts: 0,
+            reconnect_failed: false,
+            packets_received: 0,
+            packets_lost: 0,
+            channels: vec![],
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub struct Channel {
     id: String,
     name: String,
     channel_type: ChannelType,
     signal_quality: Option<f32>,
+    samples_received: u64,
+    samples_lost: u64,
+}
+
+/// How a control command sent to a device went
+#[derive(Debug, PartialEq, Clone)]
+pub enum CommandOutcome {
+    Acknowledged,
+    /// the device answered with a non-zero status
+    Rejected { status: u8 },
+    /// no answer within `command_timeout_ms`
+    TimedOut,
+    /// the command could not be sent, e.g. the device is not connected
+    Failed { message: String },
+}
+
+/// How downloading the data a device recorded while disconnected went
+#[derive(Debug, PartialEq, Clone)]
+pub enum BacklogOutcome {
+    /// packets received, fewer than missing if the device overwrote the oldest
+    Completed { packets: u32 },
+    Failed { message: String },
+}
+
+/// Gain of the ECG front end
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum EcgGain {
+    X1,
+    X2,
+    X3,
+    X4,
+    X6,
+    X8,
+    X12,
 }
 
+/// A device found by the scan, connected or not
 #[derive(Debug, PartialEq, Clone)]
+pub struct NearbyDevice {
+    /// the id to pass to `VVCore::connect_device`
+    pub transport_id: String,
+    /// stable id, if the device was connected before
+    pub device_id: Option<String>,
+    /// advertised name
+    pub name: Option<String>,
+    pub rssi: Option<i16>,
+    /// uuids of the advertised services
+    pub services: Vec<String>,
+}
+
+/// A sample with the host time it was taken at, `None` if its packet was lost
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Sample {
+    /// us since the epoch, 0 for the unfilled start of a window
+    pub timestamp_us: i64,
+    pub value: Option<i32>,
+}
+
+#[derive(Debug, PartialEq, Clone, serde::Deserialize)]
 pub enum ChannelType {
     CNT,
     ECG,
     PPG,
+    /// heart rate in bpm
+    HR,
+    /// RR intervals in ms
+    RR,
+    /// oxygen saturation in %
+    SPO2,
+    /// status flags of a measurement, as the device sends them
+    STATUS,
 }
 
 // TODO: This is a bit of a hack, but it works for now
@@ -50,6 +204,8 @@ pub type PPGAnalysisResults = ppg::Results;
 
 pub type PPGAnalysis = ppg::Analysis;
 
+pub type PPGAutoGainParameters = ble::auto_gain::Parameters;
+
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct VVCoreConfig {
@@ -61,20 +217,60 @@ pub struct VVCoreConfig {
     pub analysis_interval_points: u32,
     pub ecg_analysis_params: ECGAnalysisParameters,
     pub ppg_analysis_params: PPGAnalysisParameters,
+    pub sensor_profiles: Vec<String>,
+    pub reconnect_initial_delay_ms: u32,
+    pub reconnect_max_delay_ms: u32,
+    pub reconnect_max_attempts: u32,
+    pub step_timeout_ms: u32,
+    /// time without data until a device is resubscribed and then reconnected, 0 to never check
+    pub stall_timeout_ms: u32,
+    /// how often the RSSI of connected devices is polled, 0 to never poll
+    pub rssi_interval_ms: u32,
+    /// time a control command waits for the device's answer
+    pub command_timeout_ms: u32,
+    /// deliver windows through `new_timestamped_data` instead of `new_data`
+    pub timestamped_data: bool,
+    /// connect every discovered device, otherwise only devices passed to `connect_device`
+    pub auto_connect: bool,
+    /// if not empty, auto-connect only devices with these serials
+    pub auto_connect_serials: Vec<u16>,
+    /// adjusts the LED currents of devices with a control characteristic, `None` to leave them
+    pub ppg_auto_gain: Option<PPGAutoGainParameters>,
+    /// download the data devices recorded while disconnected once they reconnect
+    pub download_backlog: bool,
+    /// writes everything the devices send to this session file, for `start_replay`
+    pub capture_path: Option<String>,
 }
 
 pub trait VVCoreDelegate: Send + Sync {
     fn devices_changed(&self, devices: Vec<Device>);
     fn new_data(&self, uuid: String, data: Vec<Option<i32>>);
+    /// Only called if `timestamped_data` is set in the config
+    fn new_timestamped_data(&self, uuid: String, data: Vec<Sample>);
+    /// Failures of the background tasks, which keep running where they can
+    fn error_occurred(&self, error: VVCoreError);
+    /// Devices found by the scan, strongest signal first
+    fn nearby_devices_changed(&self, devices: Vec<NearbyDevice>);
+    /// Outcome of a control command, by the id its call returned
+    fn command_completed(&self, device_id: String, command_id: u32, outcome: CommandOutcome);
+    /// Packets of the data recorded while disconnected received so far, after a reconnect
+    fn backlog_progress(&self, device_id: String, received_packets: u32, total_packets: u32);
+    fn backlog_completed(&self, device_id: String, outcome: BacklogOutcome);
 }
 
 pub struct VVCore {
     config: VVCoreConfig,
     delegate: Arc<dyn VVCoreDelegate>,
+    known_devices: Arc<dyn KnownDeviceStore>,
+    profiles: Vec<SensorProfile>,
+    started: AtomicBool,
+    /// ids of the control commands, unique per instance
+    command_ids: AtomicU32,
     device_storage: Arc<RwLock<storage::DeviceStorage>>,
     data_storage: Arc<RwLock<storage::DataStorage>>,
     event_broadcast: tokio::sync::broadcast::Sender<VVCoreInternalEvent>,
-    rt: tokio::runtime::Runtime,
+    clock: Arc<dyn Clock>,
+    rt: CoreRuntime,
     logger: Logger,
 }
 
@@ -83,10 +279,30 @@ enum VVCoreInternalEvent {
     SyncTime,
     Pause,
     Resume,
+    Connect(String),
+    Disconnect(String),
+    PauseDevice(String),
+    ResumeDevice(String),
+    PauseStreaming,
+    ResumeStreaming,
+    /// device id, command id and the command
+    Command(String, u32, ControlCommand),
 }
 
 impl VVCore {
-    pub fn new(config: VVCoreConfig, delegate: Arc<dyn VVCoreDelegate>) -> Self {
+    pub fn new(config: VVCoreConfig, delegate: Arc<dyn VVCoreDelegate>) -> Result<Self, VVCoreError> {
+        Self::new_with_store(config, delegate, Arc::new(storage::known_devices::MemoryKnownDeviceStore::default()))
+    }
+
+    /// Remembers the connected devices in `known_devices`, so they are reconnected by later instances
+    pub fn new_with_store(config: VVCoreConfig, delegate: Arc<dyn VVCoreDelegate>, known_devices: Arc<dyn KnownDeviceStore>) -> Result<Self, VVCoreError> {
+        Self::new_with_clock(config, delegate, known_devices, Arc::new(SystemClock))
+    }
+
+    /// Takes the time from `clock`, e.g. a `clock::VirtualClock` to run long sessions in tests
+    pub fn new_with_clock(config: VVCoreConfig, delegate: Arc<dyn VVCoreDelegate>, known_devices: Arc<dyn KnownDeviceStore>, clock: Arc<dyn Clock>) -> Result<Self, VVCoreError> {
+        let profiles = Self::sensor_profiles(&config)?;
+
         let device_storage = storage::DeviceStorage::new();
         let arc_device_storage = Arc::new(RwLock::new(device_storage));
 
@@ -95,55 +311,104 @@ impl VVCore {
 
         let (event_broadcast, _) = tokio::sync::broadcast::channel(1000);
 
-        let rt = tokio::runtime::Runtime::new().unwrap();
+        let rt = clock.runtime().and_then(CoreRuntime::new).map_err(VVCoreError::internal)?;
 
         let logger = log::create_logger("VVCore".to_string());
         
         error!(logger, "Starting VVCore"; "config" => format!("{:?}", config));
 
-        Self {
+        Ok(Self {
             config,
             delegate,
+            known_devices,
+            profiles,
+            started: AtomicBool::new(false),
+            command_ids: AtomicU32::new(0),
             device_storage: arc_device_storage,
             data_storage: arc_data_storage,
             event_broadcast,
+            clock,
             rt,
             logger,
-        }
+        })
     }
     
     pub fn add_logger(&mut self, logger: Logger) {
         self.logger = logger;
     }
 
-    pub fn start_ble_loop(&self) {
+    pub fn start_ble_loop(&self) -> Result<(), VVCoreError> {
         if self.config.enable_mock_devices {
             debug!(self.logger, "Starting mock BLE loop");
-            let delegate = self.delegate.clone();
-            let hist_size = self.config.hist_size_api;
-            self.rt.spawn(async move {
-                ble::mock::mock_loop(delegate, hist_size).await;
-            });
-            return;
+            return self.start_mock_loop(ble::mock::MockRunner::new(ble::mock::mock_devices(), self.logger.clone()));
+        }
+
+        self.start_ble_loop_with_transport(Arc::new(ble::btleplug_transport::BtleplugTransport::new()))
+    }
+
+    /// Plays a session file written with `capture_path` back as virtual devices, `speed` times
+    /// faster than it was captured
+    pub fn start_replay(&self, path: String, speed: f64) -> Result<(), VVCoreError> {
+        let transport = ble::replay::ReplayTransport::open(&path, speed, self.logger.clone())
+            .map_err(|e| VVCoreError::InvalidArgument { message: e.to_string() })?;
+        self.start_ble_loop_with_transport(transport)
+    }
+
+    /// Runs a mock session scripted by a scenario file, see `ble::scenario::Scenario`
+    pub fn start_scenario(&self, path: String) -> Result<(), VVCoreError> {
+        let scenario = ble::scenario::Scenario::open(&path)
+            .map_err(|e| VVCoreError::InvalidArgument { message: e.to_string() })?;
+        self.start_mock_loop(ble::mock::MockRunner::from_scenario(scenario, self.logger.clone()))
+    }
+
+    fn start_mock_loop(&self, runner: ble::mock::MockRunner) -> Result<(), VVCoreError> {
+        self.start_ble_loop_with_transport(runner.transport())?;
+        self.rt.spawn(ble::mock::mock_loop(runner));
+        Ok(())
+    }
+
+    /// Runs the full BLE pipeline on the given transport, e.g. a `ble::fake::FakeTransport` in tests
+    pub fn start_ble_loop_with_transport(&self, transport: Arc<dyn BleTransport>) -> Result<(), VVCoreError> {
+        if self.started.swap(true, Ordering::SeqCst) {
+            return Err(VVCoreError::AlreadyStarted);
         }
 
+        let transport = match &self.config.capture_path {
+            Some(path) => match ble::capture::CaptureFile::create(path, self.clock.clone(), self.logger.clone()) {
+                Ok(file) => ble::capture::CaptureTransport::new(transport, file),
+                Err(e) => {
+                    self.started.store(false, Ordering::SeqCst);
+                    return Err(VVCoreError::InvalidConfig { message: e.to_string() });
+                }
+            },
+            None => transport,
+        };
+
         let rt = &self.rt;
 
         let (ble_tx, mut ble_rx) = tokio::sync::mpsc::channel(1000);
-        let max_initial_rtt_ms = self.config.max_initial_rtt_ms;
         let logger = self.logger.clone();
-        let ble = Arc::new(ble::Ble::new(ble_tx, max_initial_rtt_ms, logger));
+        let profiles = self.profiles.clone();
+        let settings = ble::BleSettings::from_config(&self.config);
+        let known_devices = self.known_devices.load();
+        let ble = Arc::new(ble::Ble::new(transport, profiles, settings, known_devices, self.clock.clone(), ble_tx, logger));
 
         let logger = self.logger.clone();
         let ble_clone = ble.clone();
+        let delegate = self.delegate.clone();
         let ble_loop = rt.spawn(async move {
             debug!(logger, "Starting BLE task");
-            ble_clone.run_loop().await.unwrap();
+            if let Err(e) = ble_clone.run_loop().await {
+                error!(logger, "BLE task failed"; "error" => e.to_string());
+                delegate.error_occurred(VVCoreError::bluetooth(e));
+            }
         });
         
         let ble_clone = ble.clone();
         let sync_interval = self.config.sync_interval_sec;
         let analysis_interval = self.config.analysis_interval_points;
+        let hist_size_api = self.config.hist_size_api as usize;
+        let timestamped_data = self.config.timestamped_data;
         let logger = self.logger.clone();
         let periodic_time_sync = rt.spawn(async move {
             debug!(logger, "Starting periodic time sync task");
@@ -159,15 +424,22 @@ impl VVCore {
         let global_events = rt.spawn(async move {
             debug!(logger, "Starting global event handler task");
             loop {
-                // forward SyncTime, Pause, Resume events to BLE
-                let event = rx.recv().await;
-                ble_clone.forward_event(event.unwrap()).await;
+                // forward the events of the public calls to BLE
+                match rx.recv().await {
+                    Ok(event) => ble_clone.forward_event(event).await,
+                    Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                        error!(logger, "Dropped global events"; "count" => skipped);
+                    }
+                    // the core is being dropped
+                    Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                }
             }
         });
 
         let device_storage = self.device_storage.clone();
         let data_storage = self.data_storage.clone();
         let delegate = self.delegate.clone();
+        let known_devices = self.known_devices.clone();
 
         let ecg_analysis = ecg::Analysis::new_with_logs(
             self.config.ecg_analysis_params.clone(),
@@ -183,16 +455,24 @@ impl VVCore {
 
         let ble_event_handler = self.rt.spawn(async move {
             debug!(logger, "Starting BLE event handler task");
-            loop {
-                let event = ble_rx.recv().await;
-                if event.is_none() {
-                    break;
-                }
-
-                match event.unwrap() {
+            while let Some(event) = ble_rx.recv().await {
+                match event {
+                    ExternalBleEvent::StateChanged(uuid, transport_id, state) => {
+                        trace!(logger, "Device state changed: {:?} {:?}", uuid, state);
+                        let mut device_storage = device_storage.write().await;
+                        storage::replace_placeholders(&mut device_storage, &uuid, &transport_id);
+                        let device = device_storage.entry(uuid.clone())
+                            .or_insert_with(|| Device::placeholder(&uuid, &transport_id));
+                        device.connected = matches!(state, DeviceState::Streaming | DeviceState::Stalled | DeviceState::Paused);
+                        device.state = state;
+                        delegate.devices_changed(device_storage.values().cloned().collect());
+                    }
                     ExternalBleEvent::DeviceConnected(device) => {
                         trace!(logger, "Device connected: {:?}", device);
+                        storage::known_devices::remember(known_devices.as_ref(), &device);
+
                         let mut device_storage = device_storage.write().await;
+                        storage::replace_placeholders(&mut device_storage, &device.id, &device.transport_id);
                         device_storage.insert(device.id.clone(), device.clone());
                         delegate.devices_changed(device_storage.values().cloned().collect());
                         drop(device_storage);
@@ -207,11 +487,39 @@ impl VVCore {
                         trace!(logger, "Device disconnected: {:?}", uuid);
                         let mut device_storage = device_storage.write().await;
                         if let Some(device) = device_storage.get_mut(&uuid) {
+                            device.state = DeviceState::Disconnected;
                             device.connected = false;
                             device.drift_us = 0;
                             for channel in device.channels.iter_mut() {
                                 channel.signal_quality = None;
                             }
+                            // channels are kept, so their history continues if the device reconnects
+                            delegate.devices_changed(device_storage.values().cloned().collect());
+                        }
+                    }
+                    ExternalBleEvent::DeviceIgnored(transport_id) => {
+                        trace!(logger, "Device ignored: {:?}", transport_id);
+                        let mut device_storage = device_storage.write().await;
+                        device_storage.retain(|_, device| device.transport_id != transport_id);
+                        delegate.devices_changed(device_storage.values().cloned().collect());
+                    }
+                    ExternalBleEvent::NearbyDevicesChanged(devices) => {
+                        trace!(logger, "Nearby devices changed: {:?}", devices);
+                        delegate.nearby_devices_changed(devices);
+                    }
+                    ExternalBleEvent::Reconnecting(uuid, attempt) => {
+                        trace!(logger, "Reconnecting: {:?} {:?}", uuid, attempt);
+                        let mut device_storage = device_storage.write().await;
+                        if let Some(device) = device_storage.get_mut(&uuid) {
+                            device.reconnect_attempts = attempt;
+                        }
+                        delegate.devices_changed(device_storage.values().cloned().collect());
+                    }
+                    ExternalBleEvent::ReconnectFailed(uuid) => {
+                        trace!(logger, "Reconnect failed: {:?}", uuid);
+                        let mut device_storage = device_storage.write().await;
+                        if let Some(device) = device_storage.get_mut(&uuid) {
+                            device.reconnect_failed = true;
                             let channels = device.channels.clone();
                             delegate.devices_changed(device_storage.values().cloned().collect());
                             drop(device_storage);
@@ -226,24 +534,89 @@ impl VVCore {
                     ExternalBleEvent::BatteryLevelChanged(uuid, battery) => {
                         trace!(logger, "Battery level changed: {:?} {:?}", uuid, battery);
                         let mut device_storage = device_storage.write().await;
-                        device_storage.get_mut(&uuid).map(|x| x.battery = battery);
+                        if let Some(device) = device_storage.get_mut(&uuid) {
+                            device.battery = battery;
+                        }
                         delegate.devices_changed(device_storage.values().cloned().collect());
                     }
-                    ExternalBleEvent::DriftChanged(uuid, drift) => {
-                        trace!(logger, "Drift changed: {:?} {:?}", uuid, drift);
+                    ExternalBleEvent::RssiChanged(uuid, rssi) => {
+                        trace!(logger, "RSSI changed: {:?} {:?}", uuid, rssi);
                         let mut device_storage = device_storage.write().await;
-                        device_storage.get_mut(&uuid).map(|x| x.drift_us = drift);
+                        if let Some(device) = device_storage.get_mut(&uuid) {
+                            device.rssi = rssi;
+                        }
+                        delegate.devices_changed(device_storage.values().cloned().collect());
+                    }
+                    ExternalBleEvent::ClockSynced(uuid, clock) => {
+                        trace!(logger, "Clock synced: {:?} {:?}", uuid, clock);
+                        let mut device_storage = device_storage.write().await;
+                        if let Some(device) = device_storage.get_mut(&uuid) {
+                            device.drift_us = clock.rtt_us;
+                            device.clock_offset_us = clock.offset_us;
+                            device.clock_skew_ppm = clock.skew_ppm;
+                            device.clock_uncertainty_us = clock.uncertainty_us;
+                        }
+                        delegate.devices_changed(device_storage.values().cloned().collect());
+                    }
+                    ExternalBleEvent::CommandCompleted(uuid, command_id, outcome) => {
+                        debug!(logger, "Command completed: {:?} {:?} {:?}", uuid, command_id, outcome);
+                        delegate.command_completed(uuid, command_id, outcome);
+                    }
+                    ExternalBleEvent::BacklogProgress(uuid, received, total) => {
+                        trace!(logger, "Backlog progress: {:?} {}/{}", uuid, received, total);
+                        delegate.backlog_progress(uuid, received, total);
+                    }
+                    ExternalBleEvent::BacklogReceived(device_id, packet) => {
+                        trace!(logger, "Backlog received: {:?} {:?}", device_id, packet);
+                        let mut data_storage = data_storage.write().await;
+                        for (uuid, data) in packet.samples.iter() {
+                            let Some(window_api) = data_storage.merge_datapoints(uuid, data, &packet.timestamps_us) else {
+                                continue;
+                            };
+                            if timestamped_data {
+                                if let Some(window) = data_storage.timestamped_window(uuid, hist_size_api) {
+                                    delegate.new_timestamped_data(uuid.clone(), window);
+                                }
+                            } else {
+                                delegate.new_data(uuid.clone(), window_api.to_vec());
+                            }
+                        }
+                        drop(data_storage);
+
+                        let mut device_storage = device_storage.write().await;
+                        if let Some(device) = device_storage.get_mut(&device_id) {
+                            for channel in &mut device.channels {
+                                if let Some(samples) = packet.samples.get(&channel.id) {
+                                    let lost = samples.iter().filter(|x| x.is_none()).count() as u64;
+                                    channel.samples_lost += lost;
+                                    channel.samples_received += samples.len() as u64 - lost;
+                                }
+                            }
+                        }
+                    }
+                    ExternalBleEvent::BacklogCompleted(uuid, outcome) => {
+                        debug!(logger, "Backlog completed: {:?} {:?}", uuid, outcome);
+                        let device_storage = device_storage.read().await;
                         delegate.devices_changed(device_storage.values().cloned().collect());
+                        drop(device_storage);
+                        delegate.backlog_completed(uuid, outcome);
                     }
-                    ExternalBleEvent::DataReceived(data) => {
-                        trace!(logger, "Data received: {:?}", data);
+                    ExternalBleEvent::Error(error) => {
+                        debug!(logger, "Error reported: {}", error);
+                        delegate.error_occurred(error);
+                    }
+                    ExternalBleEvent::DataReceived(device_id, packet) => {
+                        trace!(logger, "Data received: {:?} {:?}", device_id, packet);
                         let mut data_storage = data_storage.write().await;
                         let mut analysis_results = HashMap::new();
 
-                        for (uuid, data) in data.iter() {
-                            let ret = data_storage.add_datapoint(uuid.clone(), data.clone());
+                        for (uuid, data) in packet.samples.iter() {
+                            let timestamps_us = packet.channel_timestamps_us.get(uuid).unwrap_or(&packet.timestamps_us);
+                            let ret = data_storage.add_datapoint(uuid.clone(), data.clone(), timestamps_us, packet.settling.contains(uuid));
                             if let Some((window_api, window_analysis, channel_type, datapoint_counter)) = ret {
-                                delegate.new_data(uuid.clone(), window_api.to_vec());
+                                if !timestamped_data {
+                                    delegate.new_data(uuid.clone(), window_api.to_vec());
+                                }
 
                                 if datapoint_counter > analysis_interval {
                                     trace!(logger, "Analyzing data for {}", uuid);
@@ -251,18 +624,14 @@ impl VVCore {
                                     // TODO: Clean this up, analysis should output a single result
                                     let mut quality: Option<f32> = match channel_type {
                                         ChannelType::ECG => {
-                                            let as_f64 = window_analysis.iter().filter_map(|x| x.map(|x| x as f64)).collect::<Vec<f64>>();
-                                            let results = ecg_analysis.analyze(as_f64);
+                                            let as_f64 = window_analysis.iter().map(|x| x.map(|x| x as f64)).collect::<Vec<_>>();
+                                            let results = ecg_analysis.analyze_with_gaps(as_f64);
                                             Some(results.signal_quality as f32)
                                         }
                                         ChannelType::PPG => {
-                                            let as_f64 = window_analysis.iter().filter_map(|x| x.map(|x| x as f64)).collect::<Vec<f64>>();
-                                            let results = ppg_analysis.analyze(as_f64);
-                                            if let Some(results) = results {
-                                                Some(results.signal_quality as f32)
-                                            } else {
-                                                None
-                                            }
+                                            let as_f64 = window_analysis.iter().map(|x| x.map(|x| x as f64)).collect::<Vec<_>>();
+                                            let results = ppg_analysis.analyze_with_gaps(as_f64);
+                                            results.map(|results| results.signal_quality as f32)
                                         }
                                         _ => None,
                                     };
@@ -278,20 +647,38 @@ impl VVCore {
                                     data_storage.reset_counter(uuid.clone());
                                 }
                             }
+
+                            if timestamped_data {
+                                if let Some(window) = data_storage.timestamped_window(uuid, hist_size_api) {
+                                    delegate.new_timestamped_data(uuid.clone(), window);
+                                }
+                            }
                         }
                         drop(data_storage);
 
                         if !analysis_results.is_empty() {
                             debug!(logger, "Analysis results: {:?}", analysis_results);
+                        }
 
-                            let mut device_storage = device_storage.write().await;
-                            for device in device_storage.values_mut() {
-                                for channel in &mut device.channels { // TODO: This is a bit inefficient
-                                    if let Some(Some(result)) = analysis_results.get(&channel.id) {
-                                        channel.signal_quality = Some(*result);
-                                    }
+                        let mut device_storage = device_storage.write().await;
+                        if let Some(device) = device_storage.get_mut(&device_id) {
+                            device.packets_received += 1;
+                            device.packets_lost += packet.lost_packets as u64;
+
+                            for channel in &mut device.channels {
+                                if let Some(samples) = packet.samples.get(&channel.id) {
+                                    let lost = samples.iter().filter(|x| x.is_none()).count() as u64;
+                                    channel.samples_lost += lost;
+                                    channel.samples_received += samples.len() as u64 - lost;
+                                }
+                                if let Some(Some(result)) = analysis_results.get(&channel.id) {
+                                    channel.signal_quality = Some(*result);
                                 }
                             }
+                        }
+
+                        // loss statistics are reported along with the next change, unless packets were lost
+                        if !analysis_results.is_empty() || packet.lost_packets > 0 {
                             delegate.devices_changed(device_storage.values().cloned().collect());
                         }
                     }
@@ -299,31 +686,114 @@ impl VVCore {
             }
         });
 
-        let _handles = vec![ble_loop, periodic_time_sync, global_events, ble_event_handler];
+        let _handles = [ble_loop, periodic_time_sync, global_events, ble_event_handler];
         // maybe await on handles?
+        Ok(())
     }
 
-    pub fn sync_time(&self) {
-        if self.config.enable_mock_devices {
-            return;
+    /// Configured profiles first, so they can override the builtin ones
+    fn sensor_profiles(config: &VVCoreConfig) -> Result<Vec<SensorProfile>, VVCoreError> {
+        let mut profiles = vec![];
+        for source in config.sensor_profiles.iter() {
+            let profile = SensorProfile::from_toml(source).map_err(|e| VVCoreError::InvalidConfig {
+                message: format!("Invalid sensor profile: {}", e),
+            })?;
+            profiles.push(profile);
         }
+        profiles.extend(SensorProfile::builtin());
+        Ok(profiles)
+    }
 
-        let _ = self.event_broadcast.send(VVCoreInternalEvent::SyncTime);
+    pub fn sync_time(&self) -> Result<(), VVCoreError> {
+        self.send_event(VVCoreInternalEvent::SyncTime)
     }
 
-    pub fn pause(&self) {
-        if self.config.enable_mock_devices {
-            return;
-        }
+    pub fn pause(&self) -> Result<(), VVCoreError> {
+        self.send_event(VVCoreInternalEvent::Pause)
+    }
 
-        let _ = self.event_broadcast.send(VVCoreInternalEvent::Pause);
+    pub fn resume(&self) -> Result<(), VVCoreError> {
+        self.send_event(VVCoreInternalEvent::Resume)
     }
 
-    pub fn resume(&self) {
-        if self.config.enable_mock_devices {
-            return;
+    /// Unsubscribes from the data of all devices, keeping them connected and synced, including
+    /// devices that connect meanwhile
+    pub fn pause_streaming(&self) -> Result<(), VVCoreError> {
+        self.send_event(VVCoreInternalEvent::PauseStreaming)
+    }
+
+    /// Resubscribes to the data of all devices, also the ones paused by `pause_device`
+    pub fn resume_streaming(&self) -> Result<(), VVCoreError> {
+        self.send_event(VVCoreInternalEvent::ResumeStreaming)
+    }
+
+    /// Unsubscribes from the data of a device by its transport id or stable id, keeping the link
+    /// and the channel history. The device stays paused across reconnects.
+    pub fn pause_device(&self, id: String) -> Result<(), VVCoreError> {
+        self.send_event(VVCoreInternalEvent::PauseDevice(id))
+    }
+
+    pub fn resume_device(&self, id: String) -> Result<(), VVCoreError> {
+        self.send_event(VVCoreInternalEvent::ResumeDevice(id))
+    }
+
+    /// Connects a device by its transport id or the stable id of a known device, also when
+    /// auto-connect is off or its serial is not on the allowlist
+    pub fn connect_device(&self, id: String) -> Result<(), VVCoreError> {
+        self.send_event(VVCoreInternalEvent::Connect(id))
+    }
+
+    /// Disconnects a device by its transport id or stable id, it is not reconnected until
+    /// `connect_device` is called for it
+    pub fn disconnect_device(&self, id: String) -> Result<(), VVCoreError> {
+        self.send_event(VVCoreInternalEvent::Disconnect(id))
+    }
+
+    /// Tells the sensor to start measuring. Like all control commands this takes the transport
+    /// id or the stable id of a connected device and returns the command id, the outcome is
+    /// reported through `VVCoreDelegate::command_completed`.
+    pub fn start_sensor(&self, id: String) -> Result<u32, VVCoreError> {
+        self.send_command(id, ControlCommand::StartStreaming)
+    }
+
+    /// Tells the sensor to stop measuring, the device stays connected without the stall watchdog
+    pub fn stop_sensor(&self, id: String) -> Result<u32, VVCoreError> {
+        self.send_command(id, ControlCommand::StopStreaming)
+    }
+
+    /// Once acknowledged, samples are timestamped at the new rate
+    pub fn set_sampling_rate(&self, id: String, rate_hz: u16) -> Result<u32, VVCoreError> {
+        if rate_hz == 0 {
+            return Err(VVCoreError::InvalidArgument { message: "Sampling rate must be positive".to_string() });
         }
+        self.send_command(id, ControlCommand::SetSamplingRate(rate_hz))
+    }
+
+    /// Currents of the PPG LEDs in mA, in steps of 0.2 mA up to 51 mA
+    pub fn set_led_currents(&self, id: String, green_ma: f32, red_ma: f32, ir_ma: f32) -> Result<u32, VVCoreError> {
+        let command = ControlCommand::led_currents(green_ma, red_ma, ir_ma).ok_or_else(|| VVCoreError::InvalidArgument {
+            message: format!("LED currents {}/{}/{} mA out of range", green_ma, red_ma, ir_ma),
+        })?;
+        self.send_command(id, command)
+    }
+
+    pub fn set_ecg_gain(&self, id: String, gain: EcgGain) -> Result<u32, VVCoreError> {
+        self.send_command(id, ControlCommand::SetEcgGain(gain))
+    }
+
+    /// Blinks the LED of the device for a few seconds, to tell it apart from others
+    pub fn identify(&self, id: String, seconds: u8) -> Result<u32, VVCoreError> {
+        self.send_command(id, ControlCommand::Identify(seconds))
+    }
+
+    fn send_command(&self, id: String, command: ControlCommand) -> Result<u32, VVCoreError> {
+        let command_id = self.command_ids.fetch_add(1, Ordering::SeqCst);
+        self.send_event(VVCoreInternalEvent::Command(id, command_id, command))?;
+        Ok(command_id)
+    }
 
-        let _ = self.event_broadcast.send(VVCoreInternalEvent::Resume);
+    /// The global event handler is the only receiver, so sending fails until the BLE loop is started
+    fn send_event(&self, event: VVCoreInternalEvent) -> Result<(), VVCoreError> {
+        self.event_broadcast.send(event).map(|_| ()).map_err(|_| VVCoreError::NotStarted)
     }
 }
@@ -0,0 +1,97 @@
+use std::sync::Mutex;
+use super::*;
+
+/// A device that was connected before, remembered by its stable id
+#[derive(Debug, PartialEq, Clone)]
+pub struct KnownDevice {
+    pub id: String,
+    pub serial: u16,
+    pub name: String,
+    pub transport_id: String,
+}
+
+/// Persists the known devices between `VVCore` instances, implemented by the app
+pub trait KnownDeviceStore: Send + Sync {
+    fn load(&self) -> Vec<KnownDevice>;
+    fn save(&self, devices: Vec<KnownDevice>);
+}
+
+/// Store that forgets everything once dropped, used when the app does not provide one
+#[derive(Default)]
+pub struct MemoryKnownDeviceStore {
+    devices: Mutex<Vec<KnownDevice>>,
+}
+
+impl KnownDeviceStore for MemoryKnownDeviceStore {
+    fn load(&self) -> Vec<KnownDevice> {
+        self.devices.lock().unwrap().clone()
+    }
+
+    fn save(&self, devices: Vec<KnownDevice>) {
+        *self.devices.lock().unwrap() = devices;
+    }
+}
+
+/// Adds or updates the entry with the device's id, saving only if something changed
+pub(crate) fn remember(store: &dyn KnownDeviceStore, device: &Device) {
+    let known = KnownDevice {
+        id: device.id.clone(),
+        serial: device.serial,
+        name: device.name.clone(),
+        transport_id: device.transport_id.clone(),
+    };
+
+    let mut devices = store.load();
+    match devices.iter_mut().find(|d| d.id == known.id) {
+        Some(existing) if *existing == known => return,
+        Some(existing) => *existing = known,
+        None => devices.push(known),
+    }
+    store.save(devices);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(transport_id: &str, serial: u16) -> Device {
+        Device {
+            id: format!("vitalvision-{}", serial),
+            transport_id: transport_id.to_string(),
+            serial,
+            name: "VitalVision".to_string(),
+            manufacturer: None,
+            hardware_revision: None,
+            firmware_revision: None,
+            software_revision: None,
+            system_id: None,
+            rssi: None,
+            battery: 100,
+            drift_us: 0,
+            clock_offset_us: 0,
+            clock_skew_ppm: 0.0,
+            clock_uncertainty_us: 0,
+            state: DeviceState::Streaming,
+            connected: true,
+            reconnect_attempts: 0,
+            reconnect_failed: false,
+            packets_received: 0,
+            packets_lost: 0,
+            channels: vec![],
+        }
+    }
+
+    #[test]
+    fn remembers_devices_by_id() {
+        let store = MemoryKnownDeviceStore::default();
+        remember(&store, &device("a", 71));
+        remember(&store, &device("b", 72));
+        remember(&store, &device("c", 71));
+
+        let devices = store.load();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[0].id, "vitalvision-71");
+        assert_eq!(devices[0].transport_id, "c");
+        assert_eq!(devices[1].transport_id, "b");
+    }
+}
@@ -2,12 +2,23 @@ use super::*;
 
 use std::collections::HashMap;
 
+pub mod known_devices;
 mod ringbuffer;
 
 use ringbuffer::SliceableRingBuffer;
 
 pub type DeviceStorage = HashMap<String, Device>;
 
+/// Drops the entries of the transport id kept under another id, i.e. the placeholder
+/// a device had until its information was read
+pub fn replace_placeholders(devices: &mut DeviceStorage, id: &str, transport_id: &str) {
+    devices.retain(|key, device| key == id || device.transport_id != transport_id);
+}
+
+/// API window, analysis window with settling samples as `None`, channel type and datapoints
+/// since the last analysis
+pub type ChannelWindows<'a> = (&'a [Option<i32>], Vec<Option<i32>>, ChannelType, u32);
+
 pub struct DataStorage {
     hist_size: usize,
     ret_a_len: usize,
@@ -17,6 +28,10 @@ pub struct DataStorage {
 
 pub struct ChannelData {
     pub data: SliceableRingBuffer<Option<i32>>,
+    /// host time of every sample in `data`, 0 where nothing was written yet
+    pub timestamps: SliceableRingBuffer<i64>,
+    /// whether every sample in `data` was taken around an LED current change
+    pub settling: SliceableRingBuffer<bool>,
     pub data_type: ChannelType,
     pub datapoint_counter: u32,
 }
@@ -33,10 +48,18 @@ impl DataStorage {
         }
     }
     
+    /// Adds a channel, keeping the history of an existing channel of the same type,
+    /// e.g. when its device reconnects
     pub fn add_channel(&mut self, uuid: String, c_type: ChannelType) {
+        if self.data.get(&uuid).is_some_and(|c| c.data_type == c_type) {
+            return;
+        }
+
         self.data
             .insert(uuid.clone(), ChannelData {
                 data: SliceableRingBuffer::new(self.hist_size, None),
+                timestamps: SliceableRingBuffer::new(self.hist_size, 0),
+                settling: SliceableRingBuffer::new(self.hist_size, false),
                 data_type: c_type,
                 datapoint_counter: 0,
             });
@@ -46,15 +69,24 @@ impl DataStorage {
         self.data.remove(&uuid);
     }
     
-    pub fn add_datapoint(&mut self, uuid: String, data_points: Vec<i32>) -> Option<(&[Option<i32>], &[Option<i32>], ChannelType, u32)> {
-        return if let Some(channel_data) = self.data.get_mut(&uuid) {
-            for data_point in data_points.iter() {
-                channel_data.data.write(Some(*data_point));
-                channel_data.datapoint_counter += 1;
+    /// Appends samples, `None` for lost ones, along with their timestamps in us since the epoch.
+    /// Settling samples are kept for the API but left out of the analysis window.
+    /// Samples that would be overwritten right away are skipped.
+    pub fn add_datapoint(&mut self, uuid: String, data_points: Vec<Option<i32>>, timestamps_us: &[i64], settling: bool) -> Option<ChannelWindows<'_>> {
+        if let Some(channel_data) = self.data.get_mut(&uuid) {
+            let skip = data_points.len().saturating_sub(self.hist_size);
+            for (n, data_point) in data_points.iter().enumerate().skip(skip) {
+                channel_data.data.write(*data_point);
+                channel_data.timestamps.write(timestamps_us.get(n).copied().unwrap_or_default());
+                channel_data.settling.write(settling);
             }
+            channel_data.datapoint_counter += data_points.len() as u32;
 
             let ret_a = channel_data.data.get_slice_with_len(self.ret_a_len);
-            let ret_b = channel_data.data.get_slice_with_len(self.ret_b_len);
+            let ret_b = channel_data.data.get_slice_with_len(self.ret_b_len).iter()
+                .zip(channel_data.settling.get_slice_with_len(self.ret_b_len))
+                .map(|(data_point, settling)| data_point.filter(|_| !settling))
+                .collect();
             
             Some((ret_a, ret_b, channel_data.data_type.clone(), channel_data.datapoint_counter))
         } else {
@@ -62,9 +94,77 @@ impl DataStorage {
         }
     }
     
+    /// Inserts samples older than the latest ones by their timestamps, e.g. downloaded after a
+    /// reconnect, and returns the API window. Samples older than the history are dropped.
+    pub fn merge_datapoints(&mut self, uuid: &str, data_points: &[Option<i32>], timestamps_us: &[i64]) -> Option<&[Option<i32>]> {
+        let channel_data = self.data.get_mut(uuid)?;
+        let history = channel_data.timestamps.get_slice().iter()
+            .zip(channel_data.data.get_slice())
+            .zip(channel_data.settling.get_slice())
+            .filter(|((timestamp_us, _), _)| **timestamp_us != 0)
+            .map(|((timestamp_us, data_point), settling)| (*timestamp_us, *data_point, *settling));
+        let merged = timestamps_us.iter().zip(data_points)
+            .map(|(timestamp_us, data_point)| (*timestamp_us, *data_point, false));
+
+        let mut samples: Vec<(i64, Option<i32>, bool)> = history.chain(merged).collect();
+        // stable, so samples of the same time keep their order
+        samples.sort_by_key(|(timestamp_us, ..)| *timestamp_us);
+
+        channel_data.data = SliceableRingBuffer::new(self.hist_size, None);
+        channel_data.timestamps = SliceableRingBuffer::new(self.hist_size, 0);
+        channel_data.settling = SliceableRingBuffer::new(self.hist_size, false);
+        for (timestamp_us, data_point, settling) in samples.iter().skip(samples.len().saturating_sub(self.hist_size)) {
+            channel_data.data.write(*data_point);
+            channel_data.timestamps.write(*timestamp_us);
+            channel_data.settling.write(*settling);
+        }
+
+        Some(channel_data.data.get_slice_with_len(self.ret_a_len))
+    }
+
+    /// The latest `len` samples of a channel with their timestamps, oldest first
+    pub fn timestamped_window(&self, uuid: &str, len: usize) -> Option<Vec<Sample>> {
+        let channel_data = self.data.get(uuid)?;
+        let len = len.min(self.hist_size);
+        let values = channel_data.data.get_slice_with_len(len);
+        let timestamps = channel_data.timestamps.get_slice_with_len(len);
+
+        Some(timestamps.iter().zip(values)
+            .map(|(timestamp_us, value)| Sample { timestamp_us: *timestamp_us, value: *value })
+            .collect())
+    }
+
     pub fn reset_counter(&mut self, uuid: String) {
         if let Some(channel_data) = self.data.get_mut(&uuid) {
             channel_data.datapoint_counter = 0;
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn leaves_settling_samples_out_of_analysis() {
+        let mut storage = DataStorage::new(4, 4);
+        storage.add_channel("ppg".to_string(), ChannelType::PPG);
+        storage.add_datapoint("ppg".to_string(), vec![Some(1), Some(2)], &[1, 2], false);
+
+        let (api, analysis, ..) = storage.add_datapoint("ppg".to_string(), vec![Some(3), None], &[3, 4], true).unwrap();
+        assert_eq!(api, &[Some(1), Some(2), Some(3), None]);
+        assert_eq!(analysis, vec![Some(1), Some(2), None, None]);
+    }
+
+    #[test]
+    fn merges_older_samples_by_timestamp() {
+        let mut storage = DataStorage::new(4, 4);
+        storage.add_channel("ppg".to_string(), ChannelType::PPG);
+        storage.add_datapoint("ppg".to_string(), vec![Some(1), Some(5)], &[10, 50], false);
+
+        let window = storage.merge_datapoints("ppg", &[Some(2), Some(3), Some(4)], &[20, 30, 40]).unwrap();
+        assert_eq!(window, &[Some(2), Some(3), Some(4), Some(5)]);
+        assert_eq!(storage.timestamped_window("ppg", 2).unwrap()[0], Sample { timestamp_us: 40, value: Some(4) });
+        assert!(storage.merge_datapoints("ecg", &[], &[]).is_none());
+    }
+}
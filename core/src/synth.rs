@@ -0,0 +1,498 @@
+//! Synthetic ECG and PPG with ground truth, to drive the mock devices and to score the analysis.
+//!
+//! The ECG follows the dynamical model of ECGSYN (McSharry et al., 2003): a phase runs around
+//! the limit cycle once per beat and the P, Q, R, S and T waves are Gaussian attractors on it.
+//! The beats come from an RR tachogram with heart rate variability and respiratory sinus
+//! arrhythmia. Each beat produces a PPG pulse of a systolic and a diastolic Gaussian, a pulse
+//! transit time after the R peak. Impairments are added on top, and every generated stretch
+//! reports the true beat times and a quality label per segment.
+
+use std::collections::VecDeque;
+use std::f64::consts::PI;
+use rand::prelude::*;
+use rand::rngs::StdRng;
+
+/// θ, a and b of the P, Q, R, S and T waves, from ECGSYN, and the power of sqrt(HR / 60) that
+/// moves θ toward the R wave as the heart rate rises
+const ECG_WAVES: [(f64, f64, f64, f64); 5] = [
+    (-70.0 * PI / 180.0, 1.2, 0.25, 0.5),
+    (-15.0 * PI / 180.0, -5.0, 0.1, 1.0),
+    (0.0, 30.0, 0.1, 0.0),
+    (15.0 * PI / 180.0, -7.5, 0.1, 1.0),
+    (100.0 * PI / 180.0, 0.75, 0.4, 0.5),
+];
+/// Scales the model output to mV, about -0.4 to 1.2 mV
+const ECG_SCALE_MV: f64 = 4.0;
+/// Range of the clean signals, its size is the unit of the impairment amplitudes
+const ECG_MIN_MV: f64 = -0.4;
+const ECG_RANGE_MV: f64 = 1.6;
+const PPG_RANGE: f64 = 1.4;
+/// Frequency of the Mayer waves in the heart rate
+const MAYER_WAVE_HZ: f64 = 0.1;
+/// Frequency of the baseline wander
+const BASELINE_WANDER_HZ: f64 = 0.15;
+/// Label of a segment in which at least this fraction of the samples is impaired
+const UNUSABLE_FRACTION: f64 = 0.5;
+/// Label of a segment whose continuous noise is at least this fraction of the signal range
+const NOISY_LEVEL: f64 = 0.25;
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct Parameters {
+    pub sampling_rate: f64,
+    /// rate the ECG model is integrated at, at least four steps per sample. Lower rates are
+    /// faster and distort the narrow waves.
+    pub integration_rate: f64,
+    pub heart_rate_bpm: f64,
+    /// standard deviation of the beat to beat variation of the RR intervals
+    pub hrv_ms: f64,
+    pub respiration_rate_bpm: f64,
+    /// depth of the respiratory modulation of the RR intervals, the ECG baseline and the PPG
+    /// amplitude, 0 to 1
+    pub respiration_modulation: f64,
+    /// time from the R peak to the systolic peak of the PPG
+    pub pulse_transit_ms: f64,
+    /// amplitude of the PPG pulses, lower in cold or poorly perfused tissue, 1 by default
+    pub perfusion: f64,
+    /// amplitudes of the impairments, as fractions of the peak-to-peak amplitude of the clean
+    /// signal
+    pub baseline_wander: f64,
+    /// the mains frequency, only the ECG picks it up
+    pub powerline_hz: f64,
+    pub powerline_noise: f64,
+    /// standard deviation of the white noise
+    pub noise: f64,
+    /// motion artifacts per minute, at random times
+    pub motion_artifacts_per_min: f64,
+    pub motion_artifact: f64,
+    pub motion_artifact_ms: f64,
+    /// fraction of the clean range the ADC cuts off at either end, 0 for no limits
+    pub clipping: f64,
+    /// dropouts of both signals per minute, at random times
+    pub dropouts_per_min: f64,
+    pub dropout_ms: f64,
+    /// length of the segments the quality is labeled for
+    pub segment_sec: f64,
+    pub seed: u64,
+}
+
+impl Default for Parameters {
+    fn default() -> Self {
+        Self {
+            sampling_rate: 32.0,
+            integration_rate: 1024.0,
+            heart_rate_bpm: 60.0,
+            hrv_ms: 25.0,
+            respiration_rate_bpm: 15.0,
+            respiration_modulation: 0.5,
+            pulse_transit_ms: 250.0,
+            perfusion: 1.0,
+            baseline_wander: 0.05,
+            powerline_hz: 50.0,
+            powerline_noise: 0.02,
+            noise: 0.01,
+            motion_artifacts_per_min: 0.0,
+            motion_artifact: 1.0,
+            motion_artifact_ms: 2000.0,
+            clipping: 0.0,
+            dropouts_per_min: 0.0,
+            dropout_ms: 500.0,
+            segment_sec: 5.0,
+            seed: 0,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Quality {
+    Clean,
+    /// impaired but with the beats still to be found
+    Noisy,
+    /// mostly impaired or missing
+    Unusable,
+}
+
+/// Ground truth of a stretch of the signals, times in seconds since the generator started
+#[derive(Debug, PartialEq, Clone)]
+pub struct Segment {
+    pub start_sec: f64,
+    pub end_sec: f64,
+    pub ecg: Quality,
+    pub ppg: Quality,
+}
+
+#[derive(Debug, PartialEq, Clone, Default)]
+pub struct Synthesized {
+    /// in mV, `None` in dropouts
+    pub ecg: Vec<Option<f64>>,
+    /// in units of the clean pulse amplitude, `None` in dropouts
+    pub ppg: Vec<Option<f64>>,
+    /// times of the R peaks
+    pub beats_sec: Vec<f64>,
+    /// times of the systolic peaks of the PPG
+    pub pulses_sec: Vec<f64>,
+    /// segments completed by the generated samples
+    pub segments: Vec<Segment>,
+}
+
+/// An impairment lasting from `start` to `end`, in seconds
+#[derive(Debug, Clone, Copy)]
+struct Event {
+    start: f64,
+    end: f64,
+    /// random frequencies (Hz) and phases of a motion artifact
+    components: [(f64, f64); 3],
+}
+
+impl Event {
+    fn contains(&self, time: f64) -> bool {
+        (self.start..self.end).contains(&time)
+    }
+
+    /// A Hann windowed mix of low frequencies, peaking at about 1
+    fn motion(&self, time: f64) -> f64 {
+        let position = (time - self.start) / (self.end - self.start);
+        let window = (PI * position).sin().powi(2);
+        window * self.components.iter().map(|(hz, phase)| (2.0 * PI * hz * time + phase).sin()).sum::<f64>() / 1.5
+    }
+}
+
+/// Impaired samples of each signal in the current segment
+#[derive(Debug, Default)]
+struct SegmentCounts {
+    samples: usize,
+    ecg_impaired: usize,
+    ppg_impaired: usize,
+}
+
+/// Generates the signals sample by sample, so the parameters can change on the way
+pub struct Synthesizer {
+    parameters: Parameters,
+    rng: StdRng,
+    /// index of the next sample
+    sample: u64,
+    /// state of the ECG model
+    z: f64,
+    /// R peak times around the current time, the last one is ahead of it
+    beats: VecDeque<f64>,
+    next_motion: f64,
+    motion: Option<Event>,
+    next_dropout: f64,
+    dropout: Option<Event>,
+    segment: SegmentCounts,
+}
+
+impl Synthesizer {
+    pub fn new(parameters: Parameters) -> Self {
+        let rng = StdRng::seed_from_u64(parameters.seed);
+        // starting between two beats, the ECG model starts at rest
+        let rr = 60.0 / parameters.heart_rate_bpm.max(1.0);
+        let mut synthesizer = Self {
+            parameters,
+            rng,
+            sample: 0,
+            z: 0.0,
+            beats: VecDeque::from([-rr / 2.0, rr / 2.0]),
+            next_motion: f64::INFINITY,
+            motion: None,
+            next_dropout: f64::INFINITY,
+            dropout: None,
+            segment: SegmentCounts::default(),
+        };
+        synthesizer.schedule(0.0);
+        synthesizer
+    }
+
+    pub fn parameters(&self) -> &Parameters {
+        &self.parameters
+    }
+
+    /// Takes effect from the next sample, the impairment events are scheduled anew
+    pub fn set_parameters(&mut self, parameters: Parameters) {
+        self.parameters = parameters;
+        let now = self.time(self.sample);
+        self.schedule(now);
+    }
+
+    fn time(&self, sample: u64) -> f64 {
+        sample as f64 / self.parameters.sampling_rate
+    }
+
+    fn schedule(&mut self, now: f64) {
+        self.next_motion = now + self.wait(self.parameters.motion_artifacts_per_min);
+        self.next_dropout = now + self.wait(self.parameters.dropouts_per_min);
+    }
+
+    /// Time until the next event of a Poisson process
+    fn wait(&mut self, per_min: f64) -> f64 {
+        if per_min <= 0.0 {
+            return f64::INFINITY;
+        }
+        -(1.0 - self.rng.gen::<f64>()).ln() * 60.0 / per_min
+    }
+
+    fn gaussian(&mut self) -> f64 {
+        // Box-Muller
+        let (u, v): (f64, f64) = (1.0 - self.rng.gen::<f64>(), self.rng.gen());
+        (-2.0 * u.ln()).sqrt() * (2.0 * PI * v).cos()
+    }
+
+    fn respiration(&self, time: f64) -> f64 {
+        (2.0 * PI * self.parameters.respiration_rate_bpm / 60.0 * time).sin()
+    }
+
+    /// Adds beats until one lies beyond `time`, and forgets those no pulse reaches back from
+    fn extend_beats(&mut self, time: f64) {
+        while self.beats.back().is_some_and(|last| *last <= time + 2.0) {
+            let last = *self.beats.back().unwrap();
+            let parameters = &self.parameters;
+            let mean = 60.0 / parameters.heart_rate_bpm.max(1.0);
+            let modulation = 1.0
+                + 0.05 * parameters.respiration_modulation * self.respiration(last)
+                + 0.02 * (2.0 * PI * MAYER_WAVE_HZ * last).sin();
+            let rr = mean * modulation + self.parameters.hrv_ms / 1000.0 * self.gaussian();
+            self.beats.push_back(last + rr.max(0.25));
+        }
+        while self.beats.len() > 2 && self.beats[2] < time - 2.0 {
+            self.beats.pop_front();
+        }
+    }
+
+    /// Phase on the limit cycle, 0 at the R peaks
+    fn phase(&self, time: f64) -> f64 {
+        let next = self.beats.iter().position(|beat| *beat > time).unwrap_or(self.beats.len() - 1).max(1);
+        let (start, end) = (self.beats[next - 1], self.beats[next]);
+        let theta = 2.0 * PI * (time - start) / (end - start);
+        // P waves belong to the coming beat
+        if theta > PI { theta - 2.0 * PI } else { theta }
+    }
+
+    /// Integrates the ECG model from `from` to `to`, returning the mean of z in between like the
+    /// decimation filter of an ADC would. Point samples would miss the narrow R waves at low
+    /// sampling rates.
+    fn integrate(&mut self, from: f64, to: f64) -> f64 {
+        let steps = ((to - from) * self.parameters.integration_rate.max(4.0 * self.parameters.sampling_rate)).ceil().max(1.0) as usize;
+        let dt = (to - from) / steps as f64;
+        let rate = (self.parameters.heart_rate_bpm.max(1.0) / 60.0).sqrt();
+        let mut sum = 0.0;
+        for step in 0..steps {
+            let t = from + step as f64 * dt;
+            let theta = self.phase(t);
+            let omega = 2.0 * PI / self.rr_at(t);
+            let z0 = 0.15 / ECG_SCALE_MV * self.parameters.respiration_modulation * self.respiration(t);
+            let attraction: f64 = ECG_WAVES.iter().map(|(theta_i, a, b, power)| {
+                // the waves narrow less than the beats shorten, as in ECGSYN
+                let (theta_i, b) = (theta_i * rate.powf(*power), b * rate);
+                let delta = (theta - theta_i + PI).rem_euclid(2.0 * PI) - PI;
+                a * delta * omega * (-delta * delta / (2.0 * b * b)).exp()
+            }).sum();
+            self.z += dt * (-attraction - (self.z - z0));
+            sum += self.z;
+        }
+        sum / steps as f64
+    }
+
+    fn rr_at(&self, time: f64) -> f64 {
+        let next = self.beats.iter().position(|beat| *beat > time).unwrap_or(self.beats.len() - 1).max(1);
+        self.beats[next] - self.beats[next - 1]
+    }
+
+    /// Sum of the pulses of all beats at `time`, without the respiratory modulation
+    fn pulses(&self, time: f64) -> f64 {
+        let transit = self.parameters.pulse_transit_ms / 1000.0;
+        self.beats.iter().zip(self.beats.iter().skip(1))
+            .map(|(beat, next)| {
+                let rr = next - beat;
+                let systolic = beat + transit;
+                let diastolic = systolic + 0.35 * rr;
+                (-(time - systolic).powi(2) / (2.0 * (0.1 * rr).powi(2))).exp()
+                    + 0.4 * (-(time - diastolic).powi(2) / (2.0 * (0.15 * rr).powi(2))).exp()
+            })
+            .sum()
+    }
+
+    fn update_events(&mut self, time: f64) {
+        if self.motion.is_some_and(|event| time >= event.end) {
+            self.motion = None;
+        }
+        if self.motion.is_none() && time >= self.next_motion {
+            let components = [(); 3].map(|_| (self.rng.gen_range(0.3..3.0), self.rng.gen_range(0.0..2.0 * PI)));
+            self.motion = Some(Event { start: time, end: time + self.parameters.motion_artifact_ms / 1000.0, components });
+            self.next_motion = time + self.wait(self.parameters.motion_artifacts_per_min);
+        }
+        if self.dropout.is_some_and(|event| time >= event.end) {
+            self.dropout = None;
+        }
+        if self.dropout.is_none() && time >= self.next_dropout {
+            self.dropout = Some(Event { start: time, end: time + self.parameters.dropout_ms / 1000.0, components: [(0.0, 0.0); 3] });
+            self.next_dropout = time + self.wait(self.parameters.dropouts_per_min);
+        }
+    }
+
+    /// Saturates at the ADC limits, returns whether it had to
+    fn clip(&self, value: f64, low: f64, high: f64) -> (f64, bool) {
+        if self.parameters.clipping <= 0.0 {
+            return (value, false);
+        }
+        let margin = self.parameters.clipping * (high - low) / 2.0;
+        let (low, high) = (low + margin, high - margin);
+        (value.clamp(low, high), !(low..=high).contains(&value))
+    }
+
+    /// `noise` is the level of the continuous noise relative to the signal
+    fn label(&self, impaired: usize, noise: f64) -> Quality {
+        let fraction = impaired as f64 / self.segment.samples.max(1) as f64;
+        if fraction >= UNUSABLE_FRACTION {
+            Quality::Unusable
+        } else if impaired > 0 || noise >= NOISY_LEVEL {
+            Quality::Noisy
+        } else {
+            Quality::Clean
+        }
+    }
+
+    fn segment_samples(&self) -> u64 {
+        ((self.parameters.segment_sec * self.parameters.sampling_rate).round() as u64).max(1)
+    }
+
+    /// Labels the current segment, if it has samples
+    pub fn finish_segment(&mut self) -> Option<Segment> {
+        if self.segment.samples == 0 {
+            return None;
+        }
+        let end_sec = self.time(self.sample);
+        let start_sec = self.time(self.sample - self.segment.samples as u64);
+        let parameters = &self.parameters;
+        let noise = parameters.noise + parameters.baseline_wander;
+        let ecg = self.label(self.segment.ecg_impaired, noise + parameters.powerline_noise);
+        let ppg = self.label(self.segment.ppg_impaired, noise / parameters.perfusion.max(f64::EPSILON));
+        let segment = Segment { start_sec, end_sec, ecg, ppg };
+        self.segment = SegmentCounts::default();
+        Some(segment)
+    }
+
+    /// The next `samples` samples of both signals
+    // is_multiple_of would raise the minimum Rust version to 1.87
+    #[allow(clippy::manual_is_multiple_of)]
+    pub fn generate(&mut self, samples: usize) -> Synthesized {
+        let mut synthesized = Synthesized::default();
+        for _ in 0..samples {
+            let time = self.time(self.sample);
+            let previous = if self.sample == 0 { 0.0 } else { self.time(self.sample - 1) };
+            self.extend_beats(time);
+            let period = 1.0 / self.parameters.sampling_rate;
+            let z = self.integrate(time - period / 2.0, time + period / 2.0);
+            self.update_events(time);
+
+            let parameters = &self.parameters;
+            let transit = parameters.pulse_transit_ms / 1000.0;
+            for beat in self.beats.iter().filter(|beat| (previous..time).contains(*beat)) {
+                synthesized.beats_sec.push(*beat);
+            }
+            for beat in self.beats.iter().map(|beat| beat + transit).filter(|pulse| (previous..time).contains(pulse)) {
+                synthesized.pulses_sec.push(beat);
+            }
+
+            let respiration = self.respiration(time);
+            let wander = parameters.baseline_wander * (2.0 * PI * BASELINE_WANDER_HZ * time).sin();
+            let motion = self.motion.map_or(0.0, |event| parameters.motion_artifact * event.motion(time));
+            let ecg = ECG_SCALE_MV * z
+                + ECG_RANGE_MV * (wander + motion + parameters.powerline_noise * (2.0 * PI * parameters.powerline_hz * time).sin());
+            let ppg = parameters.perfusion * self.pulses(time) * (1.0 + 0.2 * parameters.respiration_modulation * respiration)
+                + PPG_RANGE * (wander + motion + 0.1 * parameters.respiration_modulation * respiration);
+            let (ecg_noise, ppg_noise) = (self.gaussian(), self.gaussian());
+            let ecg = ecg + ECG_RANGE_MV * self.parameters.noise * ecg_noise;
+            let ppg = ppg + PPG_RANGE * self.parameters.noise * ppg_noise;
+            let (ecg, ecg_clipped) = self.clip(ecg, ECG_MIN_MV, ECG_MIN_MV + ECG_RANGE_MV);
+            let (ppg, ppg_clipped) = self.clip(ppg, 0.0, PPG_RANGE);
+
+            let dropped = self.dropout.is_some_and(|event| event.contains(time));
+            let moving = self.motion.is_some_and(|event| event.contains(time));
+            synthesized.ecg.push(Some(ecg).filter(|_| !dropped));
+            synthesized.ppg.push(Some(ppg).filter(|_| !dropped));
+            self.segment.samples += 1;
+            self.segment.ecg_impaired += (dropped || moving || ecg_clipped) as usize;
+            self.segment.ppg_impaired += (dropped || moving || ppg_clipped) as usize;
+
+            self.sample += 1;
+            if self.sample % self.segment_samples() == 0 {
+                synthesized.segments.extend(self.finish_segment());
+            }
+        }
+        synthesized
+    }
+}
+
+/// `duration_sec` of both signals, the last segment labeled even if it is shorter
+pub fn synthesize(parameters: Parameters, duration_sec: f64) -> Synthesized {
+    let samples = (duration_sec * parameters.sampling_rate).round() as usize;
+    let mut synthesizer = Synthesizer::new(parameters);
+    let mut synthesized = synthesizer.generate(samples);
+    synthesized.segments.extend(synthesizer.finish_segment());
+    synthesized
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::ecg;
+    use crate::tests::test_config;
+
+    #[test]
+    fn generates_beats_at_the_heart_rate() {
+        let parameters = Parameters { heart_rate_bpm: 75.0, sampling_rate: 256.0, ..Parameters::default() };
+        let synthesized = synthesize(parameters.clone(), 60.0);
+        assert_eq!(synthesized.ecg.len(), 60 * 256);
+        assert!((73..=77).contains(&synthesized.beats_sec.len()), "{}", synthesized.beats_sec.len());
+        assert_eq!(synthesized.segments.len(), 12);
+        assert!(synthesized.segments.iter().all(|segment| segment.ecg == Quality::Clean && segment.ppg == Quality::Clean));
+
+        // the R peaks are the maxima of the ECG
+        let ecg: Vec<f64> = synthesized.ecg.iter().map(|x| x.unwrap()).collect();
+        for beat in synthesized.beats_sec.iter().filter(|beat| **beat > 1.0 && **beat < 59.0) {
+            let index = (beat * 256.0).round() as usize;
+            let peak = (index - 13..index + 13).max_by(|a, b| ecg[*a].total_cmp(&ecg[*b])).unwrap();
+            assert!(peak.abs_diff(index) <= 5, "R peak at {} instead of {}", peak, index);
+            assert!(ecg[peak] > 0.8, "{}", ecg[peak]);
+        }
+
+        // the same seed gives the same signals
+        assert_eq!(synthesize(parameters, 60.0), synthesized);
+    }
+
+    #[test]
+    fn labels_impaired_segments() {
+        let parameters = Parameters {
+            motion_artifacts_per_min: 6.0,
+            dropouts_per_min: 6.0,
+            clipping: 0.3,
+            seed: 7,
+            ..Parameters::default()
+        };
+        let synthesized = synthesize(parameters, 120.0);
+        let dropped = synthesized.ecg.iter().filter(|x| x.is_none()).count();
+        assert!(dropped > 0);
+        assert_eq!(synthesized.ppg.iter().filter(|x| x.is_none()).count(), dropped);
+        // clipping alone keeps every ECG segment from being clean
+        assert!(synthesized.segments.iter().all(|segment| segment.ecg != Quality::Clean));
+        assert!(synthesized.segments.iter().any(|segment| segment.ppg == Quality::Unusable));
+        let limit = synthesized.ecg.iter().flatten().fold(f64::MIN, |max, x| max.max(*x));
+        assert!((limit - 0.96).abs() < 1e-9, "{}", limit);
+
+        // poor perfusion only degrades the PPG
+        let synthesized = synthesize(Parameters { perfusion: 0.05, ..Parameters::default() }, 10.0);
+        assert!(synthesized.segments.iter().all(|segment| segment.ecg == Quality::Clean && segment.ppg == Quality::Noisy));
+    }
+
+    #[test]
+    fn scores_the_ecg_analysis() {
+        let params = ecg::Parameters { r_peak_prominence_mad_multiple: 8.0, r_peak_plateau: 1, ..test_config().ecg_analysis_params };
+        let parameters = Parameters { heart_rate_bpm: 66.0, sampling_rate: params.sampling_frequency, ..Parameters::default() };
+        let synthesized = synthesize(parameters, 30.0);
+        let true_rate = 60.0 * (synthesized.beats_sec.len() - 1) as f64
+            / (synthesized.beats_sec.last().unwrap() - synthesized.beats_sec[0]);
+
+        let results = ecg::Analysis::new(params).analyze_with_gaps(synthesized.ecg);
+        assert!((results.hr_estimate - true_rate).abs() < 2.0, "estimated {} for {}", results.hr_estimate, true_rate);
+        assert!(results.signal_quality > 0.9, "{}", results.signal_quality);
+    }
+}
@@ -0,0 +1,180 @@
+use std::sync::Arc;
+use std::time::Duration;
+use crate::ble::{CHARACTERISTIC_CONTROL, CHARACTERISTIC_DATA};
+use crate::ble::fake::{FakePeripheral, FakeTransport};
+use super::*;
+
+const ID: &str = "vitalvision-ecg-71";
+
+fn start(peripheral: &Arc<FakePeripheral>, config: VVCoreConfig) -> (VVCore, Arc<RecordingDelegate>) {
+    let transport = FakeTransport::new();
+    transport.add_peripheral(peripheral.clone());
+
+    let (core, delegate) = recording_core(config);
+    core.start_ble_loop_with_transport(transport).unwrap();
+    wait_for("device to connect", || delegate.device(ID).is_some_and(|d| d.connected));
+    (core, delegate)
+}
+
+fn outcome(delegate: &RecordingDelegate, command_id: u32) -> CommandOutcome {
+    wait_for("command outcome", || delegate.commands.lock().unwrap().contains_key(&command_id));
+    delegate.commands.lock().unwrap()[&command_id].clone()
+}
+
+#[test]
+fn sends_commands_and_reports_acknowledgements() {
+    let peripheral = FakePeripheral::builder("fake-control")
+        .device_information("71", "VitalVision ECG")
+        .current_time()
+        .data()
+        .control(FakePeripheral::acknowledge)
+        .build();
+    let (core, delegate) = start(&peripheral, test_config());
+
+    let command_id = core.set_led_currents(ID.to_string(), 10.0, 5.0, 2.4).unwrap();
+    assert_eq!(outcome(&delegate, command_id), CommandOutcome::Acknowledged);
+    assert_eq!(peripheral.value(CHARACTERISTIC_CONTROL), Some(vec![0x04, 0, 50, 25, 12]));
+
+    let command_id = core.set_ecg_gain("fake-control".to_string(), EcgGain::X6).unwrap();
+    assert_eq!(outcome(&delegate, command_id), CommandOutcome::Acknowledged);
+    assert_eq!(peripheral.value(CHARACTERISTIC_CONTROL), Some(vec![0x05, 1, 6]));
+
+    let command_id = core.identify(ID.to_string(), 3).unwrap();
+    assert_eq!(outcome(&delegate, command_id), CommandOutcome::Acknowledged);
+
+    assert!(matches!(core.set_sampling_rate(ID.to_string(), 0), Err(VVCoreError::InvalidArgument { .. })));
+    assert!(matches!(core.set_led_currents(ID.to_string(), 80.0, 0.0, 0.0), Err(VVCoreError::InvalidArgument { .. })));
+}
+
+#[test]
+fn reports_rejected_and_unanswered_commands() {
+    // rejects sampling rates, ignores identify
+    let peripheral = FakePeripheral::builder("fake-picky")
+        .device_information("71", "VitalVision ECG")
+        .current_time()
+        .data()
+        .control(|frame| match frame[0] {
+            0x03 => Some(vec![0x80, frame[1], frame[0], 2]),
+            0x06 => None,
+            _ => FakePeripheral::acknowledge(frame),
+        })
+        .build();
+    let (core, delegate) = start(&peripheral, test_config());
+
+    let rejected = core.set_sampling_rate(ID.to_string(), 1000).unwrap();
+    let unanswered = core.identify(ID.to_string(), 1).unwrap();
+    assert_eq!(outcome(&delegate, rejected), CommandOutcome::Rejected { status: 2 });
+    assert_eq!(outcome(&delegate, unanswered), CommandOutcome::TimedOut);
+
+    // and devices that are not connected
+    let command_id = core.start_sensor("fake-missing".to_string()).unwrap();
+    assert!(matches!(outcome(&delegate, command_id), CommandOutcome::Failed { .. }));
+}
+
+#[test]
+fn keeps_the_new_sampling_rate_after_a_pause() {
+    let peripheral = FakePeripheral::builder("fake-rate")
+        .device_information("71", "VitalVision ECG")
+        .current_time()
+        .data()
+        .control(FakePeripheral::acknowledge)
+        .build();
+    let mut config = test_config();
+    config.timestamped_data = true;
+    let (core, delegate) = start(&peripheral, config);
+
+    let command_id = core.set_sampling_rate(ID.to_string(), 64).unwrap();
+    assert_eq!(outcome(&delegate, command_id), CommandOutcome::Acknowledged);
+    core.pause_device(ID.to_string()).unwrap();
+    wait_for("device to pause", || delegate.device(ID).unwrap().state == DeviceState::Paused);
+    core.resume_device(ID.to_string()).unwrap();
+    wait_for("device to resume", || peripheral.is_subscribed(CHARACTERISTIC_DATA));
+
+    // paced a little slower than the 64 Hz device
+    for counter in 0..2 {
+        assert!(peripheral.notify(CHARACTERISTIC_DATA, legacy_packet(counter, [(1, 100, 200, 300); 3])));
+        std::thread::sleep(Duration::from_millis(60));
+    }
+
+    let ecg = format!("{ID}-0");
+    wait_for("samples to be stored", || delegate.timestamped_window(&ecg).is_some_and(|w| w[26].value.is_some()));
+    let window = delegate.timestamped_window(&ecg).unwrap();
+    for pair in window[26..].windows(2) {
+        let spacing = pair[1].timestamp_us - pair[0].timestamp_us;
+        assert!((spacing - 15_625).abs() <= 1, "spacing of {}us", spacing);
+    }
+}
+
+#[test]
+fn fails_commands_without_control_characteristic() {
+    let peripheral = FakePeripheral::builder("fake-legacy")
+        .device_information("71", "VitalVision ECG")
+        .current_time()
+        .data()
+        .build();
+    let (core, delegate) = start(&peripheral, test_config());
+
+    let command_id = core.start_sensor(ID.to_string()).unwrap();
+    match outcome(&delegate, command_id) {
+        CommandOutcome::Failed { message } => assert!(message.contains("no control characteristic"), "{}", message),
+        other => panic!("Unexpected outcome {:?}", other),
+    }
+}
+
+#[test]
+fn stopped_sensors_do_not_stall() {
+    let peripheral = FakePeripheral::builder("fake-stopped")
+        .device_information("71", "VitalVision ECG")
+        .current_time()
+        .data()
+        .control(FakePeripheral::acknowledge)
+        .build();
+    let mut config = test_config();
+    config.stall_timeout_ms = 100;
+    let (core, delegate) = start(&peripheral, config);
+
+    let command_id = core.stop_sensor(ID.to_string()).unwrap();
+    assert_eq!(outcome(&delegate, command_id), CommandOutcome::Acknowledged);
+
+    std::thread::sleep(Duration::from_millis(300));
+    assert_eq!(delegate.device(ID).unwrap().state, DeviceState::Streaming);
+    assert!(peripheral.is_subscribed(CHARACTERISTIC_DATA));
+}
+
+#[test]
+fn auto_gain_adjusts_led_currents() {
+    let peripheral = FakePeripheral::builder("fake-gain")
+        .device_information("71", "VitalVision ECG")
+        .current_time()
+        .data()
+        .control(FakePeripheral::acknowledge)
+        .build();
+    let mut config = test_config();
+    config.ppg_auto_gain = Some(PPGAutoGainParameters {
+        initial_current_ma: 10.0,
+        min_current_ma: 1.0,
+        max_current_ma: 50.0,
+        target_low: 0.25,
+        target_high: 0.75,
+        max_clipped: 0.01,
+        // a single packet at the legacy rate
+        window_ms: 100,
+        settle_ms: 0,
+    });
+    let (core, delegate) = start(&peripheral, config);
+    wait_for("initial LED currents", || peripheral.value(CHARACTERISTIC_CONTROL) == Some(vec![0x04, 0, 50, 50, 50]));
+
+    // green clips, red and IR sit at the floor
+    let counter = std::cell::Cell::new(0u8);
+    wait_for("LED currents to change", || {
+        counter.set(counter.get().wrapping_add(1));
+        peripheral.notify(CHARACTERISTIC_DATA, legacy_packet(counter.get(), [(0, u16::MAX, 0, 0); 3]));
+        std::thread::sleep(Duration::from_millis(10));
+        peripheral.value(CHARACTERISTIC_CONTROL) == Some(vec![0x04, 1, 25, 100, 100])
+    });
+
+    // the changes of the auto-gain are not reported, those of the app are
+    let command_id = core.set_led_currents(ID.to_string(), 5.0, 5.0, 5.0).unwrap();
+    assert_eq!(outcome(&delegate, command_id), CommandOutcome::Acknowledged);
+    assert_eq!(delegate.commands.lock().unwrap().len(), 1);
+}
@@ -0,0 +1,71 @@
+use crate::ble::fake::{FakePeripheral, FakeTransport};
+use super::*;
+
+#[test]
+fn rejects_invalid_sensor_profiles() {
+    let mut config = test_config();
+    config.sensor_profiles = vec!["name = \"Broken\"".to_string()];
+
+    let result = VVCore::new(config.clone(), Arc::new(RecordingDelegate::default()));
+    assert!(matches!(result, Err(VVCoreError::InvalidConfig { .. })));
+
+    config.sensor_profiles = vec![r#"
+        name = "No format"
+        scan_services = []
+        data_service = "0000FEE0-0000-1000-8000-00805F9B34FB"
+        data_characteristic = "0000FEE1-0000-1000-8000-00805F9B34FB"
+        [layout]
+        packet_length = 4
+        samples_per_packet = 1
+        sampling_rate = 50.0
+        [[layout.fields]]
+        name = "ECG"
+        type = "ECG"
+        format = ""
+        offset = 0
+    "#.to_string()];
+    let result = VVCore::new(config, Arc::new(RecordingDelegate::default()));
+    assert!(matches!(result, Err(VVCoreError::InvalidConfig { .. })));
+}
+
+#[test]
+fn requires_a_started_ble_loop_once() {
+    let (core, _delegate) = recording_core(test_config());
+    assert_eq!(core.sync_time(), Err(VVCoreError::NotStarted));
+    assert_eq!(core.pause(), Err(VVCoreError::NotStarted));
+
+    core.start_ble_loop_with_transport(FakeTransport::new()).unwrap();
+    assert_eq!(core.sync_time(), Ok(()));
+    assert_eq!(core.start_ble_loop_with_transport(FakeTransport::new()), Err(VVCoreError::AlreadyStarted));
+}
+
+#[test]
+fn reports_misbehaving_devices_and_keeps_running() {
+    let broken = FakePeripheral::builder("fake-broken")
+        .device_information("7x1", "VitalVision ECG")
+        .current_time()
+        .data()
+        .build();
+    let working = FakePeripheral::builder("fake-working")
+        .device_information("71", "VitalVision ECG")
+        .current_time()
+        .data()
+        .build();
+    let transport = FakeTransport::new();
+    transport.add_peripheral(broken);
+    transport.add_peripheral(working);
+
+    let (core, delegate) = recording_core(test_config());
+    core.start_ble_loop_with_transport(transport).unwrap();
+
+    wait_for("error report", || !delegate.errors.lock().unwrap().is_empty());
+    match &delegate.errors.lock().unwrap()[0] {
+        VVCoreError::Device { device_id, message } => {
+            assert_eq!(device_id, "fake-broken");
+            assert!(message.contains("Invalid serial number"), "{}", message);
+        }
+        other => panic!("Unexpected error {:?}", other),
+    }
+
+    wait_for("working device to connect", || delegate.device("vitalvision-ecg-71").is_some_and(|d| d.connected));
+}
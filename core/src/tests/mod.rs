@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::*;
+
+mod control;
+mod errors;
+mod pipeline;
+mod replay;
+mod scenario;
+mod selection;
+mod streaming;
+mod virtual_time;
+
+/// Delegate that records everything the core reports, for assertions from sync tests
+#[derive(Default)]
+pub(crate) struct RecordingDelegate {
+    pub devices: Mutex<Vec<Device>>,
+    pub data: Mutex<HashMap<String, Vec<Option<i32>>>>,
+    pub timestamped_data: Mutex<HashMap<String, Vec<Sample>>>,
+    pub errors: Mutex<Vec<VVCoreError>>,
+    pub nearby: Mutex<Vec<NearbyDevice>>,
+    pub commands: Mutex<HashMap<u32, CommandOutcome>>,
+    /// packets received and total of every backlog progress report
+    pub backlog_progress: Mutex<Vec<(u32, u32)>>,
+    pub backlogs: Mutex<Vec<BacklogOutcome>>,
+}
+
+impl VVCoreDelegate for RecordingDelegate {
+    fn devices_changed(&self, devices: Vec<Device>) {
+        *self.devices.lock().unwrap() = devices;
+    }
+
+    fn new_data(&self, uuid: String, data: Vec<Option<i32>>) {
+        self.data.lock().unwrap().insert(uuid, data);
+    }
+
+    fn new_timestamped_data(&self, uuid: String, data: Vec<Sample>) {
+        self.timestamped_data.lock().unwrap().insert(uuid, data);
+    }
+
+    fn error_occurred(&self, error: VVCoreError) {
+        self.errors.lock().unwrap().push(error);
+    }
+
+    fn nearby_devices_changed(&self, devices: Vec<NearbyDevice>) {
+        *self.nearby.lock().unwrap() = devices;
+    }
+
+    fn command_completed(&self, _device_id: String, command_id: u32, outcome: CommandOutcome) {
+        self.commands.lock().unwrap().insert(command_id, outcome);
+    }
+
+    fn backlog_progress(&self, _device_id: String, received_packets: u32, total_packets: u32) {
+        self.backlog_progress.lock().unwrap().push((received_packets, total_packets));
+    }
+
+    fn backlog_completed(&self, _device_id: String, outcome: BacklogOutcome) {
+        self.backlogs.lock().unwrap().push(outcome);
+    }
+}
+
+impl RecordingDelegate {
+    pub fn device(&self, id: &str) -> Option<Device> {
+        self.devices.lock().unwrap().iter().find(|d| d.id == id).cloned()
+    }
+
+    pub fn window(&self, channel_id: &str) -> Option<Vec<Option<i32>>> {
+        self.data.lock().unwrap().get(channel_id).cloned()
+    }
+
+    pub fn timestamped_window(&self, channel_id: &str) -> Option<Vec<Sample>> {
+        self.timestamped_data.lock().unwrap().get(channel_id).cloned()
+    }
+}
+
+pub(crate) fn test_config() -> VVCoreConfig {
+    VVCoreConfig {
+        hist_size_api: 32,
+        hist_size_analytics: 256,
+        max_initial_rtt_ms: 100,
+        sync_interval_sec: 3600,
+        enable_mock_devices: false,
+        analysis_interval_points: 64,
+        ecg_analysis_params: ECGAnalysisParameters {
+            sampling_frequency: 32.0,
+            filter_cutoff_low: 0.6,
+            filter_order: 1,
+            r_peak_prominence_mad_multiple: 12.0,
+            r_peak_distance: 5,
+            r_peak_plateau: 0,
+            hr_min: 40.0,
+            hr_max: 200.0,
+            hr_max_diff: 20.0,
+            max_interpolated_gap_sec: 0.25,
+            min_segment_sec: 4.0,
+        },
+        ppg_analysis_params: PPGAnalysisParameters {
+            sampling_frequency: 32.0,
+            filter_cutoff_low: 1.0,
+            filter_cutoff_high: 10.0,
+            filter_order: 4,
+            envelope_range: 23,
+            amplitude_min: 10.0,
+            amplitude_max: 2000.0,
+            trough_depth_min: -0.25,
+            trough_depth_max: 0.25,
+            pulse_width_min: 0.333,
+            pulse_width_max: 1.5,
+            max_interpolated_gap_sec: 0.5,
+            min_segment_sec: 4.0,
+        },
+        sensor_profiles: vec![],
+        reconnect_initial_delay_ms: 10,
+        reconnect_max_delay_ms: 40,
+        reconnect_max_attempts: 3,
+        step_timeout_ms: 1000,
+        stall_timeout_ms: 0,
+        rssi_interval_ms: 0,
+        command_timeout_ms: 200,
+        timestamped_data: false,
+        auto_connect: true,
+        auto_connect_serials: vec![],
+        ppg_auto_gain: None,
+        download_backlog: true,
+        capture_path: None,
+    }
+}
+
+/// One packet of the current wearable firmware: a counter byte and three frames of ECG, PPG green, red and IR
+pub(crate) fn legacy_packet(counter: u8, frames: [(i16, u16, u16, u16); 3]) -> Vec<u8> {
+    let mut packet = vec![counter];
+    for (ecg, green, red, ir) in frames {
+        packet.extend_from_slice(&ecg.to_le_bytes());
+        packet.extend_from_slice(&green.to_le_bytes());
+        packet.extend_from_slice(&red.to_le_bytes());
+        packet.extend_from_slice(&ir.to_le_bytes());
+    }
+    packet
+}
+
+/// Polls `condition` until it holds, panicking with `what` after a few seconds
+pub(crate) fn wait_for(what: &str, condition: impl Fn() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !condition() {
+        if Instant::now() > deadline {
+            panic!("Timed out waiting for {}", what);
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    }
+}
+
+pub(crate) fn recording_core(config: VVCoreConfig) -> (VVCore, Arc<RecordingDelegate>) {
+    let delegate = Arc::new(RecordingDelegate::default());
+    let core = VVCore::new(config, delegate.clone()).unwrap();
+    (core, delegate)
+}
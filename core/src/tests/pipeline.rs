@@ -0,0 +1,454 @@
+use std::sync::Arc;
+use std::time::Duration;
+use btleplug::api::CharPropFlags;
+use uuid::Uuid;
+use crate::ble::{CHARACTERISTIC_DATA, CHARACTERISTIC_FIRMWARE_REVISION, CHARACTERISTIC_HARDWARE_REVISION, CHARACTERISTIC_MANUFACTURER, CHARACTERISTIC_SYSTEM_ID, SERVICE_DATA, SERVICE_DEVICE_INFO};
+use crate::ble::channel_layout::{ChannelField, ChannelLayout};
+use crate::ble::fake::{FakePeripheral, FakeTransport};
+use crate::storage::known_devices::MemoryKnownDeviceStore;
+use super::*;
+
+const ECG_ID: &str = "vitalvision-ecg-71";
+
+fn ecg_device(id: &str) -> Arc<FakePeripheral> {
+    FakePeripheral::builder(id)
+        .device_information("71", "VitalVision ECG")
+        .battery(87)
+        .current_time()
+        .data()
+        .build()
+}
+
+fn start(peripheral: &Arc<FakePeripheral>) -> (VVCore, Arc<RecordingDelegate>, Arc<FakeTransport>) {
+    let transport = FakeTransport::new();
+    transport.add_peripheral(peripheral.clone());
+
+    let (core, delegate) = recording_core(test_config());
+    core.start_ble_loop_with_transport(transport.clone()).unwrap();
+
+    wait_for("data subscription", || peripheral.is_subscribed(CHARACTERISTIC_DATA));
+    (core, delegate, transport)
+}
+
+#[test]
+fn discovers_syncs_and_creates_channels() {
+    let peripheral = ecg_device("fake-1");
+    let (_core, delegate, _transport) = start(&peripheral);
+
+    wait_for("device to connect", || delegate.device(ECG_ID).is_some_and(|d| d.connected));
+    let device = delegate.device(ECG_ID).unwrap();
+
+    assert!(device.connected);
+    assert_eq!(device.state, DeviceState::Streaming);
+    // the placeholder it had under its transport id is gone
+    assert_eq!(delegate.devices.lock().unwrap().len(), 1);
+    assert_eq!(device.serial, 71);
+    assert_eq!(device.name, "VitalVision ECG");
+    assert_eq!(device.battery, 87);
+    assert!(device.drift_us.abs() < 100_000, "round trip of {}us", device.drift_us);
+    assert!(device.clock_offset_us.abs() < 100_000, "offset of {}us", device.clock_offset_us);
+
+    let channel_types: Vec<ChannelType> = device.channels.iter().map(|c| c.channel_type.clone()).collect();
+    assert_eq!(channel_types, vec![ChannelType::ECG, ChannelType::PPG, ChannelType::PPG, ChannelType::PPG]);
+    assert_eq!(device.channels[0].id, "vitalvision-ecg-71-0");
+    assert_eq!(device.transport_id, "fake-1");
+}
+
+#[test]
+fn estimates_clock_offset_and_skew() {
+    let peripheral = FakePeripheral::builder("fake-9")
+        .device_information("71", "VitalVision ECG")
+        .current_time()
+        .clock_error(250_000, 20_000.0)
+        .latency(Duration::from_millis(4))
+        .data()
+        .build();
+    let (core, delegate, _transport) = start(&peripheral);
+    wait_for("device to connect", || delegate.device(ECG_ID).is_some_and(|d| d.connected));
+
+    // the written time arrives at least one latency late
+    let device = delegate.device(ECG_ID).unwrap();
+    assert!((225_000..=248_000).contains(&device.clock_offset_us), "offset of {}us", device.clock_offset_us);
+    assert!(device.drift_us >= 4_000, "round trip of {}us", device.drift_us);
+    assert!(device.clock_uncertainty_us >= 2_000);
+    assert_eq!(device.clock_skew_ppm, 0.0);
+
+    for _ in 0..2 {
+        std::thread::sleep(Duration::from_millis(300));
+        let synced = delegate.device(ECG_ID).unwrap().clock_offset_us;
+        core.sync_time().unwrap();
+        wait_for("clock sync", || delegate.device(ECG_ID).unwrap().clock_offset_us != synced);
+    }
+
+    let device = delegate.device(ECG_ID).unwrap();
+    assert!((device.clock_skew_ppm - 20_000.0).abs() < 5_000.0, "skew of {}ppm", device.clock_skew_ppm);
+}
+
+#[test]
+// is_multiple_of would raise the minimum Rust version to 1.87
+#[allow(clippy::manual_is_multiple_of)]
+fn decodes_stores_and_analyzes_notifications() {
+    let peripheral = ecg_device("fake-2");
+    let (_core, delegate, _transport) = start(&peripheral);
+
+    // 1 Hz R peaks at 32 Hz sampling, long enough to pass the analysis interval
+    let mut sample = 0u32;
+    for counter in 0..40u8 {
+        let frames = [0, 1, 2].map(|_| {
+            let ecg = if sample % 32 == 0 { 2000 } else { 0 };
+            let ppg = 20000 + (sample % 32) as u16 * 10;
+            sample += 1;
+            (ecg, ppg, ppg + 1, ppg + 2)
+        });
+        assert!(peripheral.notify(CHARACTERISTIC_DATA, legacy_packet(counter, frames)));
+    }
+
+    wait_for("all samples to be stored", || {
+        delegate.window(&format!("{ECG_ID}-1")).and_then(|w| w.last().cloned().flatten()) == Some(20000 + 119 % 32 * 10)
+    });
+
+    let ppg_red = delegate.window(&format!("{ECG_ID}-2")).unwrap();
+    assert_eq!(ppg_red.len(), 32);
+    assert_eq!(ppg_red[31], Some(20000 + 119 % 32 * 10 + 1));
+    assert!(ppg_red.iter().all(|x| x.is_some()));
+
+    wait_for("ECG signal quality", || {
+        delegate.device(ECG_ID).is_some_and(|d| d.channels.first().is_some_and(|c| c.signal_quality.is_some()))
+    });
+}
+
+#[test]
+fn fills_lost_packets_and_counts_them() {
+    let peripheral = ecg_device("fake-8");
+    let (_core, delegate, _transport) = start(&peripheral);
+    wait_for("device to connect", || delegate.device(ECG_ID).is_some_and(|d| d.connected));
+
+    for counter in [0, 1, 4] {
+        let frames = [(1, 100 + counter as u16, 200, 300); 3];
+        assert!(peripheral.notify(CHARACTERISTIC_DATA, legacy_packet(counter, frames)));
+    }
+
+    wait_for("packet loss", || delegate.device(ECG_ID).unwrap().packets_lost == 2);
+    let window = delegate.window(&format!("{ECG_ID}-1")).unwrap();
+    assert_eq!(&window[17..23], &[Some(100), Some(100), Some(100), Some(101), Some(101), Some(101)]);
+    assert!(window[23..29].iter().all(|x| x.is_none()));
+    assert_eq!(&window[29..], &[Some(104), Some(104), Some(104)]);
+
+    let device = delegate.device(ECG_ID).unwrap();
+    assert_eq!(device.packets_received, 3);
+    assert_eq!(device.channels[1].samples_received, 9);
+    assert_eq!(device.channels[1].samples_lost, 6);
+}
+
+#[test]
+fn timestamps_samples_at_the_sampling_rate() {
+    let peripheral = ecg_device("fake-10");
+    let transport = FakeTransport::new();
+    transport.add_peripheral(peripheral.clone());
+
+    let mut config = test_config();
+    config.timestamped_data = true;
+    let (core, delegate) = recording_core(config);
+    core.start_ble_loop_with_transport(transport.clone()).unwrap();
+    wait_for("device to connect", || delegate.device(ECG_ID).is_some_and(|d| d.connected));
+
+    // paced a little slower than the 32 Hz device, with the time of the lost packet skipped
+    for counter in [0, 1, 3] {
+        let frames = [(1, 100, 200, 300); 3];
+        assert!(peripheral.notify(CHARACTERISTIC_DATA, legacy_packet(counter, frames)));
+        std::thread::sleep(Duration::from_millis(if counter == 1 { 220 } else { 110 }));
+    }
+
+    let ecg = format!("{ECG_ID}-0");
+    wait_for("all samples to be stored", || {
+        delegate.timestamped_window(&ecg).is_some_and(|w| w[31].value.is_some() && w[22].value.is_some())
+    });
+    let window = delegate.timestamped_window(&ecg).unwrap();
+    assert_eq!(window.len(), 32);
+    assert!(delegate.window(&ecg).is_none());
+
+    let received: Vec<Sample> = window[20..].to_vec();
+    let values: Vec<Option<i32>> = received.iter().map(|s| s.value).collect();
+    assert_eq!(values, [vec![Some(1); 6], vec![None; 3], vec![Some(1); 3]].concat());
+    for pair in received.windows(2) {
+        let spacing = pair[1].timestamp_us - pair[0].timestamp_us;
+        assert!((spacing - 31_250).abs() <= 1, "spacing of {}us", spacing);
+    }
+
+    let now_us = chrono::Utc::now().timestamp_micros();
+    let latest = received[11].timestamp_us;
+    assert!(latest <= now_us && latest > now_us - 2_000_000);
+    assert_eq!(window[0], Sample { timestamp_us: 0, value: None });
+}
+
+#[test]
+fn reports_battery_and_disconnects() {
+    let peripheral = ecg_device("fake-3");
+    let (_core, delegate, _transport) = start(&peripheral);
+    wait_for("device to connect", || delegate.device(ECG_ID).is_some_and(|d| d.connected));
+
+    peripheral.set_battery(12);
+    wait_for("battery update", || delegate.device(ECG_ID).unwrap().battery == 12);
+
+    peripheral.disconnect_remote();
+    wait_for("disconnect", || !delegate.device(ECG_ID).unwrap().connected);
+}
+
+#[test]
+fn builds_channels_from_layout_characteristic() {
+    let layout = ChannelLayout {
+        packet_length: 13,
+        samples_per_packet: 2,
+        sampling_rate: 64.0,
+        fields: vec![
+            ChannelField { name: "Counter".to_string(), channel_type: ChannelType::CNT, width: 1, signed: false, big_endian: false, offset: 0, stride: 0 },
+            ChannelField { name: "ECG chest".to_string(), channel_type: ChannelType::ECG, width: 3, signed: true, big_endian: false, offset: 1, stride: 6 },
+            ChannelField { name: "PPG IR".to_string(), channel_type: ChannelType::PPG, width: 3, signed: false, big_endian: false, offset: 4, stride: 6 },
+        ],
+    };
+    let peripheral = FakePeripheral::builder("fake-4")
+        .device_information("72", "VitalVision Chest")
+        .current_time()
+        .data()
+        .channel_layout(&layout)
+        .build();
+    let (_core, delegate, _transport) = start(&peripheral);
+
+    wait_for("device to connect", || delegate.device("vitalvision-chest-72").is_some_and(|d| d.connected));
+    let channels = delegate.device("vitalvision-chest-72").unwrap().channels;
+    assert_eq!(channels.len(), 2);
+    assert_eq!(channels[0].name, "ECG chest");
+    assert_eq!(channels[0].channel_type, ChannelType::ECG);
+    assert_eq!(channels[1].id, "vitalvision-chest-72-1");
+
+    let mut packet = vec![0];
+    packet.extend_from_slice(&[0xFE, 0xFF, 0xFF, 0x00, 0x00, 0x01]);
+    packet.extend_from_slice(&[0x05, 0x00, 0x00, 0x02, 0x00, 0x01]);
+    assert!(peripheral.notify(CHARACTERISTIC_DATA, packet));
+
+    wait_for("decoded samples", || delegate.window("vitalvision-chest-72-1").is_some_and(|w| w[31] == Some(65538)));
+    let ecg = delegate.window("vitalvision-chest-72-0").unwrap();
+    assert_eq!(&ecg[30..], &[Some(-2), Some(5)]);
+}
+
+#[test]
+fn decodes_devices_of_configured_profiles() {
+    let band_service = Uuid::from_u128(0x0000FEE000001000800000805F9B34FB);
+    let band_data = Uuid::from_u128(0x0000FEE100001000800000805F9B34FB);
+    let profile = format!(r#"
+        name = "Example Band"
+        model = "EXB"
+        scan_services = ["{band_service}"]
+        data_service = "{band_service}"
+        data_characteristic = "{band_data}"
+
+        [layout]
+        packet_length = 6
+        samples_per_packet = 2
+        sampling_rate = 50.0
+
+        [[layout.fields]]
+        name = "PPG green"
+        type = "PPG"
+        format = "u24"
+        endianness = "big"
+        offset = 0
+        stride = 3
+    "#);
+
+    let peripheral = FakePeripheral::builder("band-1")
+        .device_information("5", "EXB-2")
+        .current_time()
+        .advertise(band_service)
+        .characteristic(band_service, band_data, CharPropFlags::NOTIFY, vec![])
+        .build();
+    let transport = FakeTransport::new();
+    transport.add_peripheral(peripheral.clone());
+
+    let mut config = test_config();
+    config.sensor_profiles = vec![profile];
+    let (core, delegate) = recording_core(config);
+    core.start_ble_loop_with_transport(transport.clone()).unwrap();
+
+    wait_for("data subscription", || peripheral.is_subscribed(band_data));
+    wait_for("device to connect", || delegate.device("exb-2-5").is_some_and(|d| d.connected));
+    let channels = delegate.device("exb-2-5").unwrap().channels;
+    assert_eq!(channels.len(), 1);
+    assert_eq!(channels[0].name, "PPG green");
+
+    assert!(peripheral.notify(band_data, vec![0x01, 0x00, 0x00, 0x00, 0x01, 0x02]));
+    wait_for("decoded samples", || delegate.window("exb-2-5-0").is_some_and(|w| w[30..] == [Some(65536), Some(258)]));
+}
+
+#[test]
+fn reconnects_dropped_devices_keeping_channels() {
+    let peripheral = ecg_device("fake-5");
+    let (_core, delegate, _transport) = start(&peripheral);
+    wait_for("device to connect", || delegate.device(ECG_ID).is_some_and(|d| d.connected));
+
+    let frames = [(1, 100, 200, 300); 3];
+    assert!(peripheral.notify(CHARACTERISTIC_DATA, legacy_packet(0, frames)));
+    wait_for("samples", || delegate.window(&format!("{ECG_ID}-1")).is_some_and(|w| w[31] == Some(100)));
+
+    peripheral.set_connectable(false);
+    peripheral.disconnect_remote();
+    wait_for("reconnect attempts", || delegate.device(ECG_ID).unwrap().reconnect_attempts >= 2);
+    assert!(!delegate.device(ECG_ID).unwrap().connected);
+
+    peripheral.set_connectable(true);
+    wait_for("data subscription", || peripheral.is_subscribed(CHARACTERISTIC_DATA));
+    wait_for("reconnect", || delegate.device(ECG_ID).unwrap().connected);
+    let device = delegate.device(ECG_ID).unwrap();
+    assert_eq!(device.reconnect_attempts, 0);
+    assert_eq!(device.channels[1].id, format!("{ECG_ID}-1"));
+
+    // the history from before the drop is still there
+    let frames = [(1, 101, 201, 301); 3];
+    assert!(peripheral.notify(CHARACTERISTIC_DATA, legacy_packet(1, frames)));
+    wait_for("samples after reconnect", || delegate.window(&format!("{ECG_ID}-1")).is_some_and(|w| w[31] == Some(101)));
+    assert_eq!(delegate.window(&format!("{ECG_ID}-1")).unwrap()[28], Some(100));
+}
+
+#[test]
+fn reports_failed_reconnects() {
+    let peripheral = ecg_device("fake-6");
+    let (_core, delegate, _transport) = start(&peripheral);
+    wait_for("device to connect", || delegate.device(ECG_ID).is_some_and(|d| d.connected));
+
+    peripheral.set_connectable(false);
+    peripheral.disconnect_remote();
+    wait_for("reconnect failure", || delegate.device(ECG_ID).unwrap().reconnect_failed);
+    let device = delegate.device(ECG_ID).unwrap();
+    assert_eq!(device.reconnect_attempts, 3);
+    assert!(matches!(device.state, DeviceState::Failed { reason } if reason.contains("not reachable")));
+}
+
+#[test]
+fn fails_connection_steps_that_hang() {
+    let peripheral = FakePeripheral::builder("fake-11")
+        .device_information("71", "VitalVision ECG")
+        .data()
+        .hang_on_connect()
+        .build();
+    let transport = FakeTransport::new();
+    transport.add_peripheral(peripheral);
+
+    let mut config = test_config();
+    config.step_timeout_ms = 50;
+    let (core, delegate) = recording_core(config);
+    core.start_ble_loop_with_transport(transport).unwrap();
+
+    // known only by its transport id, as its information was never read
+    wait_for("connection step to fail", || {
+        delegate.device("fake-11").is_some_and(|d| matches!(d.state, DeviceState::Failed { .. }))
+    });
+    let device = delegate.device("fake-11").unwrap();
+    assert_eq!(device.state, DeviceState::Failed { reason: "Connecting timed out after 50ms".to_string() });
+    assert!(!device.connected);
+}
+
+#[test]
+fn reconnects_known_devices_of_previous_instances() {
+    // not advertising, so only the known device list can bring it back
+    let peripheral = FakePeripheral::builder("fake-7")
+        .device_information("71", "VitalVision ECG")
+        .current_time()
+        .characteristic(SERVICE_DATA, CHARACTERISTIC_DATA, CharPropFlags::NOTIFY, vec![])
+        .build();
+    let transport = FakeTransport::new();
+    transport.add_peripheral(peripheral.clone());
+
+    let store = Arc::new(MemoryKnownDeviceStore::default());
+    store.save(vec![KnownDevice { id: ECG_ID.to_string(), serial: 71, name: "VitalVision ECG".to_string(), transport_id: "fake-7".to_string() }]);
+
+    let delegate = Arc::new(RecordingDelegate::default());
+    let core = VVCore::new_with_store(test_config(), delegate.clone(), store.clone()).unwrap();
+    core.start_ble_loop_with_transport(transport.clone()).unwrap();
+
+    wait_for("device to connect", || delegate.device(ECG_ID).is_some_and(|d| d.connected));
+    drop(core);
+
+    let delegate = Arc::new(RecordingDelegate::default());
+    let core = VVCore::new_with_store(test_config(), delegate.clone(), store.clone()).unwrap();
+    peripheral.disconnect_remote();
+    core.start_ble_loop_with_transport(transport).unwrap();
+    wait_for("device to connect again", || delegate.device(ECG_ID).is_some_and(|d| d.connected));
+    assert_eq!(store.load().len(), 1);
+}
+
+#[test]
+fn keeps_device_ids_when_the_transport_id_changes() {
+    let store = Arc::new(MemoryKnownDeviceStore::default());
+
+    // e.g. the same wearable seen by a reinstalled app
+    for transport_id in ["install-1", "install-2"] {
+        let peripheral = ecg_device(transport_id);
+        let transport = FakeTransport::new();
+        transport.add_peripheral(peripheral.clone());
+
+        let delegate = Arc::new(RecordingDelegate::default());
+        let core = VVCore::new_with_store(test_config(), delegate.clone(), store.clone()).unwrap();
+        core.start_ble_loop_with_transport(transport).unwrap();
+
+        wait_for("device to connect", || delegate.device(ECG_ID).is_some_and(|d| d.connected));
+        let device = delegate.device(ECG_ID).unwrap();
+        assert_eq!(device.transport_id, transport_id);
+        assert_eq!(device.channels[0].id, format!("{ECG_ID}-0"));
+    }
+
+    let known = store.load();
+    assert_eq!(known.len(), 1);
+    assert_eq!(known[0].transport_id, "install-2");
+}
+
+#[test]
+fn addresses_devices_by_their_latest_transport_id() {
+    // known under an address it no longer advertises with
+    let store = Arc::new(MemoryKnownDeviceStore::default());
+    store.save(vec![KnownDevice { id: ECG_ID.to_string(), serial: 71, name: "VitalVision ECG".to_string(), transport_id: "old-address".to_string() }]);
+    let transport = FakeTransport::new();
+    transport.add_peripheral(ecg_device("new-address"));
+
+    let delegate = Arc::new(RecordingDelegate::default());
+    let core = VVCore::new_with_store(test_config(), delegate.clone(), store.clone()).unwrap();
+    core.start_ble_loop_with_transport(transport).unwrap();
+    wait_for("device to connect", || delegate.device(ECG_ID).is_some_and(|d| d.connected));
+
+    core.disconnect_device(ECG_ID.to_string()).unwrap();
+    wait_for("device to disconnect", || delegate.device(ECG_ID).is_some_and(|d| !d.connected));
+    assert_eq!(delegate.device(ECG_ID).unwrap().transport_id, "new-address");
+}
+
+#[test]
+fn reads_device_information_and_polls_rssi() {
+    let peripheral = FakePeripheral::builder("fake-12")
+        .device_information("0071\0", "VitalVision ECG")
+        .characteristic(SERVICE_DEVICE_INFO, CHARACTERISTIC_MANUFACTURER, CharPropFlags::READ, b"VitalVision\0".to_vec())
+        .characteristic(SERVICE_DEVICE_INFO, CHARACTERISTIC_HARDWARE_REVISION, CharPropFlags::READ, b"  ".to_vec())
+        .characteristic(SERVICE_DEVICE_INFO, CHARACTERISTIC_FIRMWARE_REVISION, CharPropFlags::READ, b"2.4.1".to_vec())
+        .characteristic(SERVICE_DEVICE_INFO, CHARACTERISTIC_SYSTEM_ID, CharPropFlags::READ, vec![5, 4, 3, 2, 1, 0xC3, 0xB2, 0xA1])
+        .rssi(-70)
+        .current_time()
+        .data()
+        .build();
+    let transport = FakeTransport::new();
+    transport.add_peripheral(peripheral.clone());
+
+    let mut config = test_config();
+    config.rssi_interval_ms = 20;
+    let (core, delegate) = recording_core(config);
+    core.start_ble_loop_with_transport(transport).unwrap();
+
+    wait_for("device to connect", || delegate.device(ECG_ID).is_some_and(|d| d.connected));
+    let device = delegate.device(ECG_ID).unwrap();
+    assert_eq!(device.serial, 71);
+    assert_eq!(device.manufacturer.as_deref(), Some("VitalVision"));
+    assert_eq!(device.hardware_revision, None);
+    assert_eq!(device.firmware_revision.as_deref(), Some("2.4.1"));
+    assert_eq!(device.software_revision, None);
+    assert_eq!(device.system_id.as_deref(), Some("A1B2C3-0102030405"));
+    assert_eq!(device.rssi, Some(-70));
+
+    peripheral.set_rssi(-55);
+    wait_for("RSSI poll", || delegate.device(ECG_ID).unwrap().rssi == Some(-55));
+}
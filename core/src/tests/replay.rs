@@ -0,0 +1,43 @@
+use std::time::Duration;
+use crate::ble::CHARACTERISTIC_DATA;
+use crate::ble::fake::{FakePeripheral, FakeTransport};
+use super::*;
+
+#[test]
+fn replays_a_captured_session() {
+    let path = std::env::temp_dir().join(format!("vvcore-capture-{}.jsonl", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+
+    let peripheral = FakePeripheral::builder("fake-captured")
+        .device_information("71", "VitalVision ECG")
+        .battery(87)
+        .current_time()
+        .data()
+        .build();
+    let transport = FakeTransport::new();
+    transport.add_peripheral(peripheral.clone());
+
+    let (core, delegate) = recording_core(VVCoreConfig { capture_path: Some(path.clone()), ..test_config() });
+    core.start_ble_loop_with_transport(transport).unwrap();
+    wait_for("data subscription", || peripheral.is_subscribed(CHARACTERISTIC_DATA));
+    for counter in 0..3 {
+        assert!(peripheral.notify(CHARACTERISTIC_DATA, legacy_packet(counter, [(1, 100 + counter as u16, 200, 300); 3])));
+        std::thread::sleep(Duration::from_millis(50));
+    }
+    wait_for("captured data", || delegate.window("vitalvision-ecg-71-1").is_some_and(|w| w.last() == Some(&Some(102))));
+    let captured = delegate.window("vitalvision-ecg-71-1").unwrap();
+    drop(core);
+
+    let (core, delegate) = recording_core(test_config());
+    core.start_replay(path.clone(), 10.0).unwrap();
+    wait_for("replayed data", || delegate.window("vitalvision-ecg-71-1").is_some_and(|w| w.last() == Some(&Some(102))));
+    assert_eq!(delegate.window("vitalvision-ecg-71-1").unwrap(), captured);
+
+    let device = delegate.device("vitalvision-ecg-71").unwrap();
+    assert_eq!(device.transport_id, "fake-captured");
+    assert_eq!(device.battery, 87);
+    assert_eq!(device.packets_lost, 0);
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(matches!(core.start_replay(path, 1.0), Err(VVCoreError::InvalidArgument { .. })));
+}
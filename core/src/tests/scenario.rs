@@ -0,0 +1,40 @@
+use super::*;
+
+#[test]
+fn runs_a_scenario_file() {
+    let path = std::env::temp_dir().join(format!("vvcore-scenario-{}.toml", std::process::id()));
+    let path = path.to_str().unwrap().to_string();
+    std::fs::write(&path, r#"
+        name = "Low battery and a bad link"
+
+        [[devices]]
+        serial = 1
+        battery = 80
+
+        [[devices]]
+        serial = 2
+
+        [[events]]
+        at_sec = 0.5
+        device = 1
+        action = "battery"
+        level = 5
+
+        [[events]]
+        at_sec = 0.5
+        device = 2
+        action = "signal"
+        dropouts_per_min = 600
+        dropout_ms = 200
+    "#).unwrap();
+
+    let (core, delegate) = recording_core(test_config());
+    core.start_scenario(path.clone()).unwrap();
+    wait_for("low battery", || delegate.device("vitalvision-mock-1").is_some_and(|d| d.battery == 5));
+    wait_for("lost packets", || delegate.device("vitalvision-mock-2").is_some_and(|d| d.packets_lost > 0));
+    assert_eq!(delegate.device("vitalvision-mock-1").unwrap().packets_lost, 0);
+    std::fs::remove_file(&path).unwrap();
+
+    let (core, _) = recording_core(test_config());
+    assert!(matches!(core.start_scenario(path), Err(VVCoreError::InvalidArgument { .. })));
+}
@@ -0,0 +1,95 @@
+use std::sync::Arc;
+use std::time::Duration;
+use crate::ble::{CHARACTERISTIC_DATA, SERVICE_DATA};
+use crate::ble::fake::{FakePeripheral, FakeTransport};
+use super::*;
+
+fn wearable(id: &str, serial: &str, rssi: i16) -> Arc<FakePeripheral> {
+    FakePeripheral::builder(id)
+        .local_name(&format!("VV {}", serial))
+        .rssi(rssi)
+        .device_information(serial, "VitalVision ECG")
+        .current_time()
+        .data()
+        .build()
+}
+
+#[test]
+fn reports_nearby_devices_without_connecting() {
+    let near = wearable("fake-near", "71", -40);
+    let far = wearable("fake-far", "72", -80);
+    let transport = FakeTransport::new();
+    transport.add_peripheral(far.clone());
+    transport.add_peripheral(near.clone());
+
+    let mut config = test_config();
+    config.auto_connect = false;
+    let (core, delegate) = recording_core(config);
+    core.start_ble_loop_with_transport(transport).unwrap();
+
+    wait_for("nearby devices", || delegate.nearby.lock().unwrap().len() == 2);
+    let nearby = delegate.nearby.lock().unwrap().clone();
+    assert_eq!(nearby[0].transport_id, "fake-near");
+    assert_eq!(nearby[0].name.as_deref(), Some("VV 71"));
+    assert_eq!(nearby[0].rssi, Some(-40));
+    assert_eq!(nearby[0].services, vec![SERVICE_DATA.to_string()]);
+    assert_eq!(nearby[0].device_id, None);
+    assert_eq!(nearby[1].transport_id, "fake-far");
+
+    far.set_rssi(-30);
+    wait_for("updated signal strength", || delegate.nearby.lock().unwrap()[0].transport_id == "fake-far");
+
+    std::thread::sleep(Duration::from_millis(50));
+    assert!(delegate.devices.lock().unwrap().is_empty());
+    assert!(!near.is_subscribed(CHARACTERISTIC_DATA));
+}
+
+#[test]
+fn connects_and_disconnects_selected_devices() {
+    let peripheral = wearable("fake-selected", "71", -50);
+    let transport = FakeTransport::new();
+    transport.add_peripheral(peripheral.clone());
+
+    let mut config = test_config();
+    config.auto_connect = false;
+    let (core, delegate) = recording_core(config);
+    core.start_ble_loop_with_transport(transport.clone()).unwrap();
+
+    wait_for("nearby device", || !delegate.nearby.lock().unwrap().is_empty());
+    core.connect_device("fake-selected".to_string()).unwrap();
+    wait_for("device to connect", || delegate.device("vitalvision-ecg-71").is_some_and(|d| d.connected));
+
+    // by its stable id, and without reconnecting afterwards
+    core.disconnect_device("vitalvision-ecg-71".to_string()).unwrap();
+    wait_for("device to disconnect", || delegate.device("vitalvision-ecg-71").is_some_and(|d| d.state == DeviceState::Disconnected));
+    transport.announce(&peripheral);
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(!peripheral.is_subscribed(CHARACTERISTIC_DATA));
+    assert_eq!(delegate.device("vitalvision-ecg-71").unwrap().reconnect_attempts, 0);
+
+    core.connect_device("vitalvision-ecg-71".to_string()).unwrap();
+    wait_for("device to reconnect", || delegate.device("vitalvision-ecg-71").is_some_and(|d| d.connected));
+}
+
+#[test]
+fn auto_connects_only_allowed_serials() {
+    let allowed = wearable("fake-allowed", "71", -50);
+    let other = wearable("fake-other", "72", -50);
+    let transport = FakeTransport::new();
+    transport.add_peripheral(other.clone());
+    transport.add_peripheral(allowed.clone());
+
+    let mut config = test_config();
+    config.auto_connect_serials = vec![71];
+    let (core, delegate) = recording_core(config);
+    core.start_ble_loop_with_transport(transport.clone()).unwrap();
+
+    wait_for("allowed device to connect", || delegate.device("vitalvision-ecg-71").is_some_and(|d| d.connected));
+    // the other one is let go once its serial is read, and not connected again
+    wait_for("other device to be dropped", || delegate.devices.lock().unwrap().len() == 1);
+    transport.announce(&other);
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(!other.is_subscribed(CHARACTERISTIC_DATA));
+    assert_eq!(delegate.devices.lock().unwrap().len(), 1);
+    assert!(delegate.errors.lock().unwrap().is_empty());
+}
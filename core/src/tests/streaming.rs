@@ -0,0 +1,258 @@
+use std::sync::Arc;
+use std::time::Duration;
+use btleplug::api::CharPropFlags;
+use crate::ble::{CHARACTERISTIC_BACKLOG, CHARACTERISTIC_DATA, CHARACTERISTIC_HEART_RATE_MEASUREMENT, CHARACTERISTIC_PLX_CONTINUOUS, CHARACTERISTIC_PLX_SPOT_CHECK, CHARACTERISTIC_SERIAL, SERVICE_DEVICE_INFO};
+use crate::ble::fake::{FakePeripheral, FakeTransport};
+use super::*;
+
+fn wearable(id: &str, serial: &str) -> Arc<FakePeripheral> {
+    FakePeripheral::builder(id)
+        .device_information(serial, "VitalVision ECG")
+        .current_time()
+        .data()
+        .build()
+}
+
+#[test]
+fn pauses_and_resumes_a_single_device() {
+    let peripheral = wearable("fake-pausing", "71");
+    let transport = FakeTransport::new();
+    transport.add_peripheral(peripheral.clone());
+
+    let (core, delegate) = recording_core(test_config());
+    core.start_ble_loop_with_transport(transport).unwrap();
+    wait_for("data subscription", || peripheral.is_subscribed(CHARACTERISTIC_DATA));
+    assert!(peripheral.notify(CHARACTERISTIC_DATA, legacy_packet(0, [(1, 100, 200, 300); 3])));
+
+    core.pause_device("vitalvision-ecg-71".to_string()).unwrap();
+    wait_for("device to pause", || delegate.device("vitalvision-ecg-71").is_some_and(|d| d.state == DeviceState::Paused));
+    assert!(!peripheral.is_subscribed(CHARACTERISTIC_DATA));
+    let device = delegate.device("vitalvision-ecg-71").unwrap();
+    assert!(device.connected);
+    assert_eq!(device.channels.len(), 4);
+
+    core.resume_device("fake-pausing".to_string()).unwrap();
+    wait_for("device to resume", || delegate.device("vitalvision-ecg-71").is_some_and(|d| d.state == DeviceState::Streaming));
+    // the counter moved on while paused, which is no loss
+    assert!(peripheral.notify(CHARACTERISTIC_DATA, legacy_packet(100, [(1, 101, 200, 300); 3])));
+
+    wait_for("data after resuming", || {
+        delegate.window("vitalvision-ecg-71-1").is_some_and(|w| w.last() == Some(&Some(101)))
+    });
+    let window = delegate.window("vitalvision-ecg-71-1").unwrap();
+    assert_eq!(&window[26..], &[Some(100), Some(100), Some(100), Some(101), Some(101), Some(101)]);
+    assert_eq!(delegate.device("vitalvision-ecg-71").unwrap().packets_lost, 0);
+}
+
+#[test]
+fn pauses_streaming_while_staying_connected() {
+    let first = wearable("fake-first", "71");
+    let second = wearable("fake-second", "72");
+    let transport = FakeTransport::new();
+    transport.add_peripheral(first.clone());
+
+    let (core, delegate) = recording_core(test_config());
+    core.start_ble_loop_with_transport(transport.clone()).unwrap();
+    wait_for("data subscription", || first.is_subscribed(CHARACTERISTIC_DATA));
+    wait_for("device to connect", || delegate.device("vitalvision-ecg-71").is_some_and(|d| d.connected));
+    let synced = delegate.device("vitalvision-ecg-71").unwrap().clock_offset_us;
+
+    core.pause_streaming().unwrap();
+    wait_for("device to pause", || delegate.device("vitalvision-ecg-71").is_some_and(|d| d.state == DeviceState::Paused));
+
+    // devices connecting meanwhile start paused
+    transport.add_peripheral(second.clone());
+    wait_for("second device to connect", || delegate.device("vitalvision-ecg-72").is_some_and(|d| d.state == DeviceState::Paused));
+    assert!(!second.is_subscribed(CHARACTERISTIC_DATA));
+
+    std::thread::sleep(Duration::from_millis(50));
+    core.resume_streaming().unwrap();
+    wait_for("data subscriptions", || first.is_subscribed(CHARACTERISTIC_DATA) && second.is_subscribed(CHARACTERISTIC_DATA));
+    wait_for("devices to stream", || {
+        delegate.devices.lock().unwrap().iter().all(|d| d.state == DeviceState::Streaming)
+    });
+
+    // without reconnecting or syncing again
+    let device = delegate.device("vitalvision-ecg-71").unwrap();
+    assert_eq!(device.clock_offset_us, synced);
+    assert_eq!(device.reconnect_attempts, 0);
+}
+
+#[test]
+fn resubscribes_stalled_devices() {
+    let peripheral = wearable("fake-stalling", "71");
+    let transport = FakeTransport::new();
+    transport.add_peripheral(peripheral.clone());
+
+    let mut config = test_config();
+    config.stall_timeout_ms = 100;
+    let (core, delegate) = recording_core(config);
+    core.start_ble_loop_with_transport(transport).unwrap();
+    wait_for("data subscription", || peripheral.is_subscribed(CHARACTERISTIC_DATA));
+
+    wait_for("device to stall", || delegate.device("vitalvision-ecg-71").is_some_and(|d| d.state == DeviceState::Stalled));
+    match &delegate.errors.lock().unwrap()[0] {
+        VVCoreError::Device { device_id, message } => {
+            assert_eq!(device_id, "vitalvision-ecg-71");
+            assert!(message.contains("resubscribing"), "{}", message);
+        }
+        other => panic!("Unexpected error {:?}", other),
+    }
+
+    assert!(peripheral.notify(CHARACTERISTIC_DATA, legacy_packet(0, [(1, 100, 200, 300); 3])));
+    wait_for("device to stream", || delegate.device("vitalvision-ecg-71").is_some_and(|d| d.state == DeviceState::Streaming));
+    assert!(delegate.device("vitalvision-ecg-71").unwrap().connected);
+}
+
+#[test]
+fn reconnects_devices_that_stay_stalled() {
+    let peripheral = wearable("fake-stuck", "71");
+    let transport = FakeTransport::new();
+    transport.add_peripheral(peripheral.clone());
+
+    let mut config = test_config();
+    config.stall_timeout_ms = 100;
+    let (core, delegate) = recording_core(config);
+    core.start_ble_loop_with_transport(transport).unwrap();
+
+    wait_for("reconnect", || delegate.device("vitalvision-ecg-71").is_some_and(|d| d.reconnect_attempts > 0));
+    let errors = delegate.errors.lock().unwrap().clone();
+    assert!(errors.len() >= 2, "{:?}", errors);
+    assert!(errors[1].to_string().contains("after resubscribing, reconnecting"), "{}", errors[1]);
+
+    // the reconnected session streams again
+    wait_for("data subscription", || peripheral.is_subscribed(CHARACTERISTIC_DATA));
+    assert!(peripheral.notify(CHARACTERISTIC_DATA, legacy_packet(0, [(1, 100, 200, 300); 3])));
+    wait_for("data", || delegate.window("vitalvision-ecg-71-1").is_some());
+}
+
+#[test]
+fn downloads_the_backlog_after_reconnecting() {
+    let peripheral = FakePeripheral::builder("fake-backlog")
+        .device_information("71", "VitalVision ECG")
+        .current_time()
+        .data()
+        .backlog()
+        .build();
+    let transport = FakeTransport::new();
+    transport.add_peripheral(peripheral.clone());
+
+    let (core, delegate) = recording_core(test_config());
+    core.start_ble_loop_with_transport(transport).unwrap();
+    wait_for("data subscription", || peripheral.is_subscribed(CHARACTERISTIC_DATA));
+    assert!(peripheral.notify(CHARACTERISTIC_DATA, legacy_packet(0, [(1, 100, 200, 300); 3])));
+    wait_for("data", || delegate.window("vitalvision-ecg-71-1").is_some_and(|w| w.last() == Some(&Some(100))));
+
+    // packets 1 to 4 are recorded while disconnected
+    peripheral.disconnect_remote();
+    wait_for("disconnect", || !delegate.device("vitalvision-ecg-71").unwrap().connected);
+    wait_for("reconnect", || delegate.device("vitalvision-ecg-71").unwrap().connected);
+    wait_for("data subscription", || peripheral.is_subscribed(CHARACTERISTIC_DATA));
+    // as long as the device takes to record them, 94 ms each
+    std::thread::sleep(Duration::from_millis(400));
+    assert!(peripheral.notify(CHARACTERISTIC_DATA, legacy_packet(5, [(1, 105, 200, 300); 3])));
+    wait_for("backlog request", || peripheral.value(CHARACTERISTIC_BACKLOG) == Some(vec![0x01, 0, 0, 0, 0, 4, 0, 0, 0]));
+
+    let mut chunk = vec![0x82, 0, 0];
+    for counter in 1..=4u8 {
+        chunk.extend(legacy_packet(counter, [(1, 100 + counter as u16, 200, 300); 3]));
+    }
+    assert!(peripheral.notify(CHARACTERISTIC_BACKLOG, vec![0x81, 4, 0, 0, 0]));
+    assert!(peripheral.notify(CHARACTERISTIC_BACKLOG, chunk));
+    assert!(peripheral.notify(CHARACTERISTIC_BACKLOG, vec![0x83, 0]));
+
+    wait_for("backlog", || !delegate.backlogs.lock().unwrap().is_empty());
+    assert_eq!(delegate.backlogs.lock().unwrap()[0], BacklogOutcome::Completed { packets: 4 });
+    assert_eq!(*delegate.backlog_progress.lock().unwrap(), vec![(0, 4), (4, 4)]);
+
+    // merged between the samples before and after the gap
+    let window = delegate.window("vitalvision-ecg-71-1").unwrap();
+    let expected: Vec<Option<i32>> = (100..=105).flat_map(|value| [Some(value); 3]).collect();
+    assert_eq!(&window[14..], expected.as_slice());
+    // counted since the reconnect
+    assert_eq!(delegate.device("vitalvision-ecg-71").unwrap().channels[1].samples_received, 15);
+}
+
+#[test]
+fn streams_heart_rate_from_a_chest_strap() {
+    // no time service, a serial that is not a number and the model only in the advertisement
+    let strap = FakePeripheral::builder("fake-strap")
+        .local_name("Polar H10 C9A1B2C3")
+        .characteristic(SERVICE_DEVICE_INFO, CHARACTERISTIC_SERIAL, CharPropFlags::READ, b"C9A1B2C3".to_vec())
+        .heart_rate()
+        .build();
+    let transport = FakeTransport::new();
+    transport.add_peripheral(strap.clone());
+
+    let (core, delegate) = recording_core(test_config());
+    core.start_ble_loop_with_transport(transport).unwrap();
+    wait_for("heart rate subscription", || strap.is_subscribed(CHARACTERISTIC_HEART_RATE_MEASUREMENT));
+    wait_for("strap to connect", || delegate.devices.lock().unwrap().iter().any(|d| d.connected));
+
+    let device = delegate.devices.lock().unwrap().iter().find(|d| d.connected).cloned().unwrap();
+    assert!(device.id.starts_with("polar-h10-c9a1b2c3-"), "{}", device.id);
+    let types: Vec<ChannelType> = device.channels.iter().map(|c| c.channel_type.clone()).collect();
+    assert_eq!(types, vec![ChannelType::HR, ChannelType::RR]);
+
+    // 8 bit heart rate with two RR intervals, then a 16 bit one with contact lost
+    assert!(strap.notify(CHARACTERISTIC_HEART_RATE_MEASUREMENT, vec![0x10, 62, 0x00, 0x04, 0x00, 0x02]));
+    assert!(strap.notify(CHARACTERISTIC_HEART_RATE_MEASUREMENT, vec![0x05, 61, 0x00]));
+
+    let (heart_rate, rr) = (device.channels[0].id.clone(), device.channels[1].id.clone());
+    wait_for("heart rate", || delegate.window(&heart_rate).is_some_and(|w| w.ends_with(&[Some(62), None])));
+    wait_for("RR intervals", || delegate.window(&rr).is_some_and(|w| w.ends_with(&[Some(1000), Some(500)])));
+}
+
+#[test]
+fn streams_spo2_from_a_pulse_oximeter() {
+    let oximeter = FakePeripheral::builder("fake-oximeter")
+        .local_name("Nonin 3150")
+        .pulse_oximeter()
+        .build();
+    let transport = FakeTransport::new();
+    transport.add_peripheral(oximeter.clone());
+
+    let (core, delegate) = recording_core(test_config());
+    core.start_ble_loop_with_transport(transport).unwrap();
+    wait_for("spot-check subscription", || oximeter.is_subscribed(CHARACTERISTIC_PLX_SPOT_CHECK));
+    wait_for("oximeter to connect", || delegate.devices.lock().unwrap().iter().any(|d| d.connected));
+
+    let device = delegate.devices.lock().unwrap().iter().find(|d| d.connected).cloned().unwrap();
+    assert!(device.id.starts_with("nonin-3150-"), "{}", device.id);
+    let types: Vec<ChannelType> = device.channels.iter().map(|c| c.channel_type.clone()).collect();
+    assert_eq!(types, vec![ChannelType::SPO2, ChannelType::HR, ChannelType::STATUS]);
+
+    // 97.5 % at 72 bpm with a status, then a spot-check flagged invalid
+    assert!(oximeter.notify(CHARACTERISTIC_PLX_CONTINUOUS, vec![0x04, 0xCF, 0xF3, 0x48, 0x00, 0x80, 0x00]));
+    assert!(oximeter.notify(CHARACTERISTIC_PLX_SPOT_CHECK, vec![0x02, 0x62, 0x00, 0x48, 0x00, 0x00, 0x80]));
+
+    let ids: Vec<String> = device.channels.iter().map(|c| c.id.clone()).collect();
+    wait_for("SpO2", || delegate.window(&ids[0]).is_some_and(|w| w.ends_with(&[Some(98), None])));
+    wait_for("status", || delegate.window(&ids[2]).is_some_and(|w| w.ends_with(&[Some(0x80), Some(0x8000)])));
+    assert!(delegate.window(&ids[1]).unwrap().ends_with(&[Some(72), None]));
+}
+
+#[test]
+fn streams_mock_devices_through_the_pipeline() {
+    let (core, delegate) = recording_core(VVCoreConfig { enable_mock_devices: true, analysis_interval_points: 8, ..test_config() });
+    assert_eq!(core.sync_time(), Err(VVCoreError::NotStarted));
+    core.start_ble_loop().unwrap();
+    assert_eq!(core.start_ble_loop(), Err(VVCoreError::AlreadyStarted));
+
+    wait_for("mock devices to stream", || {
+        ["vitalvision-mock-1", "vitalvision-mock-2"].iter().all(|id| delegate.device(id).is_some_and(|d| d.state == DeviceState::Streaming))
+    });
+    let device = delegate.device("vitalvision-mock-1").unwrap();
+    assert_eq!(device.channels.len(), 4);
+    assert_ne!(device.clock_uncertainty_us, 0);
+
+    // filled from storage at the sampling rate and analyzed
+    wait_for("a full window", || delegate.window("vitalvision-mock-1-1").is_some_and(|w| w.len() == 32 && w.iter().all(Option::is_some)));
+    wait_for("ECG quality", || delegate.device("vitalvision-mock-1").is_some_and(|d| d.channels[0].signal_quality.is_some()));
+
+    core.pause().unwrap();
+    wait_for("mock devices to disconnect", || delegate.device("vitalvision-mock-1").is_some_and(|d| !d.connected));
+    core.resume().unwrap();
+    wait_for("mock devices to reconnect", || delegate.device("vitalvision-mock-1").is_some_and(|d| d.state == DeviceState::Streaming));
+    assert_eq!(core.sync_time(), Ok(()));
+}
@@ -0,0 +1,58 @@
+use chrono::{DateTime, Utc};
+use crate::ble::mock::MockRunner;
+use crate::ble::scenario::Scenario;
+use crate::clock::{Clock, VirtualClock};
+use crate::storage::known_devices::MemoryKnownDeviceStore;
+use super::*;
+
+#[test]
+fn runs_an_hour_of_a_scenario_in_virtual_time() {
+    let scenario = Scenario::from_toml(r#"
+        name = "Drifting hour"
+        sampling_rate = 4.0               # an hour of samples at the legacy rate takes too long
+
+        [[devices]]
+        serial = 1
+        clock_skew_ppm = 200
+
+        [[events]]
+        at_sec = 1800
+        device = 1
+        action = "clock_jump"
+        ms = 2000
+
+        [[events]]
+        at_sec = 3600
+        device = 1
+        action = "battery"
+        level = 5
+    "#).unwrap();
+
+    let start: DateTime<Utc> = DateTime::parse_from_rfc3339("2024-03-01T12:00:00Z").unwrap().with_timezone(&Utc);
+    let clock = VirtualClock::new(start);
+    let delegate = Arc::new(RecordingDelegate::default());
+    let config = VVCoreConfig { sync_interval_sec: 600, timestamped_data: true, analysis_interval_points: 1_000_000, ..test_config() };
+    let core = VVCore::new_with_clock(config, delegate.clone(), Arc::new(MemoryKnownDeviceStore::default()), clock.clone()).unwrap();
+    let mut runner = MockRunner::from_scenario(scenario, core.logger.clone());
+    // a coarser ECG model, which is plenty for 4 Hz samples
+    for device in runner.devices_mut() {
+        let parameters = synth::Parameters { integration_rate: 64.0, ..device.synthesizer.parameters().clone() };
+        device.synthesizer.set_parameters(parameters);
+    }
+    core.start_mock_loop(runner).unwrap();
+
+    wait_for("an hour to pass", || delegate.device("vitalvision-mock-1").is_some_and(|d| d.battery == 5));
+    let device = delegate.device("vitalvision-mock-1").unwrap();
+    assert!(device.connected);
+    assert_eq!(device.packets_lost, 0);
+    // the jump of the device clock is not taken for skew
+    assert!((device.clock_skew_ppm - 200.0).abs() < 1.0, "skew {}", device.clock_skew_ppm);
+
+    // the samples carry the virtual time, the periodic syncs keep them on it despite the drift
+    // and the jump of the device clock
+    let window = delegate.timestamped_window("vitalvision-mock-1-1").unwrap();
+    let last = DateTime::from_timestamp_micros(window.last().unwrap().timestamp_us).unwrap();
+    assert!(last >= start + chrono::Duration::minutes(59) && last <= clock.now(), "{} at {}", last, clock.now());
+    let spacing_us = window.windows(2).map(|pair| pair[1].timestamp_us - pair[0].timestamp_us);
+    assert!(spacing_us.clone().all(|us| (220_000..280_000).contains(&us)), "{:?}", spacing_us.collect::<Vec<_>>());
+}
@@ -10,6 +10,14 @@ extension ChannelType: CustomStringConvertible {
             "ECG"
         case .ppg:
             "PPG"
+        case .hr:
+            "HR"
+        case .rr:
+            "RR"
+        case .spo2:
+            "SpO2"
+        case .status:
+            "Status"
         }
     }
 }
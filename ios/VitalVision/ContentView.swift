@@ -157,10 +157,11 @@ struct ContentView: View {
                 
                 ToolbarItem(placement: .navigationBarTrailing) {
                     Button {
+                        // devices stay connected and synced while paused
                         if paused {
-                            core.resume()
+                            core.resumeStreaming()
                         } else {
-                            core.pause()
+                            core.pauseStreaming()
                         }
                         paused.toggle()
                     } label: {
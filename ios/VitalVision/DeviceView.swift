@@ -1,5 +1,49 @@
 import SwiftUI
 
+extension DeviceState: CustomStringConvertible {
+    public var description: String {
+        switch self {
+        case .discovered:
+            "Discovered"
+        case .connecting:
+            "Connecting"
+        case .discoveringServices:
+            "Discovering Services"
+        case .readingInfo:
+            "Reading Info"
+        case .syncingTime:
+            "Syncing Time"
+        case .subscribing:
+            "Subscribing"
+        case .streaming:
+            "Streaming"
+        case .stalled:
+            "Stalled"
+        case .paused:
+            "Paused"
+        case .disconnecting:
+            "Disconnecting"
+        case .disconnected:
+            "Disconnected"
+        case .failed(let reason):
+            "Failed: \(reason)"
+        }
+    }
+
+    public var color: Color {
+        switch self {
+        case .streaming:
+            .green
+        case .paused:
+            .gray
+        case .stalled, .failed, .disconnected:
+            .red
+        default:
+            .yellow
+        }
+    }
+}
+
 struct AdditionalDeviceData: Codable {
     var participant: String?
     var location: String?
@@ -42,7 +86,7 @@ struct DevicePreviewView: View {
                     BatteryIndicator(level: device.battery)
                 }
                     .padding(.top, 6.0)
-                StatusCircle(color: device.connected ? .green : .red, size: 14.0)
+                StatusCircle(color: device.state.color, size: 14.0)
             }
         }
     }
@@ -59,9 +103,28 @@ struct DeviceDetailView: View {
         List {
             Section(header: Text("Device Info")) {
                 ListRow(key: "Serial", value: String(device.serial))
+                ListRow(key: "Transport ID", value: device.transportId)
                 ListRow(key: "Connected", value: device.connected ? "YES" : "NO")
+                ListRow(key: "State", value: device.state.description)
                 ListRow(key: "Sync Round Trip Delay", value: "\(device.driftUs / 1000)ms")
+                ListRow(key: "Clock Offset", value: "\(device.clockOffsetUs / 1000)ms ± \(device.clockUncertaintyUs / 1000)ms")
+                ListRow(key: "Clock Skew", value: String(format: "%.1fppm", device.clockSkewPpm))
                 ListRow(key: "Battery Level", value: "\(device.battery)%")
+                ListRow(key: "RSSI", value: device.rssi.map { "\($0)dBm" } ?? "-")
+                ListRow(key: "Packets Lost", value: "\(device.packetsLost) of \(device.packetsReceived + device.packetsLost)")
+            }
+            Section(header: Text("Device Information")) {
+                ListRow(key: "Model", value: device.name)
+                ListRow(key: "Manufacturer", value: device.manufacturer ?? "-")
+                ListRow(key: "Hardware Revision", value: device.hardwareRevision ?? "-")
+                ListRow(key: "Firmware Revision", value: device.firmwareRevision ?? "-")
+                ListRow(key: "Software Revision", value: device.softwareRevision ?? "-")
+                ListRow(key: "System ID", value: device.systemId ?? "-")
+            }
+            Section {
+                Button("Identify") {
+                    core.identify(id: device.id)
+                }
             }
             Section(header: Text("Additional Data")) {
                 TextField("Participant", text: $additionalData.participant ?? "")
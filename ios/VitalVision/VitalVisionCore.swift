@@ -5,6 +5,8 @@ class VitalVisionCore {
 
     public let devicesSubject: PassthroughSubject<[Device], Never>
     public let dataSubject: PassthroughSubject<(channelUuid: String, data: [Int32?]), Never>
+    public let errorSubject: PassthroughSubject<VvCoreError, Never>
+    public let nearbyDevicesSubject: PassthroughSubject<[NearbyDevice], Never>
         
     let notifications: NotificationService
 
@@ -14,19 +16,25 @@ class VitalVisionCore {
     init(){
         devicesSubject = PassthroughSubject<[Device], Never>()
         dataSubject = PassthroughSubject<(channelUuid: String, data: [Int32?]), Never>()
+        errorSubject = PassthroughSubject<VvCoreError, Never>()
+        nearbyDevicesSubject = PassthroughSubject<[NearbyDevice], Never>()
         
         notifications = NotificationService(devicesSubject: devicesSubject)
     }
     
     // not using VitalVisionCore as callback directly to break ARC cycle
     class Delegate: VvCoreDelegate {
-        init(devicesSubject: PassthroughSubject<[Device], Never>, dataSubject: PassthroughSubject<(channelUuid: String, data: [Int32?]), Never>) {
+        init(devicesSubject: PassthroughSubject<[Device], Never>, dataSubject: PassthroughSubject<(channelUuid: String, data: [Int32?]), Never>, errorSubject: PassthroughSubject<VvCoreError, Never>, nearbyDevicesSubject: PassthroughSubject<[NearbyDevice], Never>) {
             self.devicesSubject = devicesSubject
             self.dataSubject = dataSubject
+            self.errorSubject = errorSubject
+            self.nearbyDevicesSubject = nearbyDevicesSubject
         }
         
         public let devicesSubject: PassthroughSubject<[Device], Never>
         public let dataSubject: PassthroughSubject<(channelUuid: String, data: [Int32?]), Never>
+        public let errorSubject: PassthroughSubject<VvCoreError, Never>
+        public let nearbyDevicesSubject: PassthroughSubject<[NearbyDevice], Never>
 
         public weak var wself: VitalVisionCore?
         
@@ -45,8 +53,64 @@ class VitalVisionCore {
                 }
             }
         }
+
+        // the app plots bare windows and leaves timestampedData off
+        func newTimestampedData(channelUuid: String, data: [Sample]) {
+            newData(channelUuid: channelUuid, data: data.map { $0.value })
+        }
+
+        func errorOccurred(error: VvCoreError) {
+            print("VVCore error: \(error)")
+            Task {
+                await MainActor.run {
+                    errorSubject.send(error)
+                }
+            }
+        }
+
+        func commandCompleted(deviceId: String, commandId: UInt32, outcome: CommandOutcome) {
+            print("Command \(commandId) for \(deviceId): \(outcome)")
+        }
+
+        func backlogProgress(deviceId: String, receivedPackets: UInt32, totalPackets: UInt32) {
+            print("Backlog of \(deviceId): \(receivedPackets)/\(totalPackets) packets")
+        }
+
+        func backlogCompleted(deviceId: String, outcome: BacklogOutcome) {
+            print("Backlog of \(deviceId): \(outcome)")
+        }
+
+        func nearbyDevicesChanged(devices: [NearbyDevice]) {
+            Task {
+                await MainActor.run {
+                    nearbyDevicesSubject.send(devices)
+                }
+            }
+        }
     }
     
+    // keeps the devices the core connected to, so they are reconnected after an app restart
+    class KnownDevices: KnownDeviceStore {
+        let key = "knownDevices"
+
+        func load() -> [KnownDevice] {
+            let entries = UserDefaults.standard.array(forKey: key) as? [[String: Any]] ?? []
+            return entries.compactMap { entry in
+                guard let id = entry["id"] as? String,
+                      let serial = entry["serial"] as? Int,
+                      let name = entry["name"] as? String,
+                      let transportId = entry["transportId"] as? String else {
+                    return nil
+                }
+                return KnownDevice(id: id, serial: UInt16(serial), name: name, transportId: transportId)
+            }
+        }
+
+        func save(devices: [KnownDevice]) {
+            let entries = devices.map { ["id": $0.id, "serial": Int($0.serial), "name": $0.name, "transportId": $0.transportId] }
+            UserDefaults.standard.set(entries, forKey: key)
+        }
+    }
     
     func applyConfig(config: AppConfig){
         let coreConfig = VvCoreConfig(
@@ -91,10 +155,16 @@ class VitalVisionCore {
             return
         }
         
-        let delegate = Delegate(devicesSubject: devicesSubject, dataSubject: dataSubject)
+        let delegate = Delegate(devicesSubject: devicesSubject, dataSubject: dataSubject, errorSubject: errorSubject, nearbyDevicesSubject: nearbyDevicesSubject)
 
-        let vvcore = VvCore(config: coreConfig, delegate: delegate)
-        vvcore.startBleLoop()
+        let vvcore: VvCore
+        do {
+            vvcore = try VvCore.newWithStore(config: coreConfig, delegate: delegate, knownDevices: KnownDevices())
+            try vvcore.startBleLoop()
+        } catch {
+            print("Error starting VVCore: \(error)")
+            return
+        }
 
         // overwriting an old, non-nil vvcore (should) remove its last ARC reference
         self.vvcore = vvcore
@@ -104,14 +174,55 @@ class VitalVisionCore {
     }
     
     func syncTime(){
-        vvcore?.syncTime()
+        perform { try $0.syncTime() }
     }
     
     func pause(){
-        vvcore?.pause()
+        perform { try $0.pause() }
     }
     
     func resume(){
-        vvcore?.resume()
+        perform { try $0.resume() }
+    }
+
+    func pauseStreaming(){
+        perform { try $0.pauseStreaming() }
+    }
+
+    func resumeStreaming(){
+        perform { try $0.resumeStreaming() }
+    }
+
+    func pauseDevice(id: String){
+        perform { try $0.pauseDevice(id: id) }
+    }
+
+    func resumeDevice(id: String){
+        perform { try $0.resumeDevice(id: id) }
+    }
+
+    func identify(id: String){
+        perform { _ = try $0.identify(id: id, seconds: 3) }
+    }
+
+    func connectDevice(id: String){
+        perform { try $0.connectDevice(id: id) }
+    }
+
+    func disconnectDevice(id: String){
+        perform { try $0.disconnectDevice(id: id) }
+    }
+
+    private func perform(_ call: (VvCore) throws -> Void) {
+        guard let vvcore = vvcore else {
+            return
+        }
+        do {
+            try call(vvcore)
+        } catch let error as VvCoreError {
+            errorSubject.send(error)
+        } catch {
+            print("Unexpected error: \(error)")
+        }
     }
 }